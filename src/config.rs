@@ -1,16 +1,22 @@
 use crate::error::DshError;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use once_cell::sync::Lazy;
+use platform::{find_platform, normalize_domain, Endpoints};
 use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::sync::Mutex;
-use std::sync::RwLock;
+use store::{CachedStore, ConfigStore, StoreKind};
 
 // Define static constants and configurations
-static CACHED_CONFIG: Lazy<RwLock<Option<Config>>> = Lazy::new(|| RwLock::new(None));
-const SERVICE_NAME: &str = "dsh";
 const CONFIG_KEY: &str = "dsh_config";
 
+pub mod check;
+pub mod init;
+pub mod platform;
+pub mod profile;
+pub mod resolve;
+pub mod store;
+
 /// Represents the command-line arguments and options for the application.
 #[derive(Parser, Debug)]
 pub struct Command {
@@ -21,20 +27,72 @@ pub struct Command {
     #[clap(short = 'k', long)]
     api_key: Option<String>,
     /// Set the platform api url (for example: poc.kpn-dsh.com)
-    #[clap(short, long)]
+    #[clap(short, long, conflicts_with = "platform")]
     domain: Option<String>,
-    /// Set the platform mqtt client port (for example: 8883)
+    /// Set the domain and endpoints of a known platform (for example: poc), see 'dsh platforms list'
+    #[clap(long)]
+    platform: Option<String>,
+    /// Set the platform mqtt client port (for example: 8883), 0 uses the MQTT port of the platform
     #[clap(short, long)]
     port: Option<u16>,
     /// Set if connection goes over websocket
     #[clap(short, long)]
     websocket: Option<bool>,
+    /// Set the URL to request REST tokens, '{domain}' is replaced by the domain
+    #[clap(long)]
+    auth_url: Option<String>,
+    /// Set the URL to request MQTT tokens, '{domain}' is replaced by the domain
+    #[clap(long)]
+    mqtt_token_url: Option<String>,
+    /// Set the base URL of the REST APIs, '{domain}' is replaced by the domain
+    #[clap(long)]
+    rest_api_base: Option<String>,
+    /// Set the MQTT port of the platform for connections over TLS
+    #[clap(long)]
+    mqtts_port: Option<u16>,
+    /// Set the MQTT port of the platform for connections over websockets
+    #[clap(long)]
+    mqttwss_port: Option<u16>,
+    /// Reset the endpoint URLs and MQTT ports to their defaults
+    #[clap(long)]
+    reset_endpoints: bool,
     /// See the current configuration, including the full unmasked API-key
     #[clap(short, long)]
     show_all: bool,
-    /// Clean the OS secret store
+    /// Remove the configuration of the selected profile from its store
     #[clap(short, long)]
     clean_secret_store: bool,
+
+    #[clap(subcommand)]
+    command: Option<ConfigCommand>,
+}
+
+/// Subcommands of the configuration command.
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Set up the platform, tenant and API key of the selected profile step by step.
+    ///
+    /// The configuration is verified by requesting a REST token and an MQTT token, and only
+    /// saved when that succeeds.
+    Init,
+    /// Manage named configuration profiles, each with its own tenant, API key and platform.
+    #[clap(subcommand)]
+    Profile(profile::ProfileCommand),
+    /// Show the effective configuration and where every value comes from.
+    ///
+    /// Values are taken from, in order of precedence: command-line flags, `DSH_*`
+    /// environment variables, the project file `.dsh.toml` in the current directory or a
+    /// parent, the selected profile in the OS secret store and the defaults.
+    Resolve {
+        /// Show the API key unmasked.
+        #[clap(long)]
+        show_secrets: bool,
+    },
+    /// Test the configuration step by step: DNS, REST token, MQTT token and optionally the
+    /// MQTT connection, reporting the result and time of every step.
+    ///
+    /// The exit code is the one of the first failed step, e.g. 4 for rejected credentials.
+    Test(check::TestCommand),
 }
 
 // Global configuration instance
@@ -59,6 +117,8 @@ pub static CONFIG: Lazy<Mutex<Config>> = Lazy::new(|| {
 ///     domain: String::from("example.com"),
 ///     port: 8080,
 ///     websocket: false,
+///     platform: None,
+///     endpoints: Endpoints::default(),
 /// };
 /// println!("{}", config);
 /// ```
@@ -69,6 +129,12 @@ pub struct Config {
     pub domain: String,
     pub port: u16,
     pub websocket: bool,
+    /// The name of the known platform the domain and endpoints were taken from, if any.
+    #[serde(default)]
+    pub platform: Option<String>,
+    /// The endpoint URLs and MQTT ports of the platform.
+    #[serde(default)]
+    pub endpoints: Endpoints,
 }
 
 // Default values for Config
@@ -77,9 +143,12 @@ impl std::default::Default for Config {
         Config {
             tenant: "".to_string(),
             api_key: "".to_string(),
-            domain: "api.poc.kpn-dsh.com".to_string(),
-            port: 8883,
+            domain: "poc.kpn-dsh.com".to_string(),
+            // the MQTT port of the platform for the transport
+            port: 0,
             websocket: true,
+            platform: Some("poc".to_string()),
+            endpoints: Endpoints::default(),
         }
     }
 }
@@ -127,72 +196,92 @@ impl Config {
         Ok(self.clone())
     }
 
-    /// Save the current configuration to the OS secret store
-    pub fn save(&mut self, config_name: Option<&str>) -> Result<(), DshError> {
-        let serialized_config = serde_json::to_string(&self)?;
+    /// Use the domain and endpoints of the known platform with the given name.
+    pub fn platform(&mut self, name: &str) -> Result<Config, DshError> {
+        let platform = find_platform(name)?;
+        self.domain = platform.domain;
+        self.endpoints = platform.endpoints;
+        self.platform = Some(platform.name);
+        self.save(None)?;
+        Ok(self.clone())
+    }
 
-        // Use the provided config_name or fall back to the default CONFIG_KEY
-        let key_name = config_name.unwrap_or(CONFIG_KEY);
+    pub fn endpoints(&mut self, endpoints: Endpoints) -> Result<Config, DshError> {
+        self.endpoints = endpoints;
+        self.save(None)?;
+        Ok(self.clone())
+    }
 
-        let entry = keyring::Entry::new(SERVICE_NAME, key_name)?;
-        entry.set_password(&serialized_config)?;
+    /// Save the current configuration to the store of the selected profile
+    pub fn save(&mut self, config_name: Option<&str>) -> Result<(), DshError> {
+        // Use the provided config_name or fall back to the key of the selected profile
+        let (key_name, store) = location(config_name)?;
+        self.save_to(store.as_ref(), &key_name)
+    }
 
-        Ok(())
+    /// Save the current configuration to `store` under `key_name`
+    pub fn save_to(&self, store: &dyn ConfigStore, key_name: &str) -> Result<(), DshError> {
+        store.save(key_name, self)
     }
 
-    /// Clean the OS secret store for the given config_name
+    /// Remove the configuration for the given config_name from its store
     pub fn clean_secret_store(config_name: Option<&str>) -> Result<(), DshError> {
-        let key_name = config_name.unwrap_or(CONFIG_KEY);
-        let entry = keyring::Entry::new(SERVICE_NAME, key_name)?;
-
-        let mut cache = CACHED_CONFIG.write().expect("Failed to obtain write lock");
-        *cache = None;
-
-        match entry.get_password() {
-            Ok(_) => {
-                // If password retrieval is successful, delete the entry
-                entry.delete_password()?;
-                Ok(())
-            }
-            Err(keyring::Error::NoEntry) => {
-                // If there's no entry, do nothing and return Ok
-                Ok(())
-            }
-            Err(e) => Err(DshError::from(e)), // Handle other errors
-        }
+        let (key_name, store) = location(config_name)?;
+        store.delete(&key_name)
     }
 
-    /// Load the configuration from the OS secret store or cache
+    /// Load the configuration from the store of the selected profile or cache, the default
+    /// configuration when none is stored
     pub fn load(config_name: Option<&str>) -> Result<Config, DshError> {
-        // Check if the configuration is already cached
-        {
-            let cached_config_read = CACHED_CONFIG.read().unwrap();
-            if let Some(cached_config) = &*cached_config_read {
-                return Ok(cached_config.clone());
-            }
-        }
+        let (key_name, store) = location(config_name)?;
+        Config::load_from(store.as_ref(), &key_name)
+    }
 
-        // If not cached, fetch from the OS secret store
-        let key_name = config_name.unwrap_or(CONFIG_KEY);
-        let entry = keyring::Entry::new(SERVICE_NAME, key_name)?;
-        let serialized_config = match entry.get_password() {
-            Ok(config) => config,
-            Err(keyring::Error::NoEntry) => {
-                let mut new_entry = Config::default();
-                new_entry.save(Some(key_name))?;
-                return Ok(new_entry);
-            }
-            Err(e) => return Err(DshError::from(e)),
-        };
-        let config: Config = serde_json::from_str(&serialized_config)?;
+    /// Load the configuration stored under `key_name` in `store`, the default configuration
+    /// when none is stored, without storing the defaults
+    pub fn load_from(store: &dyn ConfigStore, key_name: &str) -> Result<Config, DshError> {
+        let mut config = store.load(key_name)?.unwrap_or_default();
+        config.domain = normalize_domain(&config.domain);
+        Ok(config)
+    }
+}
 
-        // Cache the fetched configuration
-        {
-            let mut cached_config_write = CACHED_CONFIG.write().unwrap();
-            *cached_config_write = Some(config.clone());
+impl Config {
+    /// Returns the platform name to show after the domain, if any.
+    fn platform_suffix(&self) -> String {
+        match &self.platform {
+            Some(platform) => format!(" ({})", platform),
+            None => String::new(),
         }
+    }
+}
 
-        Ok(config)
+/// Returns the key and the store for `config_name`, by default the key and store of the
+/// selected profile. Other configurations are kept in the OS secret store.
+fn location(config_name: Option<&str>) -> Result<(String, Box<dyn ConfigStore>), DshError> {
+    let (key_name, kind) = match config_name {
+        Some(config_name) => (config_name.to_string(), StoreKind::Keyring),
+        None => (
+            profile::config_key(profile::selected()),
+            profile::selected_store(),
+        ),
+    };
+    Ok((key_name, Box::new(CachedStore::new(kind.open()?))))
+}
+
+/// Mask the API key, showing only the last 4 characters.
+///
+/// If the API key is shorter than 4 characters, it will be fully masked.
+/// Otherwise, all but the last 4 characters will be replaced with asterisks (`*`).
+pub fn mask_api_key(api_key: &str) -> String {
+    if api_key.len() > 4 {
+        format!(
+            "{}{}",
+            "*".repeat(api_key.len() - 4),
+            &api_key[api_key.len() - 4..]
+        )
+    } else {
+        "*".repeat(api_key.len())
     }
 }
 
@@ -202,19 +291,7 @@ impl Config {
 /// while also ensuring that sensitive information (like the API key) is masked when printed.
 impl fmt::Display for Config {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // Mask the API key, showing only the last 4 characters.
-        //
-        // If the API key is shorter than 4 characters, it will be fully masked.
-        // Otherwise, all but the last 4 characters will be replaced with asterisks (`*`).
-        let masked_api_key = if self.api_key.len() > 4 {
-            format!(
-                "{}{}",
-                "*".repeat(self.api_key.len() - 4),
-                &self.api_key[self.api_key.len() - 4..]
-            )
-        } else {
-            "*".repeat(self.api_key.len())
-        };
+        let masked_api_key = mask_api_key(&self.api_key);
 
         // Write the formatted `Config` instance to the provided formatter.
         //
@@ -223,20 +300,43 @@ impl fmt::Display for Config {
         // ```plaintext
         // Tenant: [tenant]
         // API Key: [masked_api_key]
-        // Domain: [domain]
+        // Domain: [domain] ([platform])
         // Port: [port]
         // Websocket: [websocket]
+        // [endpoints]
         // ```
+        let port = match self.port {
+            0 => "0 (the MQTT port of the platform)".to_string(),
+            port => port.to_string(),
+        };
         write!(
             f,
-            "Tenant: {}\nAPI Key: {}\nDomain: {}\nPort: {}\nWebsocket: {}",
-            self.tenant, masked_api_key, self.domain, self.port, self.websocket
+            "Tenant: {}\nAPI Key: {}\nDomain: {}{}\nPort: {}\nWebsocket: {}\n{}",
+            self.tenant,
+            masked_api_key,
+            self.domain,
+            self.platform_suffix(),
+            port,
+            self.websocket,
+            self.endpoints
         )
     }
 }
 
 // Main function to run the application based on the provided command-line options
-pub fn run(opt: &Command) -> Result<(), DshError> {
+pub async fn run(opt: &Command) -> Result<(), DshError> {
+    match &opt.command {
+        Some(ConfigCommand::Init) => return init::run().await,
+        Some(ConfigCommand::Test(cmd)) => return check::run(cmd).await,
+        Some(ConfigCommand::Profile(cmd)) => return profile::run(cmd),
+        Some(ConfigCommand::Resolve { show_secrets }) => {
+            let resolved = resolve::resolve(Default::default())?;
+            println!("{}", resolved.describe(*show_secrets));
+            return Ok(());
+        }
+        None => {}
+    }
+
     // store opt values in config
     let mut config = CONFIG.lock().unwrap();
     let mut any_option_set = false; // Flag to check if any option is set
@@ -250,7 +350,15 @@ pub fn run(opt: &Command) -> Result<(), DshError> {
         any_option_set = true;
     }
     if let Some(domain) = &opt.domain {
-        config.domain = domain.to_string();
+        config.domain = normalize_domain(domain);
+        config.platform = None;
+        any_option_set = true;
+    }
+    if let Some(name) = &opt.platform {
+        let platform = find_platform(name)?;
+        config.domain = platform.domain;
+        config.endpoints = platform.endpoints;
+        config.platform = Some(platform.name);
         any_option_set = true;
     }
     if let Some(port) = &opt.port {
@@ -261,10 +369,40 @@ pub fn run(opt: &Command) -> Result<(), DshError> {
         config.websocket = *websocket;
         any_option_set = true;
     }
+    if opt.reset_endpoints {
+        config.endpoints = Endpoints::default();
+        any_option_set = true;
+    }
+    if let Some(auth_url) = &opt.auth_url {
+        config.endpoints.auth_url = auth_url.to_string();
+        any_option_set = true;
+    }
+    if let Some(mqtt_token_url) = &opt.mqtt_token_url {
+        config.endpoints.mqtt_token_url = mqtt_token_url.to_string();
+        any_option_set = true;
+    }
+    if let Some(rest_api_base) = &opt.rest_api_base {
+        config.endpoints.rest_api_base = rest_api_base.to_string();
+        any_option_set = true;
+    }
+    if let Some(mqtts_port) = &opt.mqtts_port {
+        config.endpoints.mqtts_port = *mqtts_port;
+        any_option_set = true;
+    }
+    if let Some(mqttwss_port) = &opt.mqttwss_port {
+        config.endpoints.mqttwss_port = *mqttwss_port;
+        any_option_set = true;
+    }
     if opt.show_all {
         println!(
-            "Tenant: {}\nAPI Key: {}\nDomain: {}\nPort: {}\nWebsocket: {}",
-            config.tenant, config.api_key, config.domain, config.port, config.websocket
+            "Tenant: {}\nAPI Key: {}\nDomain: {}{}\nPort: {}\nWebsocket: {}\n{}",
+            config.tenant,
+            config.api_key,
+            config.domain,
+            config.platform_suffix(),
+            config.port,
+            config.websocket,
+            config.endpoints
         );
         any_option_set = true;
     }
@@ -272,7 +410,12 @@ pub fn run(opt: &Command) -> Result<(), DshError> {
         return Config::clean_secret_store(None);
     }
     if !any_option_set {
-        println!("{}", config);
+        println!(
+            "Profile: {} ({})\n{}",
+            profile::selected(),
+            profile::selected_store(),
+            config
+        );
     }
     config.save(None)?;
     Ok(())
@@ -282,23 +425,24 @@ pub fn run(opt: &Command) -> Result<(), DshError> {
 #[cfg(test)]
 mod tests {
     use super::*;
-
-    #[cfg(feature = "mock_os_secret_store")]
-    use keyring::{mock, set_default_credential_builder};
+    use store::MemoryStore;
 
     const TEST_CONFIG_NAME: &str = "test_dsh_config";
 
     fn setup() {
-        #[cfg(feature = "mock_os_secret_store")]
-        set_default_credential_builder(mock::default_credential_builder());
-
-        // Clean the secret store with the test-specific config_name before each test
-        Config::clean_secret_store(Some(TEST_CONFIG_NAME)).unwrap();
+        // Clean the store with the test-specific config_name before each test
+        MemoryStore.delete(TEST_CONFIG_NAME).unwrap();
     }
 
     fn teardown() {
-        // Clean the secret store with the test-specific config_name after each test
-        Config::clean_secret_store(Some(TEST_CONFIG_NAME)).unwrap();
+        // Clean the store with the test-specific config_name after each test
+        MemoryStore.delete(TEST_CONFIG_NAME).unwrap();
+    }
+
+    /// Store the configuration in the in-memory store and load it again.
+    fn round_trip(config: &Config) -> Config {
+        config.save_to(&MemoryStore, TEST_CONFIG_NAME).unwrap();
+        Config::load_from(&MemoryStore, TEST_CONFIG_NAME).unwrap()
     }
 
     #[test]
@@ -307,8 +451,9 @@ mod tests {
         let config = Config::new();
         assert_eq!(config.tenant, "");
         assert_eq!(config.api_key, "");
-        assert_eq!(config.domain, "api.poc.kpn-dsh.com".to_string());
-        assert_eq!(config.port, 8883);
+        assert_eq!(config.domain, "poc.kpn-dsh.com".to_string());
+        assert_eq!(config.endpoints, Endpoints::default());
+        assert_eq!(config.port, 0);
         assert!(config.websocket);
         teardown();
     }
@@ -316,68 +461,114 @@ mod tests {
     #[test]
     fn test_set_tenant() {
         let mut config = Config::new();
-        config.tenant("tenant_name").unwrap();
-        assert_eq!(config.tenant, "tenant_name".to_string());
+        config.tenant = "tenant_name".to_string();
+        assert_eq!(round_trip(&config).tenant, "tenant_name");
     }
 
     #[test]
     fn test_set_domain() {
         let mut config = Config::new();
-        config.domain("domain").unwrap();
-        assert_eq!(config.domain, "domain".to_string());
+        config.domain = "api.domain".to_string();
+        // the domain is normalized on load
+        assert_eq!(round_trip(&config).domain, "domain");
     }
 
     #[test]
     fn test_set_port() {
         let mut config = Config::new();
-        config.port(1234).unwrap();
-        assert_eq!(config.port, 1234);
+        config.port = 1234;
+        assert_eq!(round_trip(&config).port, 1234);
     }
 
     #[test]
     fn test_set_api_key() {
         let mut config = Config::new();
-        config.api_key("api_key").unwrap();
-        assert_eq!(config.api_key, "api_key".to_string());
+        config.api_key = "api_key".to_string();
+        assert_eq!(round_trip(&config).api_key, "api_key");
+    }
+
+    #[test]
+    fn test_set_endpoints() {
+        let mut config = Config::new();
+        let endpoints = Endpoints {
+            auth_url: "http://localhost:8080/auth/v0/token".to_string(),
+            ..Default::default()
+        };
+        config.endpoints = endpoints.clone();
+        assert_eq!(round_trip(&config).endpoints, endpoints);
+    }
+
+    #[test]
+    fn test_set_platform() {
+        let platform = find_platform("prod-azure-dsh").unwrap();
+        let config = Config {
+            domain: platform.domain,
+            endpoints: platform.endpoints,
+            platform: Some(platform.name),
+            ..Config::new()
+        };
+        assert_eq!(config.domain, "az.kpn-dsh.com");
+        assert_eq!(config.platform, Some("prodaz".to_string()));
+        assert!(find_platform("nope").is_err());
+        assert_eq!(round_trip(&config), config);
+    }
+
+    #[test]
+    fn test_config_without_endpoints() {
+        let config: Config = serde_json::from_str(
+            r#"{"tenant":"t","api_key":"k","domain":"poc.kpn-dsh.com","port":8883,"websocket":true}"#,
+        )
+        .unwrap();
+        assert_eq!(config.endpoints, Endpoints::default());
+        assert_eq!(config.platform, None);
     }
 
     #[test]
     fn test_set_websocket() {
         let mut config = Config::new();
-        config.websocket(true).unwrap();
-        assert!(config.websocket);
+        config.websocket = false;
+        assert!(!round_trip(&config).websocket);
     }
 
     #[test]
     fn test_load_empty_secret_store() {
         setup();
-        let stored_config = Config::load(Some(TEST_CONFIG_NAME)).unwrap();
+        let stored_config = Config::load_from(&MemoryStore, TEST_CONFIG_NAME).unwrap();
         let config = Config::new();
         assert_eq!(config, stored_config);
+        // loading does not store the defaults
+        assert_eq!(MemoryStore.load(TEST_CONFIG_NAME).unwrap(), None);
         teardown();
     }
 
     #[test]
     fn test_store_config() {
         setup();
-        let mut config = Config::new();
-        config.tenant("tenant_name_stored").unwrap();
-        config.api_key("api_key_stored").unwrap();
-        config.domain("domain_stored").unwrap();
-        config.port(111).unwrap();
-        config.websocket(true).unwrap();
-        config.save(Some(TEST_CONFIG_NAME)).unwrap();
-        // TODO: it would be great to add a statefull version of mocking this test and validate the
-        // stored config from the mock secret store
+        let config = Config {
+            tenant: "tenant_name_stored".to_string(),
+            api_key: "api_key_stored".to_string(),
+            domain: "domain_stored".to_string(),
+            port: 111,
+            websocket: true,
+            ..Config::new()
+        };
+        config.save_to(&MemoryStore, TEST_CONFIG_NAME).unwrap();
+
+        let stored_config = MemoryStore.load(TEST_CONFIG_NAME).unwrap().unwrap();
+        assert_eq!(stored_config, config);
+        assert_eq!(stored_config.tenant, "tenant_name_stored");
+        assert_eq!(stored_config.api_key, "api_key_stored");
+        assert_eq!(stored_config.domain, "domain_stored");
+        assert_eq!(stored_config.port, 111);
         teardown();
+        assert_eq!(MemoryStore.load(TEST_CONFIG_NAME).unwrap(), None);
     }
 
     #[test]
     fn test_store_default_config() {
         setup();
-        let mut config = Config::new();
-        config.save(Some(TEST_CONFIG_NAME)).unwrap();
-        let stored_config = Config::load(Some(TEST_CONFIG_NAME)).unwrap();
+        let config = Config::new();
+        let stored_config = round_trip(&config);
         assert_eq!(config, stored_config);
         teardown();
     }
@@ -0,0 +1,389 @@
+use super::resolve::{self, PartialConfig};
+use super::{profile, Config};
+use crate::error::DshError;
+use crate::mc::client::Client;
+use crate::tf::token::Token;
+use crate::tf::{request_mqtt_token, request_rest_token, RequestAttributes};
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+use std::fmt;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// The steps of the check, in the order they are run.
+const STEPS: [&str; 5] = ["config", "dns", "rest-token", "mqtt-token", "mqtt-connect"];
+
+/// The maximum time to wait for the MQTT broker to accept the connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Options of the configuration test, overriding the configuration like `dsh tf`.
+#[derive(Args, Debug, Default)]
+pub struct TestCommand {
+    /// The name of the tenant.
+    #[clap(short, long)]
+    tenant: Option<String>,
+    /// The tenant-specific API key with privileges to fetch the tokens.
+    #[clap(short = 'k', long)]
+    api_key: Option<String>,
+    /// The platform domain (e.g., poc.kpn-dsh.com).
+    #[clap(short, long, conflicts_with = "platform")]
+    domain: Option<String>,
+    /// A known platform (e.g., poc), see 'dsh platforms list'.
+    #[clap(long)]
+    platform: Option<String>,
+    /// Also connect to the MQTT broker with the token, over TLS or secure websockets.
+    #[clap(long)]
+    connect: bool,
+    /// The transport to connect with, by default the transport of the configuration.
+    #[clap(long, value_enum, requires = "connect")]
+    transport: Option<Transport>,
+    /// Write the report as JSON on stdout.
+    #[clap(long)]
+    json: bool,
+}
+
+/// The transports to connect to the MQTT broker with.
+#[derive(ValueEnum, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Transport {
+    /// MQTT over TLS.
+    Tls,
+    /// MQTT over secure websockets.
+    Websocket,
+}
+
+/// The result of a single step.
+#[derive(Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Fail,
+    Skip,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Pass => write!(f, "PASS"),
+            Status::Fail => write!(f, "FAIL"),
+            Status::Skip => write!(f, "SKIP"),
+        }
+    }
+}
+
+/// A step of the check with its result.
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct Step {
+    pub name: &'static str,
+    pub status: Status,
+    /// The time the step took in milliseconds, absent for skipped steps.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u128>,
+    pub detail: String,
+}
+
+/// The outcome of all steps of the check.
+#[derive(Serialize, Debug)]
+pub struct CheckReport {
+    pub passed: bool,
+    pub profile: String,
+    pub steps: Vec<Step>,
+    /// The error of the failed step, which determines the exit code.
+    #[serde(skip)]
+    error: Option<DshError>,
+}
+
+impl CheckReport {
+    fn new(profile: &str) -> CheckReport {
+        CheckReport {
+            passed: true,
+            profile: profile.to_string(),
+            steps: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Run a step and record its result and duration.
+    ///
+    /// The step returns its value together with a description of what it found.
+    async fn run<T>(
+        &mut self,
+        name: &'static str,
+        step: impl Future<Output = Result<(T, String), DshError>>,
+    ) -> Option<T> {
+        let start = Instant::now();
+        let result = step.await;
+        let duration_ms = Some(start.elapsed().as_millis());
+        match result {
+            Ok((value, detail)) => {
+                self.steps.push(Step {
+                    name,
+                    status: Status::Pass,
+                    duration_ms,
+                    detail,
+                });
+                Some(value)
+            }
+            Err(e) => {
+                self.steps.push(Step {
+                    name,
+                    status: Status::Fail,
+                    duration_ms,
+                    detail: e.to_string(),
+                });
+                self.passed = false;
+                self.error = Some(e);
+                None
+            }
+        }
+    }
+
+    fn skip(&mut self, name: &'static str, reason: &str) {
+        self.steps.push(Step {
+            name,
+            status: Status::Skip,
+            duration_ms: None,
+            detail: reason.to_string(),
+        });
+    }
+
+    /// Skip the steps that were not run because an earlier step failed.
+    fn skip_remaining(&mut self) {
+        for name in STEPS.iter().skip(self.steps.len()) {
+            self.skip(name, "an earlier step failed");
+        }
+    }
+
+    /// Returns the error of the failed step, if any.
+    pub fn into_result(self) -> Result<(), DshError> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Testing the configuration of profile '{}'", self.profile)?;
+        for step in &self.steps {
+            let duration = match step.duration_ms {
+                Some(duration_ms) => format!("{} ms", duration_ms),
+                None => String::new(),
+            };
+            writeln!(
+                f,
+                "{} {:<13} {:>8}  {}",
+                step.status, step.name, duration, step.detail
+            )?;
+        }
+        write!(f, "{}", if self.passed { "PASSED" } else { "FAILED" })
+    }
+}
+
+/// Run every step of the check and return the report, the steps after a failed step are
+/// skipped.
+pub async fn check(cmd: &TestCommand) -> CheckReport {
+    let mut report = CheckReport::new(profile::selected());
+
+    let resolved = report.run("config", async { resolve_config(cmd) }).await;
+    let (config, ra) = match resolved {
+        Some(config) => {
+            let ra = RequestAttributes::for_config(&config);
+            (config, ra)
+        }
+        None => {
+            report.skip_remaining();
+            return report;
+        }
+    };
+    let deadline = ra.retry.deadline_from_now();
+
+    let auth_url = ra.endpoints.auth_url(&ra.domain);
+    let resolved = report.run("dns", lookup(&auth_url)).await;
+    let rest_token = match resolved {
+        Some(()) => {
+            report
+                .run("rest-token", async {
+                    let rest_token = request_rest_token(&ra, deadline).await?;
+                    Ok((rest_token, format!("tenant '{}' authenticated", ra.tenant)))
+                })
+                .await
+        }
+        None => None,
+    };
+    let token = match &rest_token {
+        Some(rest_token) => {
+            report
+                .run("mqtt-token", async {
+                    let token = request_mqtt_token(rest_token, &ra, deadline).await?;
+                    let detail = describe_token(&token);
+                    Ok((token, detail))
+                })
+                .await
+        }
+        None => None,
+    };
+    match token {
+        Some(token) if cmd.connect => {
+            let websocket = match cmd.transport {
+                Some(transport) => transport == Transport::Websocket,
+                None => config.websocket,
+            };
+            report
+                .run("mqtt-connect", connect(token, config.port, websocket))
+                .await;
+        }
+        Some(_) => report.skip("mqtt-connect", "use --connect to connect to the broker"),
+        None => report.skip_remaining(),
+    }
+    report
+}
+
+/// Resolve the configuration like `dsh tf`, with the options of the command as flags.
+fn resolve_config(cmd: &TestCommand) -> Result<(Config, String), DshError> {
+    let resolved = resolve::resolve(PartialConfig {
+        tenant: cmd.tenant.clone(),
+        api_key: cmd.api_key.clone(),
+        domain: cmd.domain.clone(),
+        platform: cmd.platform.clone(),
+        ..Default::default()
+    })?;
+    let config = Config {
+        tenant: resolved.tenant()?,
+        api_key: resolved.api_key()?,
+        domain: resolved.domain()?,
+        port: resolved.port(),
+        websocket: resolved.websocket(),
+        platform: resolved.platform.as_ref().map(|p| p.value.clone()),
+        endpoints: resolved.endpoints(),
+    };
+    let detail = format!("tenant '{}' on {}", config.tenant, config.domain);
+    Ok((config, detail))
+}
+
+/// Resolve the host of a URL to its addresses.
+async fn lookup(url: &str) -> Result<((), String), DshError> {
+    let (host, port) = host_and_port(url)?;
+    let addresses: Vec<_> = tokio::net::lookup_host((host.as_str(), port))
+        .await
+        .map_err(|e| DshError::Network(format!("could not resolve {}: {}", host, e)))?
+        .map(|address| address.ip().to_string())
+        .collect();
+    Ok(((), format!("{} resolved to {}", host, addresses.join(", "))))
+}
+
+/// Returns the host and port of a URL, the port defaulting to the port of the scheme.
+fn host_and_port(url: &str) -> Result<(String, u16), DshError> {
+    let parsed = reqwest::Url::parse(url)
+        .map_err(|e| DshError::DshCli(format!("Invalid URL '{}': {}", url, e)))?;
+    match (parsed.host_str(), parsed.port_or_known_default()) {
+        (Some(host), Some(port)) => Ok((host.to_string(), port)),
+        _ => Err(DshError::DshCli(format!("Invalid URL '{}': no host", url))),
+    }
+}
+
+/// Returns the endpoint and ports of an MQTT token.
+fn describe_token(token: &Token) -> String {
+    let attributes = &token.token_attributes;
+    let ports = |ports: &[u16]| {
+        ports
+            .iter()
+            .map(u16::to_string)
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    format!(
+        "endpoint {}, ports {} (tls), {} (websocket)",
+        attributes.endpoint,
+        ports(&attributes.ports.mqtts),
+        ports(&attributes.ports.mqttwss)
+    )
+}
+
+/// Connect to the endpoint of the token, on the configured port when the token allows it,
+/// otherwise on the first port of the token for the transport.
+async fn connect(token: Token, port: u16, websocket: bool) -> Result<((), String), DshError> {
+    let ports = if websocket {
+        &token.token_attributes.ports.mqttwss
+    } else {
+        &token.token_attributes.ports.mqtts
+    };
+    let port = match ports.first() {
+        Some(_) if ports.contains(&port) => port,
+        Some(first) => *first,
+        None => return Err(DshError::PortNotPresentInToken(port)),
+    };
+    let endpoint = token.token_attributes.endpoint.clone();
+    let client = Client::new(token, port, String::new(), websocket, false, false, None).await?;
+    client.check_connection(CONNECT_TIMEOUT).await?;
+    let transport = if websocket { "websocket" } else { "tls" };
+    Ok((
+        (),
+        format!("connected to {}:{} ({})", endpoint, port, transport),
+    ))
+}
+
+/// Run the configuration test and print the report, failing with the error of the failed step.
+pub async fn run(cmd: &TestCommand) -> Result<(), DshError> {
+    let report = check(cmd).await;
+    if cmd.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("{}", report);
+    }
+    report.into_result()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_and_port() {
+        assert_eq!(
+            host_and_port("https://api.poc.kpn-dsh.com/auth/v0/token").unwrap(),
+            ("api.poc.kpn-dsh.com".to_string(), 443)
+        );
+        assert_eq!(
+            host_and_port("http://localhost:8080/auth/v0/token").unwrap(),
+            ("localhost".to_string(), 8080)
+        );
+        assert!(host_and_port("not a url").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_report_skips_after_failure() {
+        let mut report = CheckReport::new("default");
+        let value = report
+            .run("config", async { Ok((1, "ok".to_string())) })
+            .await;
+        assert_eq!(value, Some(1));
+        let value: Option<()> = report
+            .run("dns", async {
+                Err(DshError::Network("could not resolve".to_string()))
+            })
+            .await;
+        assert_eq!(value, None);
+        report.skip_remaining();
+
+        let statuses: Vec<_> = report.steps.iter().map(|step| step.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                Status::Pass,
+                Status::Fail,
+                Status::Skip,
+                Status::Skip,
+                Status::Skip
+            ]
+        );
+        assert!(!report.passed);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["passed"], false);
+        assert_eq!(json["steps"][1]["status"], "fail");
+        assert!(json["steps"][2].get("duration_ms").is_none());
+        assert!(report.to_string().contains("FAIL dns"));
+        assert!(matches!(report.into_result(), Err(DshError::Network(_))));
+    }
+}
@@ -0,0 +1,277 @@
+use super::platform::{platforms, Platform};
+use super::{profile, Config};
+use crate::error::DshError;
+use crate::tf::token::Token;
+use crate::tf::{request_mqtt_token, request_rest_token, RequestAttributes};
+use std::io::{self, BufRead, Write};
+
+/// Run the setup wizard for the selected profile.
+///
+/// Asks for the platform, tenant and API key, with the current configuration as defaults,
+/// and verifies them by requesting a REST token and an MQTT token. Then asks for the
+/// transport and one of the ports of the token for it. The configuration is only saved when
+/// the verification succeeds.
+pub async fn run() -> Result<(), DshError> {
+    let mut config = Config::load(None)?;
+    let platforms = platforms()?;
+    println!("Setting up profile '{}'\n", profile::selected());
+
+    println!("Platforms:");
+    for (number, platform) in platforms.iter().enumerate() {
+        println!(
+            "  {}) {:<10} {}",
+            number + 1,
+            platform.name,
+            platform.description
+        );
+    }
+    let default = config
+        .platform
+        .as_deref()
+        .and_then(|name| platforms.iter().position(|p| p.is_named(name)))
+        .unwrap_or(0);
+    let platform = loop {
+        let answer = prompt(&format!("Platform [{}]: ", platforms[default].name))?;
+        match choose_platform(&answer, &platforms, default) {
+            Ok(platform) => break platform.clone(),
+            Err(e) => eprintln!("{}", e),
+        }
+    };
+
+    let tenant = loop {
+        let answer = if config.tenant.is_empty() {
+            prompt("Tenant: ")?
+        } else {
+            prompt(&format!("Tenant [{}]: ", config.tenant))?
+        };
+        match answer_or_current(answer, &config.tenant) {
+            Some(tenant) => break tenant,
+            None => eprintln!("The tenant is required."),
+        }
+    };
+
+    let api_key = loop {
+        let question = if config.api_key.is_empty() {
+            "API key (hidden): "
+        } else {
+            "API key (hidden, empty keeps the current key): "
+        };
+        match answer_or_current(rpassword::prompt_password(question)?, &config.api_key) {
+            Some(api_key) => break api_key,
+            None => eprintln!("The API key is required."),
+        }
+    };
+
+    config.tenant = tenant;
+    config.api_key = api_key;
+    config.domain = platform.domain;
+    config.endpoints = platform.endpoints;
+    config.platform = Some(platform.name);
+
+    let token = match verify(&config).await {
+        Ok(token) => token,
+        Err(e) => {
+            eprintln!("The configuration was not saved.");
+            return Err(e);
+        }
+    };
+
+    let current = if config.websocket { "websocket" } else { "tls" };
+    config.websocket = loop {
+        let answer = prompt(&format!("\nTransport, tls or websocket [{}]: ", current))?;
+        match choose_transport(&answer, config.websocket) {
+            Ok(websocket) => break websocket,
+            Err(e) => eprintln!("{}", e),
+        }
+    };
+    let ports = if config.websocket {
+        &token.token_attributes.ports.mqttwss
+    } else {
+        &token.token_attributes.ports.mqtts
+    };
+    config.port = match ports.first() {
+        Some(first) => {
+            let default = if ports.contains(&config.port) {
+                config.port
+            } else {
+                *first
+            };
+            loop {
+                let answer = prompt(&format!("Port ({}) [{}]: ", join_ports(ports), default))?;
+                match choose_port(&answer, ports, default) {
+                    Ok(port) => break port,
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+        }
+        // the token has no ports for the transport, use the MQTT port of the platform
+        None => 0,
+    };
+
+    config.save(None)?;
+    println!(
+        "\nSaved the configuration of profile '{}'",
+        profile::selected()
+    );
+    Ok(())
+}
+
+/// Request a REST token and an MQTT token with the configuration, showing the MQTT endpoint.
+async fn verify(config: &Config) -> Result<Token, DshError> {
+    let ra = RequestAttributes::for_config(config);
+    let deadline = ra.retry.deadline_from_now();
+    println!("\nVerifying the configuration on {}", config.domain);
+
+    let rest_token = request_rest_token(&ra, deadline).await?;
+    println!("  REST token:    ok");
+    let token = request_mqtt_token(&rest_token, &ra, deadline).await?;
+    println!("  MQTT token:    ok");
+
+    let attributes = &token.token_attributes;
+    println!("  MQTT endpoint: {}", attributes.endpoint);
+    println!(
+        "  MQTT ports:    {} (tls), {} (websocket)",
+        join_ports(&attributes.ports.mqtts),
+        join_ports(&attributes.ports.mqttwss)
+    );
+    Ok(token)
+}
+
+/// Ask a question on stdout and read the answer from stdin.
+fn prompt(question: &str) -> Result<String, DshError> {
+    print!("{}", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer)? == 0 {
+        return Err(DshError::DshCli(
+            "No input, 'dsh config init' must be run interactively.".to_string(),
+        ));
+    }
+    Ok(answer.trim().to_string())
+}
+
+/// Returns the answer, or the current value for an empty answer, `None` if both are empty.
+fn answer_or_current(answer: String, current: &str) -> Option<String> {
+    let answer = answer.trim();
+    if !answer.is_empty() {
+        Some(answer.to_string())
+    } else if !current.is_empty() {
+        Some(current.to_string())
+    } else {
+        None
+    }
+}
+
+/// Choose a platform by its number in the list or its name, an empty answer chooses the
+/// platform at index `default`.
+fn choose_platform<'a>(
+    answer: &str,
+    platforms: &'a [Platform],
+    default: usize,
+) -> Result<&'a Platform, DshError> {
+    let answer = answer.trim();
+    if answer.is_empty() {
+        return Ok(&platforms[default]);
+    }
+    if let Ok(number) = answer.parse::<usize>() {
+        return number
+            .checked_sub(1)
+            .and_then(|index| platforms.get(index))
+            .ok_or_else(|| {
+                DshError::DshCli(format!("Choose a number from 1 to {}.", platforms.len()))
+            });
+    }
+    platforms
+        .iter()
+        .find(|platform| platform.is_named(answer))
+        .ok_or_else(|| DshError::DshCli(format!("Unknown platform '{}'.", answer)))
+}
+
+/// Choose the transport by its name, an empty answer keeps the current transport. Returns
+/// whether websockets are used.
+fn choose_transport(answer: &str, websocket: bool) -> Result<bool, DshError> {
+    match answer.trim() {
+        "" => Ok(websocket),
+        "tls" => Ok(false),
+        "websocket" => Ok(true),
+        answer => Err(DshError::DshCli(format!(
+            "Unknown transport '{}', choose tls or websocket.",
+            answer
+        ))),
+    }
+}
+
+/// Choose one of the ports of the token, an empty answer chooses `default`.
+fn choose_port(answer: &str, ports: &[u16], default: u16) -> Result<u16, DshError> {
+    let answer = answer.trim();
+    if answer.is_empty() {
+        return Ok(default);
+    }
+    answer
+        .parse::<u16>()
+        .ok()
+        .filter(|port| ports.contains(port))
+        .ok_or_else(|| DshError::DshCli(format!("Choose one of the ports {}.", join_ports(ports))))
+}
+
+/// Returns the ports as a comma separated list.
+fn join_ports(ports: &[u16]) -> String {
+    ports
+        .iter()
+        .map(u16::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::platform::builtin_platforms;
+
+    #[test]
+    fn test_choose_platform() {
+        let platforms = builtin_platforms();
+        assert_eq!(choose_platform("", &platforms, 1).unwrap().name, "prod");
+        assert_eq!(choose_platform("3", &platforms, 0).unwrap().name, "prodaz");
+        assert_eq!(
+            choose_platform("np-aws-lz-dsh", &platforms, 0)
+                .unwrap()
+                .name,
+            "nplz"
+        );
+        assert!(choose_platform("0", &platforms, 0).is_err());
+        assert!(choose_platform("99", &platforms, 0).is_err());
+        assert!(choose_platform("nope", &platforms, 0).is_err());
+    }
+
+    #[test]
+    fn test_answer_or_current() {
+        assert_eq!(
+            answer_or_current(" new ".to_string(), "old"),
+            Some("new".to_string())
+        );
+        assert_eq!(
+            answer_or_current("".to_string(), "old"),
+            Some("old".to_string())
+        );
+        assert_eq!(answer_or_current("".to_string(), ""), None);
+    }
+
+    #[test]
+    fn test_choose_transport() {
+        assert!(choose_transport("", true).unwrap());
+        assert!(!choose_transport("", false).unwrap());
+        assert!(!choose_transport(" tls ", true).unwrap());
+        assert!(choose_transport("websocket", false).unwrap());
+        assert!(choose_transport("mqtt", false).is_err());
+    }
+
+    #[test]
+    fn test_choose_port() {
+        let ports = [8883, 1883];
+        assert_eq!(choose_port("", &ports, 8883).unwrap(), 8883);
+        assert_eq!(choose_port("1883", &ports, 8883).unwrap(), 1883);
+        assert!(choose_port("443", &ports, 8883).is_err());
+        assert!(choose_port("http", &ports, 8883).is_err());
+    }
+}
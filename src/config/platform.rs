@@ -0,0 +1,353 @@
+use crate::error::DshError;
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+
+const APP_NAME: &str = "dsh";
+const PLATFORMS_NAME: &str = "platforms";
+
+/// The placeholder in an endpoint URL template which is replaced by the platform domain.
+pub const DOMAIN_PLACEHOLDER: &str = "{domain}";
+
+/// The URLs and MQTT ports of a DSH platform.
+///
+/// The URLs are templates in which `{domain}` is replaced by the platform domain, so the
+/// same endpoints serve every platform. A URL without placeholder is used as is, e.g.
+/// `http://localhost:8080/auth/v0/token` for a local mock server.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Endpoints {
+    /// The URL to request the REST token of a tenant.
+    pub auth_url: String,
+    /// The URL to request MQTT tokens with a REST token.
+    pub mqtt_token_url: String,
+    /// The base URL of the REST APIs of the platform.
+    pub rest_api_base: String,
+    /// The MQTT port for connections over TLS.
+    pub mqtts_port: u16,
+    /// The MQTT port for connections over secure websockets.
+    pub mqttwss_port: u16,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Endpoints {
+            auth_url: "https://api.{domain}/auth/v0/token".to_string(),
+            mqtt_token_url: "https://api.{domain}/datastreams/v0/mqtt/token".to_string(),
+            rest_api_base: "https://api.{domain}".to_string(),
+            mqtts_port: 8883,
+            mqttwss_port: 443,
+        }
+    }
+}
+
+impl Endpoints {
+    /// Returns the URL to request the REST token on `domain`.
+    pub fn auth_url(&self, domain: &str) -> String {
+        render(&self.auth_url, domain)
+    }
+
+    /// Returns the URL to request MQTT tokens on `domain`.
+    pub fn mqtt_token_url(&self, domain: &str) -> String {
+        render(&self.mqtt_token_url, domain)
+    }
+
+    /// Returns the base URL of the REST APIs on `domain`.
+    pub fn rest_api_base(&self, domain: &str) -> String {
+        render(&self.rest_api_base, domain)
+    }
+
+    /// Returns the MQTT port of the platform for the given transport.
+    pub fn mqtt_port(&self, websocket: bool) -> u16 {
+        if websocket {
+            self.mqttwss_port
+        } else {
+            self.mqtts_port
+        }
+    }
+}
+
+impl fmt::Display for Endpoints {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Auth URL: {}\nMQTT token URL: {}\nREST API base: {}\nMQTT ports: {} (tls), {} (websocket)",
+            self.auth_url, self.mqtt_token_url, self.rest_api_base, self.mqtts_port, self.mqttwss_port
+        )
+    }
+}
+
+/// A DSH platform which can be selected by name, e.g. with `dsh config --platform poc`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Platform {
+    /// The short name of the platform.
+    pub name: String,
+    /// Other names the platform is known by.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    #[serde(default)]
+    pub description: String,
+    /// The domain of the platform, to which the endpoints add the `api.` prefix.
+    pub domain: String,
+    #[serde(default)]
+    pub endpoints: Endpoints,
+}
+
+impl Platform {
+    fn builtin(name: &str, alias: &str, description: &str, domain: &str) -> Platform {
+        Platform {
+            name: name.to_string(),
+            aliases: vec![alias.to_string()],
+            description: description.to_string(),
+            domain: domain.to_string(),
+            endpoints: Endpoints::default(),
+        }
+    }
+
+    /// Returns whether the platform is known by `name`, ignoring case.
+    pub fn is_named(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || self
+                .aliases
+                .iter()
+                .any(|alias| alias.eq_ignore_ascii_case(name))
+    }
+}
+
+/// The platforms defined in the platforms file.
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct CustomPlatforms {
+    #[serde(default)]
+    platforms: Vec<Platform>,
+}
+
+/// Subcommands for the known platforms.
+#[derive(Subcommand, Debug)]
+pub enum PlatformsCommand {
+    /// List the built-in and custom platforms.
+    List,
+}
+
+/// Returns the platforms that are known without configuration.
+pub fn builtin_platforms() -> Vec<Platform> {
+    vec![
+        Platform::builtin(
+            "poc",
+            "poc-aws-dsh",
+            "Proof of concept platform on AWS",
+            "poc.kpn-dsh.com",
+        ),
+        Platform::builtin(
+            "prod",
+            "prod-aws-dsh",
+            "Production platform on AWS",
+            "kpn-dsh.com",
+        ),
+        Platform::builtin(
+            "prodaz",
+            "prod-azure-dsh",
+            "Production platform on Azure",
+            "az.kpn-dsh.com",
+        ),
+        Platform::builtin(
+            "prodlz",
+            "prod-aws-lz-dsh",
+            "Production landing zone on AWS",
+            "dsh-prod.dsh.prod.aws.kpn.com",
+        ),
+        Platform::builtin(
+            "nplz",
+            "np-aws-lz-dsh",
+            "Non-production landing zone on AWS",
+            "dsh-dev.dsh.np.aws.kpn.com",
+        ),
+        Platform::builtin(
+            "laas",
+            "prod-aws-lz-laas",
+            "Logging as a service landing zone on AWS",
+            "laas-prod.dsh.prod.aws.kpn.com",
+        ),
+    ]
+}
+
+/// Returns the location of the file with custom platforms.
+pub fn platforms_path() -> Result<PathBuf, DshError> {
+    Ok(confy::get_configuration_file_path(
+        APP_NAME,
+        PLATFORMS_NAME,
+    )?)
+}
+
+/// Load the custom platforms, an absent file results in no custom platforms.
+fn custom_platforms() -> Result<Vec<Platform>, DshError> {
+    let path = platforms_path()?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let custom: CustomPlatforms = confy::load_path(&path)?;
+    Ok(custom.platforms)
+}
+
+/// Combine the built-in and custom platforms, a custom platform replaces a built-in
+/// platform with the same name.
+fn merge_platforms(builtin: Vec<Platform>, custom: Vec<Platform>) -> Vec<Platform> {
+    let mut platforms: Vec<Platform> = builtin
+        .into_iter()
+        .filter(|platform| !custom.iter().any(|c| c.is_named(&platform.name)))
+        .collect();
+    platforms.extend(custom);
+    platforms
+}
+
+/// Returns all known platforms, built-in and from the platforms file.
+pub fn platforms() -> Result<Vec<Platform>, DshError> {
+    Ok(merge_platforms(builtin_platforms(), custom_platforms()?))
+}
+
+/// Find a platform by its name or one of its aliases.
+pub fn find_platform(name: &str) -> Result<Platform, DshError> {
+    let platforms = platforms()?;
+    match platforms.iter().find(|platform| platform.is_named(name)) {
+        Some(platform) => Ok(platform.clone()),
+        None => Err(DshError::DshCli(format!(
+            "Unknown platform '{}', use one of: {}. Run 'dsh platforms list' for details.",
+            name,
+            platforms
+                .iter()
+                .map(|platform| platform.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+    }
+}
+
+/// Run the platforms subcommands.
+pub fn run(cmd: &PlatformsCommand) -> Result<(), DshError> {
+    match cmd {
+        PlatformsCommand::List => {
+            let builtin = builtin_platforms();
+            println!(
+                "{:<10} {:<18} {:<32} DESCRIPTION",
+                "NAME", "ALIASES", "DOMAIN"
+            );
+            for platform in platforms()? {
+                let custom = if builtin.contains(&platform) {
+                    ""
+                } else {
+                    " (custom)"
+                };
+                println!(
+                    "{:<10} {:<18} {:<32} {}{}",
+                    platform.name,
+                    platform.aliases.join(","),
+                    platform.domain,
+                    platform.description,
+                    custom
+                );
+            }
+            eprintln!(
+                "Custom platforms can be added in {}",
+                platforms_path()?.display()
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Replace the domain placeholder in a URL template.
+fn render(template: &str, domain: &str) -> String {
+    template.replace(DOMAIN_PLACEHOLDER, domain)
+}
+
+/// Remove the `api.` prefix from a domain, as the endpoints already add it.
+///
+/// Earlier versions used `api.poc.kpn-dsh.com` as default domain, which resulted in
+/// requests to `api.api.poc.kpn-dsh.com`.
+pub fn normalize_domain(domain: &str) -> String {
+    domain.strip_prefix("api.").unwrap_or(domain).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_endpoints() {
+        let endpoints = Endpoints::default();
+        assert_eq!(
+            endpoints.auth_url("poc.kpn-dsh.com"),
+            "https://api.poc.kpn-dsh.com/auth/v0/token"
+        );
+        assert_eq!(
+            endpoints.mqtt_token_url("poc.kpn-dsh.com"),
+            "https://api.poc.kpn-dsh.com/datastreams/v0/mqtt/token"
+        );
+        assert_eq!(endpoints.mqtt_port(true), 443);
+        assert_eq!(endpoints.mqtt_port(false), 8883);
+    }
+
+    #[test]
+    fn test_endpoints_without_placeholder() {
+        let endpoints = Endpoints {
+            auth_url: "http://localhost:8080/auth/v0/token".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            endpoints.auth_url("poc.kpn-dsh.com"),
+            "http://localhost:8080/auth/v0/token"
+        );
+    }
+
+    #[test]
+    fn test_normalize_domain() {
+        assert_eq!(normalize_domain("api.poc.kpn-dsh.com"), "poc.kpn-dsh.com");
+        assert_eq!(normalize_domain("poc.kpn-dsh.com"), "poc.kpn-dsh.com");
+    }
+
+    #[test]
+    fn test_builtin_platform_names_are_unique() {
+        let platforms = builtin_platforms();
+        for platform in &platforms {
+            let named = platforms
+                .iter()
+                .filter(|other| other.is_named(&platform.name))
+                .count();
+            assert_eq!(named, 1, "{}", platform.name);
+        }
+    }
+
+    #[test]
+    fn test_custom_platforms() {
+        let custom: CustomPlatforms = toml::from_str(
+            r#"
+            [[platforms]]
+            name = "local"
+            domain = "localhost"
+            endpoints = { auth_url = "http://localhost:8080/auth/v0/token" }
+
+            [[platforms]]
+            name = "POC"
+            domain = "poc.example.com"
+            "#,
+        )
+        .unwrap();
+        let platforms = merge_platforms(builtin_platforms(), custom.platforms);
+        assert_eq!(platforms.len(), builtin_platforms().len() + 1);
+
+        let local = platforms.iter().find(|p| p.is_named("local")).unwrap();
+        assert_eq!(
+            local.endpoints.auth_url(&local.domain),
+            "http://localhost:8080/auth/v0/token"
+        );
+        let poc = platforms.iter().find(|p| p.is_named("poc")).unwrap();
+        assert_eq!(poc.domain, "poc.example.com");
+    }
+
+    #[test]
+    fn test_deserialize_partial_endpoints() {
+        let endpoints: Endpoints = serde_json::from_str(r#"{"mqtts_port": 1883}"#).unwrap();
+        assert_eq!(endpoints.mqtts_port, 1883);
+        assert_eq!(endpoints.auth_url, Endpoints::default().auth_url);
+    }
+}
@@ -0,0 +1,397 @@
+use super::store::{ConfigStore, StoreKind};
+use super::{Config, CONFIG_KEY};
+use crate::error::DshError;
+use clap::Subcommand;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+const APP_NAME: &str = "dsh";
+const PROFILES_NAME: &str = "profiles";
+
+/// The profile which always exists, stored under the original configuration key.
+pub const DEFAULT_PROFILE: &str = "default";
+
+// The profile selected for this run and its store, see `select`
+static SELECTED_PROFILE: OnceCell<(String, StoreKind)> = OnceCell::new();
+
+/// Subcommands for managing configuration profiles.
+#[derive(Subcommand, Debug)]
+pub enum ProfileCommand {
+    /// Create a profile with the default configuration.
+    Create {
+        /// The name of the profile, consisting of letters, digits, '-' and '_'.
+        name: String,
+        /// Use the profile from now on.
+        #[clap(long = "use")]
+        activate: bool,
+        /// Where to keep the configuration of the profile.
+        #[clap(long, value_enum, default_value_t = StoreKind::Keyring)]
+        store: StoreKind,
+    },
+    /// List the profiles and their stores, marking the active profile with '*'.
+    List,
+    /// Use a profile from now on, unless another profile is given with '--profile'.
+    Use {
+        /// The name of the profile.
+        name: String,
+    },
+    /// Delete a profile and its configuration from its store.
+    Delete {
+        /// The name of the profile.
+        name: String,
+    },
+    /// Move the configuration of a profile to another store.
+    Store {
+        /// The name of the profile.
+        name: String,
+        /// Where to keep the configuration of the profile from now on.
+        #[clap(value_enum)]
+        store: StoreKind,
+        /// Move to the file store even though it does not keep the API key of the profile.
+        #[clap(long)]
+        force: bool,
+    },
+}
+
+/// The names of the profiles and the active profile, stored in a file next to the
+/// platforms file. The configuration of every profile is kept in the store of the profile,
+/// by default the OS secret store.
+#[derive(Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct Profiles {
+    /// The profile used when no profile is given, the default profile if none.
+    #[serde(default)]
+    active: Option<String>,
+    /// The profiles besides the default profile.
+    #[serde(default)]
+    profiles: Vec<String>,
+    /// The stores of the profiles which do not use the OS secret store.
+    #[serde(default)]
+    stores: BTreeMap<String, StoreKind>,
+}
+
+impl Profiles {
+    /// Returns the location of the profiles file.
+    pub fn path() -> Result<PathBuf, DshError> {
+        Ok(confy::get_configuration_file_path(APP_NAME, PROFILES_NAME)?)
+    }
+
+    /// Load the profiles, an absent file results in only the default profile.
+    pub fn load() -> Result<Profiles, DshError> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(Profiles::default());
+        }
+        Ok(confy::load_path(&path)?)
+    }
+
+    /// Save the profiles.
+    pub fn save(&self) -> Result<(), DshError> {
+        Ok(confy::store_path(Self::path()?, self)?)
+    }
+
+    /// Returns the name of the active profile.
+    pub fn active(&self) -> &str {
+        self.active.as_deref().unwrap_or(DEFAULT_PROFILE)
+    }
+
+    /// Returns the names of all profiles, starting with the default profile.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = vec![DEFAULT_PROFILE];
+        names.extend(self.profiles.iter().map(String::as_str));
+        names
+    }
+
+    /// Returns whether a profile with the given name exists.
+    pub fn contains(&self, name: &str) -> bool {
+        self.names().contains(&name)
+    }
+
+    /// Add a profile.
+    pub fn create(&mut self, name: &str) -> Result<(), DshError> {
+        validate_name(name)?;
+        if self.contains(name) {
+            return Err(DshError::DshCli(format!(
+                "Profile '{}' already exists.",
+                name
+            )));
+        }
+        self.profiles.push(name.to_string());
+        self.profiles.sort();
+        Ok(())
+    }
+
+    /// Returns the store of a profile.
+    pub fn store(&self, name: &str) -> StoreKind {
+        self.stores.get(name).copied().unwrap_or_default()
+    }
+
+    /// Set the store of an existing profile.
+    pub fn set_store(&mut self, name: &str, store: StoreKind) -> Result<(), DshError> {
+        self.check_exists(name)?;
+        if store == StoreKind::default() {
+            self.stores.remove(name);
+        } else {
+            self.stores.insert(name.to_string(), store);
+        }
+        Ok(())
+    }
+
+    /// Make an existing profile the active profile.
+    pub fn activate(&mut self, name: &str) -> Result<(), DshError> {
+        self.check_exists(name)?;
+        self.active = (name != DEFAULT_PROFILE).then(|| name.to_string());
+        Ok(())
+    }
+
+    /// Remove a profile, the default profile becomes active when it was the active profile.
+    pub fn delete(&mut self, name: &str) -> Result<(), DshError> {
+        if name == DEFAULT_PROFILE {
+            return Err(DshError::DshCli(
+                "The default profile can not be deleted.".to_string(),
+            ));
+        }
+        self.check_exists(name)?;
+        self.profiles.retain(|profile| profile != name);
+        self.stores.remove(name);
+        if self.active.as_deref() == Some(name) {
+            self.active = None;
+        }
+        Ok(())
+    }
+
+    fn check_exists(&self, name: &str) -> Result<(), DshError> {
+        if self.contains(name) {
+            Ok(())
+        } else {
+            Err(DshError::ConfigMissing(format!(
+                "Profile '{}' does not exist. Please use 'dsh config profile create {}' to create it.",
+                name, name
+            )))
+        }
+    }
+}
+
+/// Check that a profile name only consists of letters, digits, '-' and '_'.
+fn validate_name(name: &str) -> Result<(), DshError> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(DshError::DshCli(format!(
+            "Invalid profile name '{}', use letters, digits, '-' and '_'.",
+            name
+        )))
+    }
+}
+
+/// Returns the key of the configuration of a profile in the OS secret store.
+pub fn config_key(profile: &str) -> String {
+    if profile == DEFAULT_PROFILE {
+        CONFIG_KEY.to_string()
+    } else {
+        format!("{}_{}", CONFIG_KEY, profile)
+    }
+}
+
+/// Select the profile for this run: the given profile, otherwise the profile in
+/// `DSH_PROFILE`, otherwise the active profile.
+///
+/// Must be called before the configuration is loaded.
+pub fn select(profile: Option<&str>) -> Result<(), DshError> {
+    let profiles = Profiles::load()?;
+    let env_profile = std::env::var("DSH_PROFILE")
+        .ok()
+        .filter(|name| !name.is_empty());
+    let name = match profile.or(env_profile.as_deref()) {
+        Some(name) => {
+            profiles.check_exists(name)?;
+            name.to_string()
+        }
+        None => profiles.active().to_string(),
+    };
+    let store = profiles.store(&name);
+    debug!("Using profile '{}' in the {} store", name, store);
+    let _ = SELECTED_PROFILE.set((name, store));
+    Ok(())
+}
+
+/// Returns the profile selected for this run, the default profile if none was selected.
+pub fn selected() -> &'static str {
+    SELECTED_PROFILE
+        .get()
+        .map(|(name, _)| name.as_str())
+        .unwrap_or(DEFAULT_PROFILE)
+}
+
+/// Returns the store of the profile selected for this run.
+pub fn selected_store() -> StoreKind {
+    SELECTED_PROFILE
+        .get()
+        .map(|(_, store)| *store)
+        .unwrap_or_default()
+}
+
+/// Run the profile subcommands.
+pub fn run(cmd: &ProfileCommand) -> Result<(), DshError> {
+    let mut profiles = Profiles::load()?;
+    match cmd {
+        ProfileCommand::Create {
+            name,
+            activate,
+            store,
+        } => {
+            profiles.create(name)?;
+            profiles.set_store(name, *store)?;
+            store.open()?.save(&config_key(name), &Config::new())?;
+            if *activate {
+                profiles.activate(name)?;
+            }
+            profiles.save()?;
+            println!("Created profile '{}'", name);
+        }
+        ProfileCommand::List => {
+            for name in profiles.names() {
+                let marker = if name == profiles.active() { "*" } else { " " };
+                println!("{} {} ({})", marker, name, profiles.store(name));
+            }
+        }
+        ProfileCommand::Use { name } => {
+            profiles.activate(name)?;
+            profiles.save()?;
+            println!("Using profile '{}'", name);
+        }
+        ProfileCommand::Delete { name } => {
+            let store = profiles.store(name);
+            profiles.delete(name)?;
+            store.open()?.delete(&config_key(name))?;
+            profiles.save()?;
+            println!("Deleted profile '{}'", name);
+        }
+        ProfileCommand::Store { name, store, force } => {
+            let current = profiles.store(name);
+            profiles.set_store(name, *store)?;
+            if current != *store {
+                let key = config_key(name);
+                let (from, to) = (current.open()?, store.open()?);
+                let mut config = from.load(&key)?.unwrap_or_default();
+                if *store == StoreKind::File && !config.api_key.is_empty() {
+                    if !force {
+                        return Err(DshError::DshCli(format!(
+                            "The file store does not keep the API key of profile '{}'. Use --force to move it anyway and set the API key in DSH_API_KEY.",
+                            name
+                        )));
+                    }
+                    config.api_key.clear();
+                }
+                move_config(&key, &config, from.as_ref(), to.as_ref())?;
+            }
+            profiles.save()?;
+            println!("Profile '{}' uses the {} store", name, store);
+        }
+    }
+    Ok(())
+}
+
+/// Store `config` under `key` in `to` and remove it from `from`, once `to` returns it
+/// unchanged.
+fn move_config(
+    key: &str,
+    config: &Config,
+    from: &dyn ConfigStore,
+    to: &dyn ConfigStore,
+) -> Result<(), DshError> {
+    to.save(key, config)?;
+    if to.load(key)?.as_ref() != Some(config) {
+        return Err(DshError::DshCli(
+            "The configuration was not moved, the new store did not return it unchanged."
+                .to_string(),
+        ));
+    }
+    from.delete(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::store::{FileStore, MemoryStore};
+    use crate::test_util::test_dir;
+
+    #[test]
+    fn test_profiles() {
+        let mut profiles = Profiles::default();
+        assert_eq!(profiles.active(), DEFAULT_PROFILE);
+
+        profiles.create("prod").unwrap();
+        profiles.create("acc").unwrap();
+        assert!(profiles.create("prod").is_err());
+        assert!(profiles.create("no spaces").is_err());
+        assert_eq!(profiles.names(), vec![DEFAULT_PROFILE, "acc", "prod"]);
+
+        profiles.activate("prod").unwrap();
+        assert_eq!(profiles.active(), "prod");
+        assert!(matches!(
+            profiles.activate("nope"),
+            Err(DshError::ConfigMissing(_))
+        ));
+
+        profiles.set_store("prod", StoreKind::File).unwrap();
+        assert_eq!(profiles.store("prod"), StoreKind::File);
+        assert_eq!(profiles.store("acc"), StoreKind::Keyring);
+        assert!(profiles.set_store("nope", StoreKind::Vault).is_err());
+
+        profiles.delete("prod").unwrap();
+        assert_eq!(profiles.active(), DEFAULT_PROFILE);
+        assert_eq!(profiles.store("prod"), StoreKind::Keyring);
+        assert!(profiles.delete(DEFAULT_PROFILE).is_err());
+        assert!(profiles.delete("prod").is_err());
+    }
+
+    #[test]
+    fn test_config_key() {
+        assert_eq!(config_key(DEFAULT_PROFILE), "dsh_config");
+        assert_eq!(config_key("prod"), "dsh_config_prod");
+    }
+
+    #[test]
+    fn test_move_config() {
+        let dir = test_dir("move-config");
+        let config = Config {
+            tenant: "tenant".to_string(),
+            ..Config::default()
+        };
+        MemoryStore.save("dsh_config_prod", &config).unwrap();
+        move_config(
+            "dsh_config_prod",
+            &config,
+            &MemoryStore,
+            &FileStore::new(&dir),
+        )
+        .unwrap();
+        assert_eq!(MemoryStore.load("dsh_config_prod").unwrap(), None);
+        assert_eq!(
+            FileStore::new(&dir).load("dsh_config_prod").unwrap(),
+            Some(config)
+        );
+
+        // the source is kept when the target loses part of the configuration
+        let config = Config {
+            api_key: "secret".to_string(),
+            ..Config::default()
+        };
+        MemoryStore.save("dsh_config_acc", &config).unwrap();
+        assert!(move_config(
+            "dsh_config_acc",
+            &config,
+            &MemoryStore,
+            &FileStore::new(&dir)
+        )
+        .is_err());
+        assert_eq!(MemoryStore.load("dsh_config_acc").unwrap(), Some(config));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,578 @@
+use super::platform::{find_platform, normalize_domain, Endpoints};
+use super::{mask_api_key, profile, store, Config};
+use crate::error::DshError;
+use serde::Deserialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// The name of the project-local configuration file, searched in the current directory
+/// and its parents.
+pub const PROJECT_FILE: &str = ".dsh.toml";
+
+/// Where an effective configuration value came from.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Source {
+    /// A command-line flag.
+    Flag,
+    /// A `DSH_*` environment variable, named after the setting in a resolved value.
+    Env(&'static str),
+    /// The project-local configuration file.
+    ProjectFile(PathBuf),
+    /// The configuration of a profile in the OS secret store.
+    Profile(String),
+    /// The built-in default.
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Flag => write!(f, "command-line flag"),
+            Source::Env(name) => write!(f, "environment variable {}", name),
+            Source::ProjectFile(path) => write!(f, "project file {}", path.display()),
+            Source::Profile(name) => write!(f, "profile '{}'", name),
+            Source::Default => write!(f, "default"),
+        }
+    }
+}
+
+/// An effective configuration value together with its source.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Resolved<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+/// Configuration values of a single layer, every value is optional.
+///
+/// This is also the format of the project file, which can not contain an API key as it
+/// is usually committed together with the project.
+#[derive(Debug, Default, Clone, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PartialConfig {
+    pub tenant: Option<String>,
+    pub api_key: Option<String>,
+    pub domain: Option<String>,
+    /// A known platform, setting the domain and endpoints unless given in the same layer.
+    pub platform: Option<String>,
+    pub port: Option<u16>,
+    pub websocket: Option<bool>,
+    pub auth_url: Option<String>,
+    pub mqtt_token_url: Option<String>,
+    pub rest_api_base: Option<String>,
+    pub mqtts_port: Option<u16>,
+    pub mqttwss_port: Option<u16>,
+}
+
+impl PartialConfig {
+    /// Read the layer from `DSH_*` environment variables, using `var` to look them up.
+    pub fn from_env(var: impl Fn(&str) -> Option<String>) -> Result<PartialConfig, DshError> {
+        let string = |name: &str| var(name).filter(|value| !value.is_empty());
+        let parse = |name: &str| -> Result<Option<u16>, DshError> {
+            match string(name) {
+                Some(value) => value.parse().map(Some).map_err(|_| {
+                    DshError::DshCli(format!("Invalid port '{}' in {}.", value, name))
+                }),
+                None => Ok(None),
+            }
+        };
+        let websocket = match string("DSH_WEBSOCKET").as_deref() {
+            Some("true" | "1") => Some(true),
+            Some("false" | "0") => Some(false),
+            Some(value) => {
+                return Err(DshError::DshCli(format!(
+                    "Invalid value '{}' in DSH_WEBSOCKET, use true or false.",
+                    value
+                )))
+            }
+            None => None,
+        };
+        Ok(PartialConfig {
+            tenant: string("DSH_TENANT"),
+            api_key: string("DSH_API_KEY"),
+            domain: string("DSH_DOMAIN"),
+            platform: string("DSH_PLATFORM"),
+            port: parse("DSH_PORT")?,
+            websocket,
+            auth_url: string("DSH_AUTH_URL"),
+            mqtt_token_url: string("DSH_MQTT_TOKEN_URL"),
+            rest_api_base: string("DSH_REST_API_BASE"),
+            mqtts_port: parse("DSH_MQTTS_PORT")?,
+            mqttwss_port: parse("DSH_MQTTWSS_PORT")?,
+        })
+    }
+
+    /// Read the layer from a project file.
+    pub fn from_project_file(path: &Path) -> Result<PartialConfig, DshError> {
+        let contents = std::fs::read_to_string(path)?;
+        let layer: PartialConfig = toml::from_str(&contents).map_err(|e| {
+            DshError::DshCli(format!("Invalid project file {}: {}", path.display(), e))
+        })?;
+        if layer.api_key.is_some() {
+            return Err(DshError::DshCli(format!(
+                "The project file {} must not contain an api_key, use DSH_API_KEY or a profile instead.",
+                path.display()
+            )));
+        }
+        Ok(layer)
+    }
+
+    /// The layer of a stored configuration, where empty values and values equal to the
+    /// defaults are not set, so they are reported as defaults.
+    pub fn from_config(config: &Config) -> PartialConfig {
+        let defaults = PartialConfig::defaults();
+        let mut layer = PartialConfig::from_values(config);
+        macro_rules! unset_defaults {
+            ($($field:ident),*) => {
+                $(
+                    if layer.$field == defaults.$field {
+                        layer.$field = None;
+                    }
+                )*
+            };
+        }
+        unset_defaults!(
+            domain,
+            platform,
+            websocket,
+            auth_url,
+            mqtt_token_url,
+            rest_api_base,
+            mqtts_port,
+            mqttwss_port
+        );
+        layer
+    }
+
+    /// The layer of the built-in defaults.
+    pub fn defaults() -> PartialConfig {
+        PartialConfig::from_values(&Config::default())
+    }
+
+    /// Every value of a configuration, where empty values are not set.
+    fn from_values(config: &Config) -> PartialConfig {
+        let string = |value: &str| (!value.is_empty()).then(|| value.to_string());
+        PartialConfig {
+            tenant: string(&config.tenant),
+            api_key: string(&config.api_key),
+            domain: string(&config.domain),
+            platform: config.platform.clone(),
+            port: (config.port != 0).then_some(config.port),
+            websocket: Some(config.websocket),
+            auth_url: Some(config.endpoints.auth_url.clone()),
+            mqtt_token_url: Some(config.endpoints.mqtt_token_url.clone()),
+            rest_api_base: Some(config.endpoints.rest_api_base.clone()),
+            mqtts_port: Some(config.endpoints.mqtts_port),
+            mqttwss_port: Some(config.endpoints.mqttwss_port),
+        }
+    }
+
+    /// Fill in the domain and endpoints of the platform, if any, which are not set in this layer.
+    ///
+    /// Returns the names of the fields that were filled in.
+    fn expand_platform(&mut self) -> Result<Vec<&'static str>, DshError> {
+        let mut expanded = Vec::new();
+        let name = match &self.platform {
+            Some(name) => name,
+            None => return Ok(expanded),
+        };
+        let complete = self.domain.is_some()
+            && self.auth_url.is_some()
+            && self.mqtt_token_url.is_some()
+            && self.rest_api_base.is_some()
+            && self.mqtts_port.is_some()
+            && self.mqttwss_port.is_some();
+        if complete {
+            return Ok(expanded);
+        }
+        let platform = find_platform(name)?;
+        let endpoints = platform.endpoints;
+        macro_rules! expand {
+            ($field:ident, $value:expr) => {
+                if self.$field.is_none() {
+                    self.$field = Some($value);
+                    expanded.push(stringify!($field));
+                }
+            };
+        }
+        expand!(domain, platform.domain);
+        expand!(auth_url, endpoints.auth_url);
+        expand!(mqtt_token_url, endpoints.mqtt_token_url);
+        expand!(rest_api_base, endpoints.rest_api_base);
+        expand!(mqtts_port, endpoints.mqtts_port);
+        expand!(mqttwss_port, endpoints.mqttwss_port);
+        Ok(expanded)
+    }
+}
+
+/// The effective configuration, with the source of every value.
+#[derive(Debug, Clone)]
+pub struct ResolvedConfig {
+    pub tenant: Option<Resolved<String>>,
+    pub api_key: Option<Resolved<String>>,
+    pub domain: Option<Resolved<String>>,
+    pub platform: Option<Resolved<String>>,
+    pub port: Option<Resolved<u16>>,
+    pub websocket: Option<Resolved<bool>>,
+    pub auth_url: Option<Resolved<String>>,
+    pub mqtt_token_url: Option<Resolved<String>>,
+    pub rest_api_base: Option<Resolved<String>>,
+    pub mqtts_port: Option<Resolved<u16>>,
+    pub mqttwss_port: Option<Resolved<u16>>,
+}
+
+impl ResolvedConfig {
+    /// Combine the layers, where the first layer with a value wins.
+    pub fn from_layers(layers: Vec<(Source, PartialConfig)>) -> Result<ResolvedConfig, DshError> {
+        let mut expanded = Vec::with_capacity(layers.len());
+        for (source, mut layer) in layers {
+            let from_platform = layer.expand_platform()?;
+            expanded.push((source, layer, from_platform));
+        }
+        // the first layer with the field wins, environment variables are named after the field
+        macro_rules! resolve {
+            ($field:ident, $var:literal) => {
+                expanded.iter().find_map(|(source, layer, from_platform)| {
+                    let source = match source {
+                        Source::Env(_) if from_platform.contains(&stringify!($field)) => {
+                            Source::Env("DSH_PLATFORM")
+                        }
+                        Source::Env(_) => Source::Env($var),
+                        source => source.clone(),
+                    };
+                    layer.$field.clone().map(|value| Resolved { value, source })
+                })
+            };
+        }
+        Ok(ResolvedConfig {
+            tenant: resolve!(tenant, "DSH_TENANT"),
+            api_key: resolve!(api_key, "DSH_API_KEY"),
+            domain: resolve!(domain, "DSH_DOMAIN").map(|domain| Resolved {
+                value: normalize_domain(&domain.value),
+                source: domain.source,
+            }),
+            platform: resolve!(platform, "DSH_PLATFORM"),
+            port: resolve!(port, "DSH_PORT"),
+            websocket: resolve!(websocket, "DSH_WEBSOCKET"),
+            auth_url: resolve!(auth_url, "DSH_AUTH_URL"),
+            mqtt_token_url: resolve!(mqtt_token_url, "DSH_MQTT_TOKEN_URL"),
+            rest_api_base: resolve!(rest_api_base, "DSH_REST_API_BASE"),
+            mqtts_port: resolve!(mqtts_port, "DSH_MQTTS_PORT"),
+            mqttwss_port: resolve!(mqttwss_port, "DSH_MQTTWSS_PORT"),
+        })
+    }
+
+    /// Returns the tenant, or an error when it is not configured in any layer.
+    pub fn tenant(&self) -> Result<String, DshError> {
+        required(
+            &self.tenant,
+            "No tenant configured. Please use the config command to set the tenant.",
+        )
+    }
+
+    /// Returns the API key, or an error when it is not configured in any layer.
+    pub fn api_key(&self) -> Result<String, DshError> {
+        required(
+            &self.api_key,
+            "No api_key configured. Please use the config command to set the api_key.",
+        )
+    }
+
+    /// Returns the platform domain, or an error when it is not configured in any layer.
+    pub fn domain(&self) -> Result<String, DshError> {
+        required(
+            &self.domain,
+            "No domain configured. Please use the config command to set the domain.",
+        )
+    }
+
+    /// Returns whether websockets are used, by default they are.
+    pub fn websocket(&self) -> bool {
+        self.websocket
+            .as_ref()
+            .is_none_or(|websocket| websocket.value)
+    }
+
+    /// Returns the MQTT port, by default the MQTT port of the platform for the transport.
+    pub fn port(&self) -> u16 {
+        match &self.port {
+            Some(port) => port.value,
+            None => self.endpoints().mqtt_port(self.websocket()),
+        }
+    }
+
+    /// Returns the endpoints, using the default endpoints for values that are not configured.
+    pub fn endpoints(&self) -> Endpoints {
+        let defaults = Endpoints::default();
+        let value = |resolved: &Option<Resolved<String>>, default: String| match resolved {
+            Some(resolved) => resolved.value.clone(),
+            None => default,
+        };
+        Endpoints {
+            auth_url: value(&self.auth_url, defaults.auth_url),
+            mqtt_token_url: value(&self.mqtt_token_url, defaults.mqtt_token_url),
+            rest_api_base: value(&self.rest_api_base, defaults.rest_api_base),
+            mqtts_port: self
+                .mqtts_port
+                .as_ref()
+                .map_or(defaults.mqtts_port, |port| port.value),
+            mqttwss_port: self
+                .mqttwss_port
+                .as_ref()
+                .map_or(defaults.mqttwss_port, |port| port.value),
+        }
+    }
+
+    /// Returns a line per setting with its effective value and source, masking the API key
+    /// unless `show_secrets` is set.
+    pub fn describe(&self, show_secrets: bool) -> String {
+        fn line<T: fmt::Display>(name: &str, resolved: &Option<Resolved<T>>) -> String {
+            match resolved {
+                Some(resolved) => {
+                    format!("{:<16} {:<48} {}", name, resolved.value, resolved.source)
+                }
+                None => format!("{:<16} {:<48} {}", name, "-", "not set"),
+            }
+        }
+        let api_key = self.api_key.clone().map(|api_key| Resolved {
+            value: if show_secrets {
+                api_key.value
+            } else {
+                mask_api_key(&api_key.value)
+            },
+            source: api_key.source,
+        });
+        [
+            format!("{:<16} {:<48} SOURCE", "SETTING", "VALUE"),
+            line("tenant", &self.tenant),
+            line("api_key", &api_key),
+            line("platform", &self.platform),
+            line("domain", &self.domain),
+            line("port", &self.port),
+            line("websocket", &self.websocket),
+            line("auth_url", &self.auth_url),
+            line("mqtt_token_url", &self.mqtt_token_url),
+            line("rest_api_base", &self.rest_api_base),
+            line("mqtts_port", &self.mqtts_port),
+            line("mqttwss_port", &self.mqttwss_port),
+        ]
+        .join("\n")
+    }
+}
+
+/// Returns a required value, or a missing configuration error with `message`.
+fn required(resolved: &Option<Resolved<String>>, message: &str) -> Result<String, DshError> {
+    match resolved {
+        Some(resolved) => Ok(resolved.value.clone()),
+        None => Err(DshError::ConfigMissing(message.to_string())),
+    }
+}
+
+/// Find the project file in `dir` or one of its parents.
+pub fn find_project_file(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(PROJECT_FILE))
+        .find(|path| path.is_file())
+}
+
+/// Resolve the effective configuration, in order of precedence:
+///
+/// 1. the command-line flags in `flags`,
+/// 2. the `DSH_*` environment variables,
+/// 3. the project file `.dsh.toml` in the current directory or one of its parents,
+/// 4. the configuration of the selected profile in the OS secret store,
+/// 5. the built-in defaults.
+///
+/// An unavailable OS secret store is skipped, so the configuration can be given with
+/// environment variables only, e.g. on CI runners. Other errors of the store are returned.
+pub fn resolve(flags: PartialConfig) -> Result<ResolvedConfig, DshError> {
+    let mut layers = vec![
+        (Source::Flag, flags),
+        (
+            Source::Env("DSH_*"),
+            PartialConfig::from_env(|name| std::env::var(name).ok())?,
+        ),
+    ];
+    if let Some(path) = find_project_file(&std::env::current_dir()?) {
+        let layer = PartialConfig::from_project_file(&path)?;
+        layers.push((Source::ProjectFile(path), layer));
+    }
+    if let Some(config) = skip_unavailable(Config::load(None))? {
+        layers.push((
+            Source::Profile(profile::selected().to_string()),
+            PartialConfig::from_config(&config),
+        ));
+    }
+    layers.push((Source::Default, PartialConfig::defaults()));
+
+    let resolved = ResolvedConfig::from_layers(layers)?;
+    debug!("Resolved configuration: {:?}", resolved);
+    Ok(resolved)
+}
+
+/// Returns the loaded configuration, or None when the OS secret store is unavailable.
+fn skip_unavailable(loaded: Result<Config, DshError>) -> Result<Option<Config>, DshError> {
+    match loaded {
+        Ok(config) => Ok(Some(config)),
+        Err(e) if store::is_unavailable(&e) => {
+            debug!("Skipping the OS secret store: {}", e);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_dir;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> PartialConfig {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        PartialConfig::from_env(|name| vars.get(name).cloned()).unwrap()
+    }
+
+    #[test]
+    fn test_precedence() {
+        let flags = PartialConfig {
+            tenant: Some("flag-tenant".to_string()),
+            ..Default::default()
+        };
+        let profile = PartialConfig::from_config(&Config {
+            tenant: "profile-tenant".to_string(),
+            api_key: "profile-key".to_string(),
+            ..Default::default()
+        });
+        let resolved = ResolvedConfig::from_layers(vec![
+            (Source::Flag, flags),
+            (
+                Source::Env("DSH_*"),
+                env(&[("DSH_API_KEY", "env-key"), ("DSH_TENANT", "")]),
+            ),
+            (Source::Profile("default".to_string()), profile),
+            (Source::Default, PartialConfig::defaults()),
+        ])
+        .unwrap();
+
+        assert_eq!(resolved.tenant().unwrap(), "flag-tenant");
+        assert_eq!(resolved.tenant.as_ref().unwrap().source, Source::Flag);
+        assert_eq!(resolved.api_key().unwrap(), "env-key");
+        assert_eq!(
+            resolved.api_key.as_ref().unwrap().source,
+            Source::Env("DSH_API_KEY")
+        );
+        assert_eq!(resolved.domain.unwrap().source, Source::Default);
+    }
+
+    #[test]
+    fn test_profile_defaults_are_not_set() {
+        let layer = PartialConfig::from_config(&Config {
+            tenant: "tenant".to_string(),
+            websocket: false,
+            ..Default::default()
+        });
+        assert_eq!(
+            layer,
+            PartialConfig {
+                tenant: Some("tenant".to_string()),
+                websocket: Some(false),
+                ..Default::default()
+            }
+        );
+
+        let layer = PartialConfig::from_config(&Config {
+            domain: "kpn-dsh.com".to_string(),
+            platform: Some("prod".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(layer.domain, Some("kpn-dsh.com".to_string()));
+        assert_eq!(layer.platform, Some("prod".to_string()));
+        assert_eq!(layer.auth_url, None);
+    }
+
+    #[test]
+    fn test_env_only() {
+        let resolved = ResolvedConfig::from_layers(vec![
+            (
+                Source::Env("DSH_*"),
+                env(&[
+                    ("DSH_TENANT", "tenant"),
+                    ("DSH_API_KEY", "key"),
+                    ("DSH_PLATFORM", "prod"),
+                    ("DSH_WEBSOCKET", "false"),
+                ]),
+            ),
+            (Source::Default, PartialConfig::defaults()),
+        ])
+        .unwrap();
+        assert_eq!(resolved.domain().unwrap(), "kpn-dsh.com");
+        assert_eq!(
+            resolved.domain.as_ref().unwrap().source,
+            Source::Env("DSH_PLATFORM")
+        );
+        assert!(!resolved.websocket());
+        assert_eq!(resolved.port(), 8883);
+    }
+
+    #[test]
+    fn test_missing_values() {
+        let resolved =
+            ResolvedConfig::from_layers(vec![(Source::Default, PartialConfig::defaults())])
+                .unwrap();
+        assert!(matches!(resolved.tenant(), Err(DshError::ConfigMissing(_))));
+        assert!(matches!(
+            resolved.api_key(),
+            Err(DshError::ConfigMissing(_))
+        ));
+        assert_eq!(resolved.domain().unwrap(), "poc.kpn-dsh.com");
+    }
+
+    #[test]
+    fn test_invalid_env() {
+        let vars = |name: &str| (name == "DSH_PORT").then(|| "http".to_string());
+        assert!(PartialConfig::from_env(vars).is_err());
+    }
+
+    #[test]
+    fn test_skip_unavailable() {
+        let unavailable = keyring::Error::NoStorageAccess("locked".into());
+        assert!(matches!(
+            skip_unavailable(Err(unavailable.into())),
+            Ok(None)
+        ));
+        let unavailable = keyring::Error::PlatformFailure("no dbus".into());
+        assert!(matches!(
+            skip_unavailable(Err(unavailable.into())),
+            Ok(None)
+        ));
+        let corrupt = keyring::Error::BadEncoding(vec![0xff]);
+        assert!(skip_unavailable(Err(corrupt.into())).is_err());
+        assert!(matches!(
+            skip_unavailable(Ok(Config::default())),
+            Ok(Some(_))
+        ));
+    }
+
+    #[test]
+    fn test_project_file() {
+        let dir = test_dir("project");
+        let nested = dir.join("src");
+        std::fs::create_dir_all(&nested).unwrap();
+        let path = dir.join(PROJECT_FILE);
+
+        std::fs::write(&path, "tenant = \"project\"\nplatform = \"nplz\"\n").unwrap();
+        assert_eq!(find_project_file(&nested), Some(path.clone()));
+        let layer = PartialConfig::from_project_file(&path).unwrap();
+        assert_eq!(layer.tenant, Some("project".to_string()));
+
+        std::fs::write(&path, "api_key = \"secret\"\n").unwrap();
+        assert!(PartialConfig::from_project_file(&path).is_err());
+        std::fs::write(&path, "tennant = \"typo\"\n").unwrap();
+        assert!(PartialConfig::from_project_file(&path).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,538 @@
+use super::Config;
+use crate::error::DshError;
+use crate::tf::output::write_private_file;
+use clap::ValueEnum;
+use once_cell::sync::Lazy;
+use securestore::{ErrorKind, KeySource, SecretsManager};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+const APP_NAME: &str = "dsh";
+const SERVICE_NAME: &str = "dsh";
+const VAULT_NAME: &str = "vault";
+
+/// The environment variable with the password of the vault, instead of the vault key file.
+pub const VAULT_PASSWORD_VAR: &str = "DSH_VAULT_PASSWORD";
+
+// Warn only once per run when falling back from the keyring
+static FALLBACK_WARNED: AtomicBool = AtomicBool::new(false);
+
+// The configurations loaded or saved in this run, by key
+static CACHED_CONFIGS: Lazy<Mutex<HashMap<String, Config>>> = Lazy::new(Default::default);
+
+/// The kinds of stores a profile can keep its configuration in.
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    /// The OS secret store, falling back to the vault when it is unavailable.
+    #[default]
+    Keyring,
+    /// An encrypted SecureStore vault file, for machines without an OS secret store.
+    Vault,
+    /// A plain TOML file without the API key, which is taken from `DSH_API_KEY`.
+    File,
+}
+
+impl StoreKind {
+    /// Open the store of this kind.
+    pub fn open(self) -> Result<Box<dyn ConfigStore>, DshError> {
+        Ok(match self {
+            StoreKind::Keyring => Box::new(FallbackStore {
+                primary: Box::new(KeyringStore),
+                fallback: Box::new(VaultStore::new(&config_dir()?)),
+            }),
+            StoreKind::Vault => Box::new(VaultStore::new(&config_dir()?)),
+            StoreKind::File => Box::new(FileStore::new(&config_dir()?)),
+        })
+    }
+}
+
+impl fmt::Display for StoreKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreKind::Keyring => write!(f, "keyring"),
+            StoreKind::Vault => write!(f, "vault"),
+            StoreKind::File => write!(f, "file"),
+        }
+    }
+}
+
+/// A place to keep configurations, each under its own key.
+pub trait ConfigStore {
+    /// Returns the configuration stored under `key`, `None` if there is none.
+    fn load(&self, key: &str) -> Result<Option<Config>, DshError>;
+
+    /// Store the configuration under `key`, replacing a previous configuration.
+    fn save(&self, key: &str, config: &Config) -> Result<(), DshError>;
+
+    /// Remove the configuration stored under `key`, if any.
+    fn delete(&self, key: &str) -> Result<(), DshError>;
+}
+
+/// Returns the directory of the configuration files, next to the platforms and profiles files.
+fn config_dir() -> Result<PathBuf, DshError> {
+    let path = confy::get_configuration_file_path(APP_NAME, VAULT_NAME)?;
+    Ok(path.parent().map(Path::to_path_buf).unwrap_or_default())
+}
+
+/// Stores the configurations in the OS secret store.
+pub struct KeyringStore;
+
+impl ConfigStore for KeyringStore {
+    fn load(&self, key: &str) -> Result<Option<Config>, DshError> {
+        let entry = keyring::Entry::new(SERVICE_NAME, key)?;
+        match entry.get_password() {
+            Ok(serialized_config) => Ok(Some(serde_json::from_str(&serialized_config)?)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, key: &str, config: &Config) -> Result<(), DshError> {
+        let entry = keyring::Entry::new(SERVICE_NAME, key)?;
+        entry.set_password(&serde_json::to_string(config)?)?;
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), DshError> {
+        let entry = keyring::Entry::new(SERVICE_NAME, key)?;
+        match entry.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Stores the configurations encrypted in a SecureStore vault file.
+///
+/// The vault is decrypted with the password in `DSH_VAULT_PASSWORD` if set, otherwise with
+/// a key file next to the vault which is generated when the vault is created.
+pub struct VaultStore {
+    path: PathBuf,
+    key_path: PathBuf,
+}
+
+impl VaultStore {
+    /// Returns the vault store with its files in `dir`.
+    pub fn new(dir: &Path) -> VaultStore {
+        let path = dir.join(VAULT_NAME);
+        VaultStore {
+            key_path: path.with_extension("key"),
+            path: path.with_extension("json"),
+        }
+    }
+
+    fn open(&self) -> Result<Option<SecretsManager>, DshError> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let password = std::env::var(VAULT_PASSWORD_VAR).ok();
+        let secrets = match &password {
+            Some(password) => SecretsManager::load(&self.path, KeySource::Password(password))?,
+            None => SecretsManager::load(&self.path, KeySource::from_file(&self.key_path))?,
+        };
+        Ok(Some(secrets))
+    }
+
+    /// Create a new vault, with a new key file unless a password is given.
+    fn create(&self) -> Result<SecretsManager, DshError> {
+        if let Ok(password) = std::env::var(VAULT_PASSWORD_VAR) {
+            return Ok(SecretsManager::new(KeySource::Password(&password))?);
+        }
+        let secrets = SecretsManager::new(KeySource::Csprng)?;
+        // create the key file for the current user only before the key is written to it
+        write_private_file(&self.key_path, b"")?;
+        secrets.export_key(&self.key_path)?;
+        Ok(secrets)
+    }
+}
+
+impl ConfigStore for VaultStore {
+    fn load(&self, key: &str) -> Result<Option<Config>, DshError> {
+        let secrets = match self.open()? {
+            Some(secrets) => secrets,
+            None => return Ok(None),
+        };
+        match secrets.get(key) {
+            Ok(serialized_config) => Ok(Some(serde_json::from_str(&serialized_config)?)),
+            Err(e) if e.kind() == ErrorKind::SecretNotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, key: &str, config: &Config) -> Result<(), DshError> {
+        let mut secrets = match self.open()? {
+            Some(secrets) => secrets,
+            None => self.create()?,
+        };
+        secrets.set(key, serde_json::to_string(config)?);
+        secrets.save_as(&self.path)?;
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), DshError> {
+        let mut secrets = match self.open()? {
+            Some(secrets) => secrets,
+            None => return Ok(()),
+        };
+        match secrets.remove(key) {
+            Ok(()) => Ok(secrets.save_as(&self.path)?),
+            Err(e) if e.kind() == ErrorKind::SecretNotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// The configuration without the API key, as written to a plain TOML file.
+#[derive(Serialize, Deserialize)]
+struct FileConfig {
+    tenant: String,
+    domain: String,
+    port: u16,
+    websocket: bool,
+    #[serde(default)]
+    platform: Option<String>,
+    #[serde(default)]
+    endpoints: super::Endpoints,
+}
+
+impl From<&Config> for FileConfig {
+    fn from(config: &Config) -> Self {
+        FileConfig {
+            tenant: config.tenant.clone(),
+            domain: config.domain.clone(),
+            port: config.port,
+            websocket: config.websocket,
+            platform: config.platform.clone(),
+            endpoints: config.endpoints.clone(),
+        }
+    }
+}
+
+impl Default for FileConfig {
+    fn default() -> Self {
+        FileConfig::from(&Config::default())
+    }
+}
+
+/// Stores the configurations in plain TOML files, one per key, leaving out the API key.
+///
+/// Meant for machines without an OS secret store where the API key is passed in
+/// `DSH_API_KEY`, e.g. CI runners and containers.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    /// Returns the file store with its files in `dir`.
+    pub fn new(dir: &Path) -> FileStore {
+        FileStore {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key).with_extension("toml")
+    }
+}
+
+impl ConfigStore for FileStore {
+    fn load(&self, key: &str) -> Result<Option<Config>, DshError> {
+        let path = self.path(key);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file_config: FileConfig = confy::load_path(&path)?;
+        Ok(Some(Config {
+            tenant: file_config.tenant,
+            api_key: String::new(),
+            domain: file_config.domain,
+            port: file_config.port,
+            websocket: file_config.websocket,
+            platform: file_config.platform,
+            endpoints: file_config.endpoints,
+        }))
+    }
+
+    fn save(&self, key: &str, config: &Config) -> Result<(), DshError> {
+        if !config.api_key.is_empty() {
+            eprintln!(
+                "Warning: the API key is not saved in the file store, set it in DSH_API_KEY instead"
+            );
+        }
+        Ok(confy::store_path(self.path(key), FileConfig::from(config))?)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), DshError> {
+        match std::fs::remove_file(self.path(key)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Uses the primary store, or the fallback store when the primary store is unavailable.
+pub struct FallbackStore {
+    primary: Box<dyn ConfigStore>,
+    fallback: Box<dyn ConfigStore>,
+}
+
+impl FallbackStore {
+    /// Run `op` on the primary store, and on the fallback store when the primary store is
+    /// unavailable. Only changes of the fallback store are warned about, so reading the
+    /// configuration on machines without any store stays silent.
+    fn with_store<T>(
+        &self,
+        warn: bool,
+        op: impl Fn(&dyn ConfigStore) -> Result<T, DshError>,
+    ) -> Result<T, DshError> {
+        match op(self.primary.as_ref()) {
+            Err(e) if is_unavailable(&e) => {
+                if !warn {
+                    debug!(
+                        "The OS secret store is unavailable ({}), using the vault file",
+                        e
+                    );
+                } else if !FALLBACK_WARNED.swap(true, Ordering::Relaxed) {
+                    eprintln!(
+                        "Warning: the OS secret store is unavailable ({}), using the vault file instead",
+                        e
+                    );
+                }
+                op(self.fallback.as_ref())
+            }
+            result => result,
+        }
+    }
+}
+
+impl ConfigStore for FallbackStore {
+    fn load(&self, key: &str) -> Result<Option<Config>, DshError> {
+        self.with_store(false, |store| store.load(key))
+    }
+
+    fn save(&self, key: &str, config: &Config) -> Result<(), DshError> {
+        self.with_store(true, |store| store.save(key, config))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), DshError> {
+        self.with_store(true, |store| store.delete(key))
+    }
+}
+
+/// Keeps the configurations loaded or saved in this run in memory, in front of another store,
+/// so the store is read at most once per key.
+pub struct CachedStore {
+    store: Box<dyn ConfigStore>,
+}
+
+impl CachedStore {
+    /// Returns the store with the cache of this run in front of `store`.
+    pub fn new(store: Box<dyn ConfigStore>) -> CachedStore {
+        CachedStore { store }
+    }
+
+    fn with_cache<R>(f: impl FnOnce(&mut HashMap<String, Config>) -> R) -> R {
+        f(&mut CACHED_CONFIGS.lock().expect("Failed to obtain cache lock"))
+    }
+}
+
+impl ConfigStore for CachedStore {
+    fn load(&self, key: &str) -> Result<Option<Config>, DshError> {
+        if let Some(config) = Self::with_cache(|cache| cache.get(key).cloned()) {
+            return Ok(Some(config));
+        }
+        let config = self.store.load(key)?;
+        if let Some(config) = &config {
+            Self::with_cache(|cache| cache.insert(key.to_string(), config.clone()));
+        }
+        Ok(config)
+    }
+
+    fn save(&self, key: &str, config: &Config) -> Result<(), DshError> {
+        self.store.save(key, config)?;
+        Self::with_cache(|cache| cache.insert(key.to_string(), config.clone()));
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), DshError> {
+        Self::with_cache(|cache| cache.remove(key));
+        self.store.delete(key)
+    }
+}
+
+/// Keeps the configurations in memory, serialized like in the OS secret store.
+///
+/// Every thread has its own configurations, which persist between instances, so tests
+/// can run in parallel and verify what they stored.
+#[cfg(test)]
+pub struct MemoryStore;
+
+#[cfg(test)]
+thread_local! {
+    static MEMORY_STORE: std::cell::RefCell<std::collections::HashMap<String, String>> =
+        Default::default();
+}
+
+#[cfg(test)]
+impl ConfigStore for MemoryStore {
+    fn load(&self, key: &str) -> Result<Option<Config>, DshError> {
+        match MEMORY_STORE.with(|store| store.borrow().get(key).cloned()) {
+            Some(serialized_config) => Ok(Some(serde_json::from_str(&serialized_config)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save(&self, key: &str, config: &Config) -> Result<(), DshError> {
+        let serialized_config = serde_json::to_string(config)?;
+        MEMORY_STORE.with(|store| {
+            store
+                .borrow_mut()
+                .insert(key.to_string(), serialized_config)
+        });
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), DshError> {
+        MEMORY_STORE.with(|store| store.borrow_mut().remove(key));
+        Ok(())
+    }
+}
+
+/// Returns whether the error means that the OS secret store can not be used on this machine.
+pub fn is_unavailable(error: &DshError) -> bool {
+    matches!(
+        error,
+        DshError::KeyringError(
+            keyring::Error::PlatformFailure(_) | keyring::Error::NoStorageAccess(_)
+        )
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_dir;
+
+    fn test_config() -> Config {
+        Config {
+            tenant: "tenant".to_string(),
+            api_key: "api_key".to_string(),
+            ..Config::default()
+        }
+    }
+
+    /// A store which behaves like a keyring without a secret service.
+    struct UnavailableStore;
+
+    impl ConfigStore for UnavailableStore {
+        fn load(&self, _key: &str) -> Result<Option<Config>, DshError> {
+            Err(keyring::Error::PlatformFailure("no secret service".into()).into())
+        }
+
+        fn save(&self, _key: &str, _config: &Config) -> Result<(), DshError> {
+            Err(keyring::Error::PlatformFailure("no secret service".into()).into())
+        }
+
+        fn delete(&self, _key: &str) -> Result<(), DshError> {
+            Err(keyring::Error::PlatformFailure("no secret service".into()).into())
+        }
+    }
+
+    #[test]
+    fn test_memory_store() {
+        MemoryStore.save("dsh_config", &test_config()).unwrap();
+        assert_eq!(MemoryStore.load("dsh_config").unwrap(), Some(test_config()));
+        assert_eq!(MemoryStore.load("dsh_config_prod").unwrap(), None);
+        MemoryStore.delete("dsh_config").unwrap();
+        assert_eq!(MemoryStore.load("dsh_config").unwrap(), None);
+
+        // other threads have their own configurations
+        MemoryStore.save("dsh_config", &test_config()).unwrap();
+        std::thread::spawn(|| assert_eq!(MemoryStore.load("dsh_config").unwrap(), None))
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_vault_store() {
+        let dir = test_dir("vault-store");
+        let store = VaultStore::new(&dir);
+        assert_eq!(store.load("dsh_config").unwrap(), None);
+        store.save("dsh_config", &test_config()).unwrap();
+        store.save("dsh_config_prod", &Config::default()).unwrap();
+        assert_eq!(store.load("dsh_config").unwrap(), Some(test_config()));
+
+        let contents = std::fs::read_to_string(dir.join("vault.json")).unwrap();
+        assert!(!contents.contains("api_key"));
+
+        store.delete("dsh_config").unwrap();
+        store.delete("dsh_config").unwrap();
+        assert_eq!(store.load("dsh_config").unwrap(), None);
+        assert_eq!(
+            store.load("dsh_config_prod").unwrap(),
+            Some(Config::default())
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_file_store() {
+        let dir = test_dir("file-store");
+        let store = FileStore::new(&dir);
+        assert_eq!(store.load("dsh_config").unwrap(), None);
+        store.save("dsh_config", &test_config()).unwrap();
+
+        let contents = std::fs::read_to_string(dir.join("dsh_config.toml")).unwrap();
+        assert!(!contents.contains("api_key"));
+        let loaded = store.load("dsh_config").unwrap().unwrap();
+        assert_eq!(loaded.tenant, "tenant");
+        assert_eq!(loaded.api_key, "");
+
+        store.delete("dsh_config").unwrap();
+        store.delete("dsh_config").unwrap();
+        assert_eq!(store.load("dsh_config").unwrap(), None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_cached_store() {
+        let store = CachedStore::new(Box::new(MemoryStore));
+        let other = Config {
+            tenant: "other".to_string(),
+            ..Config::default()
+        };
+        assert_eq!(store.load("test_cached_config").unwrap(), None);
+        store.save("test_cached_config", &test_config()).unwrap();
+        store.save("test_cached_config_other", &other).unwrap();
+
+        // the cache is kept per key and is used instead of the store
+        MemoryStore.delete("test_cached_config").unwrap();
+        assert_eq!(
+            store.load("test_cached_config").unwrap(),
+            Some(test_config())
+        );
+        assert_eq!(store.load("test_cached_config_other").unwrap(), Some(other));
+
+        store.delete("test_cached_config").unwrap();
+        assert_eq!(store.load("test_cached_config").unwrap(), None);
+        store.delete("test_cached_config_other").unwrap();
+    }
+
+    #[test]
+    fn test_fallback_store() {
+        let dir = test_dir("fallback-store");
+        let store = FallbackStore {
+            primary: Box::new(UnavailableStore),
+            fallback: Box::new(FileStore::new(&dir)),
+        };
+        store.save("dsh_config", &Config::default()).unwrap();
+        assert_eq!(store.load("dsh_config").unwrap(), Some(Config::default()));
+        assert!(dir.join("dsh_config.toml").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -8,10 +8,22 @@
 /// ## Variants
 ///
 /// - `SerdeJson`: Errors related to serialization and deserialization using `serde_json`.
+/// - `SerdeYaml`: Errors related to serialization and deserialization using `serde_yaml`.
 /// - `Base64`: Errors related to Base64 encoding and decoding.
 /// - `Request`: Errors that may occur during HTTP requests using `reqwest`.
 /// - `DshCli`: Custom errors specific to DSH CLI, represented as a string.
 /// - `PortNotPresentInToken`: Error when a specified port is not present in a token.
+/// - `MalformedToken`: Error when a token is not a well-formed JWT.
+/// - `InvalidBase64Segment`: Error when a segment of a token is not URL-safe base64 encoded.
+/// - `UnsupportedAlgorithm`: Error when a token is signed with an unsupported algorithm.
+/// - `InvalidClaims`: Error when the requested claims are invalid.
+/// - `NotAllowedByClaims`: Error when the claims of a token do not allow an action on a topic.
+/// - `DeadlineExceeded`: Error when the requests did not finish before the overall deadline.
+/// - `ConfigMissing`: Error when a required configuration value is not set.
+/// - `Authentication`: Error when the platform rejects the API key or REST token.
+/// - `Network`: Error when the platform could not be reached or did not respond in time.
+/// - `PartialFailure`: Error when fewer tokens were received than required, with the number of
+///   received and required tokens.
 /// - `SecureStore`: Errors related to secure storage operations.
 /// - `Io`: Standard input/output errors.
 /// - `Client`: Errors related to MQTT client operations using `rumqttc`.
@@ -26,6 +38,11 @@
 /// from other error types to `DshError`, providing a seamless way to propagate
 /// errors up the call stack and convert them into a `DshError` variant.
 ///
+/// ## Exit codes
+///
+/// `DshError::exit_code` maps the errors to distinct process exit codes, so scripts and
+/// CI pipelines can react to the kind of failure.
+///
 /// ## Display
 ///
 /// It also implements the `Display` trait to facilitate user-friendly error messages
@@ -33,15 +50,26 @@
 #[derive(Debug)]
 pub enum DshError {
     SerdeJson(serde_json::Error),
+    SerdeYaml(serde_yaml::Error),
     Base64(base64::DecodeError),
     Request(reqwest::Error),
     DshCli(String),
     PortNotPresentInToken(u16),
+    MalformedToken(String),
+    InvalidBase64Segment(&'static str, base64::DecodeError),
+    UnsupportedAlgorithm(String),
+    InvalidClaims(String),
+    NotAllowedByClaims(String),
+    DeadlineExceeded,
+    ConfigMissing(String),
+    Authentication(String),
+    Network(String),
+    PartialFailure(usize, usize),
     SecureStore(securestore::Error),
     Io(std::io::Error),
     Client(rumqttc::ClientError),
     Mqtt(rumqttc::Error),
-    MqttConnection(rumqttc::ConnectionError),
+    MqttConnection(Box<rumqttc::ConnectionError>),
     Confy(confy::ConfyError),
     KeyringError(keyring::Error),
 }
@@ -67,6 +95,13 @@ impl From<serde_json::Error> for DshError {
     }
 }
 
+/// From SerdeYamlError
+impl From<serde_yaml::Error> for DshError {
+    fn from(error: serde_yaml::Error) -> Self {
+        DshError::SerdeYaml(error)
+    }
+}
+
 /// From Base64Error
 impl From<base64::DecodeError> for DshError {
     fn from(e: base64::DecodeError) -> DshError {
@@ -119,7 +154,7 @@ impl From<rumqttc::Error> for DshError {
 /// From MqttConnectionError
 impl From<rumqttc::ConnectionError> for DshError {
     fn from(e: rumqttc::ConnectionError) -> Self {
-        DshError::MqttConnection(e)
+        DshError::MqttConnection(Box::new(e))
     }
 }
 
@@ -137,6 +172,32 @@ impl From<keyring::Error> for DshError {
     }
 }
 
+/// Exit code for errors without a more specific exit code.
+pub const EXIT_FAILURE: i32 = 1;
+/// Exit code when a required configuration value is missing.
+pub const EXIT_CONFIG_MISSING: i32 = 3;
+/// Exit code when the platform rejects the credentials.
+pub const EXIT_AUTHENTICATION: i32 = 4;
+/// Exit code when only part of the requested tokens were received.
+pub const EXIT_PARTIAL_FAILURE: i32 = 5;
+/// Exit code when the platform could not be reached.
+pub const EXIT_NETWORK: i32 = 6;
+
+impl DshError {
+    /// Returns the process exit code for this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            DshError::ConfigMissing(_) => EXIT_CONFIG_MISSING,
+            DshError::Authentication(_) => EXIT_AUTHENTICATION,
+            DshError::PartialFailure(_, _) => EXIT_PARTIAL_FAILURE,
+            DshError::Request(_) | DshError::Network(_) | DshError::DeadlineExceeded => {
+                EXIT_NETWORK
+            }
+            _ => EXIT_FAILURE,
+        }
+    }
+}
+
 /// # Display Implementation for DshError
 ///
 /// This implementation of the `std::fmt::Display` trait allows for
@@ -147,6 +208,7 @@ impl std::fmt::Display for DshError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             DshError::SerdeJson(e) => write!(f, "SerdeJsonError: {}", e),
+            DshError::SerdeYaml(e) => write!(f, "SerdeYamlError: {}", e),
             DshError::Base64(e) => write!(f, "Base64 error: {}", e),
             DshError::Request(e) => write!(f, "Reqwest error: {}", e),
             DshError::DshCli(e) => write!(f, "DshCli error: {}", e),
@@ -157,6 +219,22 @@ impl std::fmt::Display for DshError {
             DshError::MqttConnection(e) => write!(f, "Mqtt connection error: {}", e),
             DshError::Confy(e) => write!(f, "Confy error: {}", e),
             DshError::PortNotPresentInToken(e) => write!(f, "Port not present in token: {}", e),
+            DshError::MalformedToken(e) => write!(f, "Malformed token: {}", e),
+            DshError::InvalidBase64Segment(segment, e) => {
+                write!(f, "Invalid base64 in {} segment of token: {}", segment, e)
+            }
+            DshError::UnsupportedAlgorithm(e) => write!(f, "Unsupported token algorithm: {}", e),
+            DshError::InvalidClaims(e) => write!(f, "Invalid claims: {}", e),
+            DshError::NotAllowedByClaims(e) => write!(f, "Not allowed by token claims: {}", e),
+            DshError::DeadlineExceeded => write!(f, "Deadline exceeded while requesting tokens"),
+            DshError::ConfigMissing(e) => write!(f, "Missing configuration: {}", e),
+            DshError::Authentication(e) => write!(f, "Authentication failed: {}", e),
+            DshError::Network(e) => write!(f, "Network error: {}", e),
+            DshError::PartialFailure(received, required) => write!(
+                f,
+                "Received {} tokens, while at least {} are required",
+                received, required
+            ),
             DshError::KeyringError(e) => write!(f, "Keyring Error: {}", e),
         }
     }
@@ -208,4 +286,25 @@ mod tests {
 
         // ... Similar tests for other error types ...
     }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(
+            DshError::ConfigMissing("tenant".to_string()).exit_code(),
+            EXIT_CONFIG_MISSING
+        );
+        assert_eq!(
+            DshError::Authentication("401".to_string()).exit_code(),
+            EXIT_AUTHENTICATION
+        );
+        assert_eq!(
+            DshError::PartialFailure(1, 2).exit_code(),
+            EXIT_PARTIAL_FAILURE
+        );
+        assert_eq!(DshError::DeadlineExceeded.exit_code(), EXIT_NETWORK);
+        assert_eq!(
+            DshError::DshCli("other".to_string()).exit_code(),
+            EXIT_FAILURE
+        );
+    }
 }
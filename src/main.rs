@@ -2,20 +2,33 @@
 extern crate log;
 
 use self::error::DshError;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 pub mod config;
 mod error;
 mod mc;
+#[cfg(test)]
+mod test_util;
 mod tf;
 
+/// The command-line interface, with the options that apply to every command.
+#[derive(Parser, Debug)]
+#[clap(author, version, about)]
+struct Cli {
+    /// The configuration profile to use instead of the active profile.
+    #[clap(long, global = true)]
+    profile: Option<String>,
+
+    #[clap(subcommand)]
+    command: Commands,
+}
+
 /// Enum representing the available CLI commands.
 ///
 /// This enum defines the various commands that can be used with the CLI,
 /// each variant corresponds to a different subcommand and associated parameters.
-#[derive(Parser, Debug)]
-#[clap(author, version, about)]
-enum Cli {
+#[derive(Subcommand, Debug)]
+enum Commands {
     /// Command for interacting with the token fetcher.
     ///
     /// The `Tf` variant is used for requesting tokens from the platform.
@@ -36,32 +49,59 @@ enum Cli {
     /// It takes a `mc::Command` as a parameter, which contains the specific options
     /// and arguments for the MQTT client functionality.
     Mc(mc::Command),
+
+    /// Command for listing the known DSH platforms.
+    ///
+    /// The `Platforms` variant shows the built-in platforms and the custom platforms
+    /// from the platforms file, which can be selected with `dsh config --platform`.
+    #[clap(subcommand)]
+    Platforms(config::platform::PlatformsCommand),
 }
 
 /// The main entry point for the CLI application.
 ///
 /// This asynchronous function initializes the logger, parses the command-line arguments,
 /// and dispatches the appropriate functionality based on the provided subcommand.
-/// It returns a `Result` to handle any potential errors that may occur during execution.
+/// Errors are printed on stderr and exit the process with the exit code of the error.
 #[tokio::main]
-async fn main() -> Result<(), DshError> {
+async fn main() {
     // Initialize the logger
     env_logger::init();
 
-    // Parse the command-line arguments into a `Cli` enum
+    // Parse the command-line arguments into a `Cli` struct
     let args = Cli::parse();
 
     // Log the parsed arguments for debugging purposes
     debug!("{:?}", &args);
 
-    // Log the current configuration for debugging purposes
-    debug!("{:?}", &config::CONFIG.lock().unwrap());
+    // Select the profile before the configuration is loaded
+    if let Err(e) = config::profile::select(args.profile.as_deref()) {
+        eprintln!("Error: {}", e);
+        std::process::exit(e.exit_code());
+    }
 
     // Match on the parsed arguments to determine which subcommand to execute,
     // and call the appropriate function with the parsed command parameters.
-    match args {
-        Cli::Config(cmd) => config::run(&cmd),
-        Cli::Tf(cmd) => tf::run(&cmd).await,
-        Cli::Mc(cmd) => mc::run(&cmd).await,
+    let result: Result<(), DshError> = match args.command {
+        Commands::Config(cmd) => config::run(&cmd).await,
+        Commands::Tf(cmd) => tf::run(&cmd).await,
+        Commands::Mc(cmd) => mc::run(&cmd).await,
+        Commands::Platforms(cmd) => config::platform::run(&cmd),
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_verify_cli() {
+        Cli::command().debug_assert();
     }
 }
@@ -1,10 +1,12 @@
-use crate::config;
+use crate::config::resolve::{self, PartialConfig, ResolvedConfig};
 use crate::error::DshError;
-use crate::tf::token::Token;
+use crate::tf::claims::{self, ClaimsArgs};
+use crate::tf::token::topic;
+use crate::tf::token::{Action, Claims, Token};
 use clap::Parser;
 use std::path::PathBuf;
 
-mod client;
+pub mod client;
 
 /// Represents the command-line arguments and options for the application.
 #[derive(Parser, Debug)]
@@ -12,7 +14,7 @@ pub struct Command {
     /// Specifies the MQTT topic, e.g., "/tt/topicname/".
     #[clap(short, long)]
     topic: String,
-    /// Optionally overrides the MQTT client ID from the token.
+    /// The MQTT client ID to request the token for, a random client ID by default.
     #[clap(long)]
     client_id: Option<String>,
     /// Optionally overrides the MQTT broker address from the token.
@@ -25,13 +27,15 @@ pub struct Command {
     #[clap(short, long)]
     api_key: Option<String>,
     /// tenant name
-    #[clap(short, long)]
+    #[clap(long)]
     tenant: Option<String>,
     /// Claims to be added to the token, e.g., for specifying permissions.
     /// for example:  '[ { "action": "subscribe", "resource": { "stream": "publicstreamname",
     /// "prefix": "/tt", "topic": "topicname/#", "type": "topic" } } ]'
     #[clap(long)]
     claims: Option<String>,
+    #[clap(flatten)]
+    claims_args: ClaimsArgs,
     /// MQTT message to be sent. If provided, only this message will be sent and the app will exit.
     #[clap(short, long)]
     message: Option<String>,
@@ -44,6 +48,18 @@ pub struct Command {
     /// Enables concise output, printing only topic and message, if set.
     #[clap(short, long)]
     concise: bool,
+    /// Do not use the token cache at all, also not for the REST token.
+    #[clap(long)]
+    no_cache: bool,
+    /// Reuse a valid MQTT token of an earlier invocation from the token cache.
+    ///
+    /// The reused token has the client-id of the earlier invocation, so a concurrent client
+    /// with the same token is disconnected by the broker.
+    #[clap(long, conflicts_with = "no_cache")]
+    reuse_token: bool,
+    /// Skip checking the token claims before connecting, e.g. to test enforcement by the broker.
+    #[clap(long)]
+    skip_claim_check: bool,
 }
 
 /// Executes the main logic based on the provided command-line options.
@@ -51,14 +67,20 @@ pub async fn run(opt: &Command) -> Result<(), DshError> {
     debug!("Commands input: {:?}", opt);
 
     // get attributes
-    let token = get_token(opt).await?;
-    let port = get_port(opt)?;
+    let config = resolve_config(opt)?;
+    let token = get_token(opt, &config).await?;
     let topic = get_topic(opt)?;
-    let websocket = get_websocket(opt)?;
+    let websocket = config.websocket();
+    let port = config.port();
     let concise = opt.concise;
     let verbose = opt.verbose_heartbeat;
     let message = opt.message.clone();
 
+    if !opt.skip_claim_check {
+        let action = get_action(opt);
+        check_claims(&token, action, &topic)?;
+    }
+
     let client =
         client::Client::new(token, port, topic, websocket, verbose, concise, message).await?;
     client.connect().await?;
@@ -66,96 +88,25 @@ pub async fn run(opt: &Command) -> Result<(), DshError> {
     Ok(())
 }
 
-// returns the platform domain url with the order
-// 1 ) the argument given as a parameter
-// 2 ) the config
-/// Determines the platform domain URL, prioritizing the command-line argument, then the config.
-fn get_platform(opt: &Command) -> Result<String, DshError> {
-    match &opt.domain {
-        Some(domain) => Ok(domain.to_string()),
-        None => {
-            let config = config::CONFIG.lock().unwrap();
-            if config.domain.is_empty() {
-                Err(DshError::DshCli(
-                    "No domain configured. Please use the config command to set the domain."
-                        .to_string(),
-                ))
-            } else {
-                Ok(config.domain.to_string())
-            }
-        }
-    }
+/// Resolves the configuration, prioritizing the command-line arguments, then the `DSH_*`
+/// environment variables, the project file, the profile and the defaults.
+///
+/// The API key, tenant and domain are only required to request a token, the port defaults
+/// to the MQTT port of the platform for the transport.
+fn resolve_config(opt: &Command) -> Result<ResolvedConfig, DshError> {
+    resolve::resolve(PartialConfig {
+        tenant: opt.tenant.clone(),
+        api_key: opt.api_key.clone(),
+        domain: opt.domain.clone(),
+        port: opt.port,
+        websocket: opt.websocket.then_some(true),
+        ..Default::default()
+    })
 }
 
-// return the tenant with the order
-// 1 ) the argument given as a parameter
-// 1 ) the config
-/// Determines the tenant, prioritizing the command-line argument, then the config.
-fn get_tenant(opt: &Command) -> Result<String, DshError> {
-    match &opt.tenant {
-        Some(tenant) => Ok(tenant.to_string()),
-        None => {
-            let config = config::CONFIG.lock().unwrap();
-            if config.domain.is_empty() {
-                Err(DshError::DshCli(
-                    "No tenant configuration. Please us the config command to set the tenant."
-                        .to_string(),
-                ))
-            } else {
-                Ok(config.tenant.to_string())
-            }
-        }
-    }
-}
-
-// return the api key with the order
-// 1 ) the argument given as a parameter
-// 1 ) the config
-/// Determines the API key, prioritizing the command-line argument, then the config.
-fn get_api_key(opt: &Command) -> Result<String, DshError> {
-    match &opt.api_key {
-        Some(api_key) => Ok(api_key.to_string()),
-        None => {
-            let config = config::CONFIG.lock().unwrap();
-            if config.domain.is_empty() {
-                Err(DshError::DshCli(
-                    "No api key configured. Please use the config command to set the api key."
-                        .to_string(),
-                ))
-            } else {
-                Ok(config.api_key.to_string())
-            }
-        }
-    }
-}
-
-// return if websocket should be used
-// 1 ) the argument given as a parameter
-// 1 ) the config
-/// Determines whether to use websockets, prioritizing the command-line argument, then the config.
-fn get_websocket(opt: &Command) -> Result<bool, DshError> {
-    match &opt.websocket {
-        true => Ok(true),
-        false => {
-            let config = config::CONFIG.lock().unwrap();
-            if config.domain.is_empty() {
-                Err(DshError::DshCli(
-                    "No websockets configuration. Please us the config command to set the websockets."
-                        .to_string(),
-                ))
-            } else {
-                Ok(config.websocket)
-            }
-        }
-    }
-}
-
-/// Retrieves the claims from the command-line argument.
-pub fn get_claims(opt: &Command) -> Result<Option<String>, DshError> {
-    match &opt.claims {
-        Some(claims) => Ok(Some(claims.to_string())),
-        None => Ok(None),
-    }
+/// Retrieves and validates the claims from the command-line arguments.
+pub fn get_claims(opt: &Command) -> Result<Option<Vec<Claims>>, DshError> {
+    claims::resolve_claims(opt.claims.as_deref(), &opt.claims_args)
 }
 
 /// Return token amount of 1 because this is a single client
@@ -173,16 +124,26 @@ pub fn get_output() -> Result<Option<PathBuf>, DshError> {
     Ok(None)
 }
 
-/// Retrieves a token, prioritizing the command-line argument, then the config.
-pub async fn get_token(opt: &Command) -> Result<Token, DshError> {
+/// Retrieves a token for the resolved configuration.
+pub async fn get_token(opt: &Command, config: &ResolvedConfig) -> Result<Token, DshError> {
     let ra = super::tf::RequestAttributes {
-        domain: get_platform(opt)?,
-        tenant: get_tenant(opt)?,
-        api_key: get_api_key(opt)?,
+        domain: config.domain()?,
+        tenant: config.tenant()?,
+        api_key: config.api_key()?,
         token_amount: get_token_amount()?,
         concurrent_connections: get_concurrent_connections()?,
         output: get_output()?,
+        endpoints: config.endpoints(),
         claims: get_claims(opt)?,
+        client_ids: opt
+            .client_id
+            .clone()
+            .map(super::tf::client_id::ClientIds::Fixed)
+            .unwrap_or_default(),
+        use_cache: opt.reuse_token,
+        cache_rest_token: !opt.no_cache,
+        cache_margin: super::tf::cache::DEFAULT_MARGIN,
+        retry: Default::default(),
     };
     debug!("Request attributes: {:#?}", ra);
 
@@ -195,37 +156,72 @@ pub async fn get_token(opt: &Command) -> Result<Token, DshError> {
     }
 }
 
-// returns the platform port with the order
-// 1 ) the argument given as a parameter
-// 2 ) the config
-// TODO: validate if port is in to be provided token
-/// Determines the platform port, prioritizing the command-line argument, then the config.
-fn get_port(opt: &Command) -> Result<u16, DshError> {
-    match &opt.port {
-        Some(port) => Ok(*port),
-        None => {
-            let config = config::CONFIG.lock().unwrap();
-            if config.port == 0 {
-                Err(DshError::DshCli(
-                    "No port configured. Please use the config command to set the port."
-                        .to_string(),
-                ))
-            } else {
-                Ok(config.port)
-            }
-        }
-    }
-}
-
 // returns the propaly formated topic
 /// Formats the topic properly, ensuring it starts with "/tt".
 fn get_topic(opt: &Command) -> Result<String, DshError> {
-    let topic = opt.topic.clone();
-
     // add /tt prefix to topic
-    if topic.starts_with('/') {
-        Ok(format!("/tt{}", topic))
-    } else {
-        Ok(format!("/tt/{}", topic))
+    Ok(topic::with_prefix(&opt.topic))
+}
+
+/// Returns the intended action: publish when a message is provided, otherwise subscribe.
+fn get_action(opt: &Command) -> Action {
+    match opt.message {
+        Some(_) => Action::Publish,
+        None => Action::Subscribe,
+    }
+}
+
+/// Checks that the claims of the token allow the action on the topic, before connecting.
+///
+/// The broker silently ignores subscriptions and publications that are not covered by the
+/// claims, so a missing claim is reported here together with the flag to request it.
+fn check_claims(token: &Token, action: Action, topic: &str) -> Result<(), DshError> {
+    let evaluation = topic::evaluate(&token.token_attributes.claims, action, topic);
+    if evaluation.is_allowed() {
+        return Ok(());
+    }
+    for (index, reason) in &evaluation.mismatches {
+        debug!("Claim #{} does not match: {}", index + 1, reason);
+    }
+
+    // suggest the claim flag, e.g. `/tt/stream/a/b` results in `--subscribe stream:a/b`
+    let stream_topic = topic
+        .strip_prefix(&format!("{}/", topic::DEFAULT_PREFIX))
+        .and_then(|stream_topic| stream_topic.split_once('/'));
+    let suggestion = match stream_topic {
+        Some((stream, topic)) => format!(", request it with --{} {}:{}", action, stream, topic),
+        None => String::new(),
+    };
+    Err(DshError::NotAllowedByClaims(format!(
+        "the token has no {} claim for {}{} (or use --skip-claim-check)",
+        action, topic, suggestion
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tf::token::Resource;
+
+    #[test]
+    fn test_check_claims() {
+        let raw_token = crate::tf::token::jwt::encode(
+            r#"{"alg":"HS256"}"#,
+            r#"{"exp":1700000000,"claims":[{"resource":{"stream":"stream","prefix":"/tt","topic":"a/#","type":"topic"},"action":"subscribe"}]}"#,
+        );
+        let token = Token::new(raw_token).unwrap();
+        assert_eq!(
+            token.token_attributes.claims[0],
+            Claims::new(Action::Subscribe, Resource::new("stream", "/tt", "a/#"))
+        );
+
+        assert!(check_claims(&token, Action::Subscribe, "/tt/stream/a/b").is_ok());
+
+        let error = check_claims(&token, Action::Publish, "/tt/stream/a/b").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Not allowed by token claims: the token has no publish claim for /tt/stream/a/b, \
+             request it with --publish stream:a/b (or use --skip-claim-check)"
+        );
     }
 }
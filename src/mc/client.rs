@@ -1,6 +1,8 @@
 use crate::error::DshError;
 use crate::tf::token::Token;
-use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, Outgoing, PubAck, QoS, Transport};
+use rumqttc::{
+    AsyncClient, ConnectReturnCode, Event, Incoming, MqttOptions, Outgoing, PubAck, QoS, Transport,
+};
 use rustls::ClientConfig;
 use std::thread;
 use std::time::Duration;
@@ -80,6 +82,69 @@ impl Client {
     /// - `Ok(())`: If the connection and operation (publish/subscribe) are successful.
     /// - `Err(DshError)`: If an error occurs during the operation.
     pub async fn connect(&self) -> Result<(), DshError> {
+        let mqttoptions = self.mqtt_options();
+
+        info!("Config: {:?}", self);
+        // check if there is only a message to be pushed
+        match &self.message {
+            Some(message) => {
+                Self::publish_message_to_topic(self, mqttoptions, message.to_owned()).await?
+            }
+            None => Self::subscribe_to_topic(self, mqttoptions).await?,
+        }
+
+        info!("Connection closed");
+
+        Ok(())
+    }
+
+    /// Connects to the MQTT broker and disconnects as soon as the broker accepted the connection.
+    ///
+    /// # Parameters
+    /// - `timeout`: The maximum time to wait for the broker to accept the connection.
+    ///
+    /// # Returns
+    /// - `Ok(())`: If the broker accepted the connection.
+    /// - `Err(DshError)`: If the connection failed, was refused or timed out.
+    pub async fn check_connection(&self, timeout: Duration) -> Result<(), DshError> {
+        let (client, mut eventloop) = AsyncClient::new(self.mqtt_options(), 10);
+        let connack = tokio::time::timeout(timeout, async {
+            loop {
+                if let Event::Incoming(Incoming::ConnAck(connack)) = eventloop.poll().await? {
+                    return Ok::<_, DshError>(connack);
+                }
+            }
+        })
+        .await
+        .map_err(|_| {
+            DshError::Network(format!(
+                "no answer from {}:{} within {:?}",
+                self.broker_url, self.port, timeout
+            ))
+        })??;
+        if connack.code != ConnectReturnCode::Success {
+            return Err(DshError::DshCli(format!(
+                "The broker refused the connection: {:?}",
+                connack.code
+            )));
+        }
+        // the disconnect is only written to the broker while the event loop is polled
+        let _ = client.disconnect().await;
+        let _ = tokio::time::timeout(timeout, async {
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) | Err(_) => break,
+                    Ok(_) => {}
+                }
+            }
+        })
+        .await;
+        Ok(())
+    }
+
+    /// Returns the options to connect to the broker over TLS or secure websockets, with the
+    /// token as password.
+    fn mqtt_options(&self) -> MqttOptions {
         let mut mqttoptions = MqttOptions::new(&self.client_id, &self.broker_url, self.port);
         mqttoptions.set_keep_alive(Duration::from_secs(5));
 
@@ -108,19 +173,7 @@ impl Client {
         // set tls options and credentials
         mqttoptions.set_credentials(&self.client_id, &self.token);
         debug!("{:?}", &mqttoptions);
-
-        info!("Config: {:?}", self);
-        // check if there is only a message to be pushed
-        match &self.message {
-            Some(message) => {
-                Self::publish_message_to_topic(self, mqttoptions, message.to_owned()).await?
-            }
-            None => Self::subscribe_to_topic(self, mqttoptions).await?,
-        }
-
-        info!("Connection closed");
-
-        Ok(())
+        mqttoptions
     }
 
     /// Publishes a message to a specified topic.
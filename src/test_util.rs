@@ -0,0 +1,11 @@
+//! Helpers shared by the tests of several modules.
+
+use std::path::PathBuf;
+
+/// Returns an empty directory for the test `name` in the temporary directory, which is not
+/// created yet. The tests of every process get their own directories.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("dsh-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
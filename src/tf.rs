@@ -1,14 +1,36 @@
-use crate::config;
+use crate::config::platform::Endpoints;
+use crate::config::resolve::{self, PartialConfig, ResolvedConfig};
+use crate::config::Config;
 use crate::error::DshError;
-use crate::tf::token::Token;
-use clap::Parser;
-use futures::{stream, StreamExt};
+use crate::tf::cache::{CacheKey, TokenCache};
+use crate::tf::claims::ClaimsArgs;
+use crate::tf::client_id::{ClientIdArgs, ClientIds};
+use crate::tf::format::OutputFormat;
+use crate::tf::output::{in_request_order, OutputOptions, TokenSink};
+use crate::tf::report::{Failure, FetchReport, Progress, RequestOutcome};
+use crate::tf::retry::{send_with_retry, RetryArgs, RetryPolicy};
+use crate::tf::token::{Claims, Token};
+use clap::{Parser, Subcommand};
+use futures::{stream, Stream, StreamExt};
 use serde_json::json;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use uuid::Uuid;
 
+pub mod cache;
+pub mod can;
+pub mod claims;
+pub mod client_id;
+pub mod format;
+pub mod inspect;
+pub mod output;
+pub mod pool;
+pub mod report;
+pub mod rest_token;
+pub mod retry;
+pub mod serve;
 pub mod token;
+pub mod watch;
 
 /// Represents command-line arguments and options for the Command.
 ///
@@ -40,19 +62,106 @@ pub struct Command {
     #[clap(short, long)]
     pub claims: Option<String>,
 
+    #[clap(flatten)]
+    pub claims_args: ClaimsArgs,
+
+    #[clap(flatten)]
+    pub client_id_args: ClientIdArgs,
+
     /// The number of tokens to fetch.
     #[clap(short = 'a', long, default_value = "1")]
     pub token_amount: usize,
 
     /// The number of concurrent connections for fetching tokens.
-    #[clap(short = 'k', long, default_value = "1")]
+    #[clap(long, default_value = "1")]
     pub concurrent_connections: usize,
 
     /// The location of the output file.
     ///
-    /// If not specified, the output is written to stdout.
+    /// If not specified, the output is written to stdout. The file is replaced atomically
+    /// and is only readable for the current user.
     #[clap(short, long)]
     pub output: Option<PathBuf>,
+
+    /// Write every token to its own file.
+    ///
+    /// The output is used as file name template, supporting the placeholders
+    /// `{client_id}`, `{tenant}` and `{index}`, the number of the request starting at 1
+    /// (e.g. 'tokens/{client_id}.jwt').
+    #[clap(long, requires = "output")]
+    pub file_per_token: bool,
+
+    /// Append the tokens to the output file instead of replacing it, in the raw or jsonl format.
+    #[clap(long, requires = "output", conflicts_with = "file_per_token")]
+    pub append: bool,
+
+    /// The format in which the tokens are written.
+    ///
+    /// The json, jsonl and yaml formats include the decoded token attributes,
+    /// the env format emits `export DSH_MQTT_*=...` statements ready to `eval`.
+    #[clap(short, long, value_enum, default_value_t)]
+    pub format: OutputFormat,
+
+    /// Do not use the token cache at all, also not for the REST token used to request the
+    /// MQTT tokens.
+    #[clap(long)]
+    pub no_cache: bool,
+
+    /// Reuse valid MQTT tokens from earlier invocations, found in the token cache.
+    ///
+    /// A reused token has the client-id of the earlier invocation, so only reuse tokens
+    /// that are not used for concurrent MQTT connections. Fetches of more than 100 tokens
+    /// do not use the cache, to keep its size bounded.
+    #[clap(long, conflicts_with = "no_cache")]
+    pub reuse_tokens: bool,
+
+    /// The number of seconds before expiry after which a cached token is refreshed.
+    #[clap(long, default_value_t = cache::DEFAULT_MARGIN)]
+    pub cache_margin: u64,
+
+    #[clap(flatten)]
+    pub retry_args: RetryArgs,
+
+    /// The minimum number of tokens that must be received for a successful exit.
+    ///
+    /// Defaults to the number of requested tokens. When fewer tokens are received, the
+    /// received tokens are still written and the exit code is 5.
+    #[clap(long)]
+    pub min_success: Option<usize>,
+
+    /// Write no tokens at all unless every requested token was received.
+    ///
+    /// The tokens are kept in memory until all are received, instead of being written as
+    /// soon as they arrive.
+    #[clap(long, conflicts_with = "min_success")]
+    pub strict: bool,
+
+    /// Report the outcome of every token request on stderr, not only the failed ones.
+    #[clap(long)]
+    pub report: bool,
+
+    #[clap(subcommand)]
+    pub command: Option<TfCommand>,
+}
+
+/// Subcommands of the token fetcher.
+#[derive(Subcommand, Debug)]
+pub enum TfCommand {
+    /// Manage the local token cache.
+    #[clap(subcommand)]
+    Cache(cache::CacheCommand),
+    /// Decode a token and show what it allows.
+    Inspect(inspect::InspectCommand),
+    /// Check whether a token allows an action on a topic, e.g. `dsh tf can subscribe stream/topic/#`.
+    Can(can::CanCommand),
+    /// Keep a valid token in the output, fetching a new one before it expires.
+    Watch(watch::WatchCommand),
+    /// Serve tokens to local applications over HTTP, without exposing the API key.
+    Serve(serve::ServeCommand),
+    /// Keep a pool of unused tokens ready and hand them out over stdin/stdout or a socket.
+    Pool(pool::PoolCommand),
+    /// Print the REST token of the tenant, e.g. for use with curl against other DSH APIs.
+    RestToken(rest_token::RestTokenCommand),
 }
 
 /// Contains attributes required for making requests.
@@ -64,119 +173,84 @@ pub struct RequestAttributes {
     pub tenant: String,
     pub api_key: String,
     pub domain: String,
-    pub claims: Option<String>,
+    pub claims: Option<Vec<Claims>>,
+    pub client_ids: ClientIds,
     pub token_amount: usize,
     pub concurrent_connections: usize,
     pub output: Option<PathBuf>,
+    pub endpoints: Endpoints,
+    /// Whether MQTT tokens are taken from and stored in the token cache.
+    pub use_cache: bool,
+    /// Whether the REST token is taken from and stored in the token cache.
+    pub cache_rest_token: bool,
+    pub cache_margin: u64,
+    pub retry: RetryPolicy,
 }
 
-/// Retrieve the claims specified in the Command options.
-///
-/// # Arguments
-///
-/// * `opt` - A reference to the Command struct containing possible user-specified claims.
-///
-/// # Returns
-///
-/// * `Result<Option<String>, DshError>` - The claims as a JSON string if specified, otherwise None.
-pub fn get_claims(opt: &Command) -> Result<Option<String>, DshError> {
-    match &opt.claims {
-        Some(claims) => Ok(Some(claims.to_string())),
-        None => Ok(None),
+impl RequestAttributes {
+    /// Returns the attributes to request a single token with the given configuration,
+    /// bypassing the token cache, e.g. to verify the configuration.
+    pub fn for_config(config: &Config) -> RequestAttributes {
+        RequestAttributes {
+            tenant: config.tenant.clone(),
+            api_key: config.api_key.clone(),
+            domain: config.domain.clone(),
+            claims: None,
+            client_ids: ClientIds::Random,
+            token_amount: 1,
+            concurrent_connections: 1,
+            output: None,
+            endpoints: config.endpoints.clone(),
+            use_cache: false,
+            cache_rest_token: false,
+            cache_margin: cache::DEFAULT_MARGIN,
+            retry: RetryPolicy::default(),
+        }
     }
 }
 
-/// Get the platform domain based on user input or configuration.
+/// Retrieve the claims specified in the Command options.
 ///
-/// This function retrieves the platform domain URL according to the following order of precedence:
-/// 1. Utilizes the platform domain provided as an argument to the function (if provided).
-/// 2. If no argument is provided, it retrieves the platform domain from the configuration.
+/// The claims from `--claims`, `--claims-file`, `--subscribe` and `--publish` are combined
+/// and validated, so invalid claims are reported before any request is made.
 ///
 /// # Arguments
 ///
-/// * `opt` - A reference to the Command struct containing possible user-specified options and arguments.
+/// * `opt` - A reference to the Command struct containing possible user-specified claims.
 ///
 /// # Returns
 ///
-/// * `Result<String, DshError>` - The platform domain as a string if found, otherwise returns an error.
-///
-/// # Examples
-///
-/// ```
-/// // Example usage of `get_platform`:
-/// let platform_domain = get_platform(&command_options)?;
-/// ```
-///
-/// # Errors
-///
-/// This function will return an error if:
-/// - Neither the argument nor the configuration provides a valid platform domain.
-/// - There are issues accessing or reading the configuration.
-fn get_platform(opt: &Command) -> Result<String, DshError> {
-    match &opt.domain {
-        Some(domain) => Ok(domain.to_string()),
-        None => {
-            let config = config::CONFIG.lock().unwrap();
-            if config.domain.is_empty() {
-                Err(DshError::DshCli(
-                    "No domain configured. Please use the config command to set the domain."
-                        .to_string(),
-                ))
-            } else {
-                Ok(config.domain.to_string())
-            }
-        }
-    }
+/// * `Result<Option<Vec<Claims>>, DshError>` - The claims if specified, otherwise None.
+pub fn get_claims(opt: &Command) -> Result<Option<Vec<Claims>>, DshError> {
+    claims::resolve_claims(opt.claims.as_deref(), &opt.claims_args)
 }
 
-/// Retrieve the tenant name based on user input or configuration.
+/// Retrieve the client ids specified in the Command options.
 ///
-/// This function determines the tenant name using the following priority:
-/// 1. Uses the tenant name provided as an argument to the function (if provided).
-/// 2. If no argument is provided, it retrieves the tenant name from the configuration.
+/// The client ids are checked against the amount of tokens, so every token gets its own
+/// client id unless random client ids are used.
 ///
 /// # Arguments
 ///
-/// * `opt` - A reference to the Command struct containing possible user-specified options and arguments.
+/// * `opt` - A reference to the Command struct containing possible user-specified client ids.
 ///
 /// # Returns
 ///
-/// * `Result<String, DshError>` - The tenant name as a string if found, otherwise returns an error.
-///
-/// # Examples
-///
-/// ```
-/// // Example usage of `get_tenant`:
-/// let tenant_name = get_tenant(&command_options)?;
-/// ```
-///
-/// # Errors
-///
-/// This function will return an error if:
-/// - Neither the argument nor the configuration provides a valid tenant name.
-/// - There are issues accessing or reading the configuration.
-fn get_tenant(opt: &Command) -> Result<String, DshError> {
-    match &opt.tenant {
-        Some(tenant) => Ok(tenant.to_string()),
-        None => {
-            let config = config::CONFIG.lock().unwrap();
-            if config.tenant.is_empty() {
-                Err(DshError::DshCli(
-                    "No tenant configured. Please use the config command to set the tenant."
-                        .to_string(),
-                ))
-            } else {
-                Ok(config.tenant.to_string())
-            }
-        }
-    }
+/// * `Result<ClientIds, DshError>` - The client ids, random if none were specified.
+pub fn get_client_ids(opt: &Command) -> Result<ClientIds, DshError> {
+    let client_ids = client_id::resolve_client_ids(&opt.client_id_args)?;
+    client_ids.validate(opt.token_amount)?;
+    Ok(client_ids)
 }
 
-/// Retrieve the user's API key for platform access.
+/// Resolve the configuration based on user input, the environment and the stored configuration.
 ///
-/// This function obtains the user's API key by checking:
-/// 1. The API key provided as a function argument (if any).
-/// 2. The API key stored in the configuration if no argument is provided.
+/// Every value is taken from the first of:
+/// 1. The `--tenant`, `--api-key` and `--domain` options.
+/// 2. The `DSH_*` environment variables, e.g. `DSH_TENANT`, `DSH_API_KEY` and `DSH_PLATFORM`.
+/// 3. The project file `.dsh.toml` in the current directory or one of its parents.
+/// 4. The selected profile in the OS secret store.
+/// 5. The defaults.
 ///
 /// # Arguments
 ///
@@ -184,149 +258,113 @@ fn get_tenant(opt: &Command) -> Result<String, DshError> {
 ///
 /// # Returns
 ///
-/// * `Result<String, DshError>` - The API key as a string if found, otherwise returns an error.
-///
-/// # Errors
-///
-/// This function will return an error if:
-/// - Neither the argument nor the configuration provides a valid API key.
-/// - There are issues accessing or reading the configuration.
-fn get_api_key(opt: &Command) -> Result<String, DshError> {
-    match &opt.api_key {
-        Some(api_key) => Ok(api_key.to_string()),
-        None => {
-            let config = config::CONFIG.lock().unwrap();
-            if config.api_key.is_empty() {
-                Err(DshError::DshCli(
-                    "No api_key configured. Please use the config command to set the api_key."
-                        .to_string(),
-                ))
-            } else {
-                Ok(config.api_key.to_string())
-            }
-        }
+/// * `Result<ResolvedConfig, DshError>` - The effective configuration, or an error when a layer is invalid.
+fn resolve_config(opt: &Command) -> Result<ResolvedConfig, DshError> {
+    resolve::resolve(flags(opt))
+}
+
+/// The configuration layer of the command-line flags.
+fn flags(opt: &Command) -> PartialConfig {
+    PartialConfig {
+        tenant: opt.tenant.clone(),
+        api_key: opt.api_key.clone(),
+        domain: opt.domain.clone(),
+        ..Default::default()
     }
 }
 
 /// Request MQTT tokens from the platform.
 ///
-/// This asynchronous function sends a request to the platform to retrieve MQTT tokens.
-/// It requires either a configuration or parameters to be set.
+/// This function returns a stream which sends the requests to the platform, at most
+/// `concurrent_connections` at a time. The requests are only sent while the stream is
+/// polled, so a slow consumer slows down the requests instead of buffering tokens.
 ///
 /// # Arguments
 ///
-/// * `rest_token` - A String containing the REST token used for authorization.
+/// * `rest_token` - The REST token used for authorization.
 /// * `ra` - A reference to the RequestAttributes struct containing request parameters like domain, tenant, etc.
+/// * `indexes` - The indexes of the tokens to request, which determine their client ids.
+/// * `deadline` - The moment after which no more requests are made, if any.
 ///
 /// # Returns
 ///
-/// * `Result<Vec<Token>, DshError>` - A vector of Token structs if the request is successful, otherwise returns an error.
+/// * `impl Stream<Item = (usize, u32, Result<Token, Failure>)>` - For every request, in the order the
+///   responses arrive, its index, the number of retries and the token or the failure.
 ///
 /// # Examples
 ///
 /// ```
-/// // Example usage of `request_mqtt_token`:
-/// let mqtt_tokens = request_mqtt_token(rest_token_string, &request_attributes).await?;
+/// // Example usage of `request_mqtt_tokens`:
+/// let mut results = request_mqtt_tokens(&rest_token, &request_attributes, vec![0], None);
+/// while let Some((index, retries, result)) = results.next().await { ... }
 /// ```
-///
-/// # Errors
-///
-/// This function will return an error if:
-/// - The platform returns a non-OK status code.
-/// - There are issues with sending the request or parsing the response.
-async fn request_mqtt_token(
-    rest_token: String,
-    ra: &RequestAttributes,
-) -> Result<Vec<Token>, DshError> {
-    let platform = &ra.domain;
-
-    let request_mqtt_token_url = format!("https://api.{platform}/datastreams/v0/mqtt/token",);
-
-    let authorization_header = &*format!("Bearer {}", rest_token);
+fn request_mqtt_tokens<'a>(
+    rest_token: &str,
+    ra: &'a RequestAttributes,
+    indexes: Vec<usize>,
+    deadline: Option<Instant>,
+) -> impl Stream<Item = (usize, u32, Result<Token, Failure>)> + 'a {
+    let request_mqtt_token_url = ra.endpoints.mqtt_token_url(&ra.domain);
+
+    let authorization_header = format!("Bearer {}", rest_token);
     debug!("{:?}", &authorization_header);
 
     let client = reqwest::Client::builder()
         .build()
         .expect("should be able to build reqwest client");
 
-    let urls = vec![&request_mqtt_token_url; ra.token_amount];
-    let bodies = stream::iter(urls)
-        .map(|url| {
-            let client = &client;
+    stream::iter(indexes)
+        .map(move |index| {
+            let client = client.clone();
+            let url = request_mqtt_token_url.clone();
+            let authorization_header = authorization_header.clone();
             async move {
                 // claims are applyed in the request of a token
+                let client_id = ra
+                    .client_ids
+                    .id(index)
+                    .unwrap_or_else(|| Uuid::new_v4().to_string());
                 let map = json!({
-                    "id": Uuid::new_v4().to_string(),
+                    "id": client_id,
                     "tenant": ra.tenant,
-                    // if opt claims are set, use them, else don't add claims
+                    // if claims are set, use them, else don't add claims
                     //
-                    // $ dsh tf --claims '[ { "action": "subscribe", "resource": { "stream":
-                    //   "ajucpublic", "prefix": "/tt", "topic": "ajuc/test/#", "type": "topic" } }
-                    //   ]'
+                    // $ dsh tf --subscribe ajucpublic:ajuc/test/#
                     //
-                    "claims": match &ra.claims {
-                        Some(claims) => serde_json::from_str(claims)?,
-                        None => serde_json::Value::Null,
-                    },
+                    "claims": ra.claims,
                 });
                 debug!("json payload request: {:?}", &map);
 
-                let resp = client
-                    .post(url)
-                    .header("Authorization", &authorization_header.to_string())
-                    .json(&map)
-                    .send()
-                    .await?;
-
-                match resp.status() {
-                    reqwest::StatusCode::OK => {
-                        let body = resp.text().await?;
-                        debug!("response body: {:?}", &body);
-                        Ok(body)
-                    }
-                    _ => Err(DshError::DshCli(format!(
-                        "Error requesting token server response code: {:?} body: {:?}",
-                        resp.status(),
-                        resp.text().await?
-                    ))),
-                }
-            }
-        })
-        .buffer_unordered(ra.concurrent_connections);
-
-    // mutable vector available in a async blok which contains the tokens
-    let tokens = Arc::new(Mutex::new(Vec::new()));
-
-    // create new Token based on body of request and push it to the tokens vector
-    bodies
-        .for_each(|body| {
-            let tokens = Arc::clone(&tokens);
-            async move {
-                match body {
-                    Ok(body) => {
-                        let token = Token::new(body);
-                        match token {
-                            Ok(token) => {
-                                let mut tokens = tokens.lock().unwrap();
-                                tokens.push(token);
-                            }
-                            Err(e) => {
-                                error!("Error creating token: {:?}", e);
-                            }
+                let sent = send_with_retry(&ra.retry, deadline, || {
+                    client
+                        .post(&url)
+                        .header("Authorization", &authorization_header)
+                        .json(&map)
+                })
+                .await;
+
+                let result = match sent.result {
+                    Ok(resp) if resp.status() == reqwest::StatusCode::OK => match resp.text().await
+                    {
+                        Ok(body) => {
+                            debug!("response body: {:?}", &body);
+                            Token::new(body).map_err(|e| Failure::InvalidToken(e.to_string()))
                         }
+                        Err(e) => Err(Failure::Network(e.to_string())),
+                    },
+                    Ok(resp) => {
+                        let status = resp.status();
+                        Err(Failure::Status(
+                            status,
+                            resp.text().await.unwrap_or_default(),
+                        ))
                     }
-                    Err(e) => {
-                        error!("Error buffered return body: {:?}", e);
-                    }
-                }
+                    Err(e) => Err(Failure::Network(e.to_string())),
+                };
+                (index, sent.retries, result)
             }
         })
-        .await;
-
-    let return_value = tokens.lock().unwrap().to_vec();
-    debug!("return_value: {:?}", &return_value);
-
-    Ok(return_value)
+        .buffer_unordered(ra.concurrent_connections)
 }
 
 /// Request a REST token from the platform.
@@ -337,6 +375,7 @@ async fn request_mqtt_token(
 /// # Arguments
 ///
 /// * `ra` - A reference to the RequestAttributes struct containing request parameters like domain, tenant, etc.
+/// * `deadline` - The moment after which no more requests are made, if any.
 ///
 /// # Returns
 ///
@@ -346,31 +385,44 @@ async fn request_mqtt_token(
 ///
 /// ```
 /// // Example usage of `request_rest_token`:
-/// let rest_token = request_rest_token(&request_attributes).await?;
+/// let rest_token = request_rest_token(&request_attributes, None).await?;
 /// ```
 ///
 /// # Errors
 ///
 /// This function will return an error if:
-/// - The platform returns a non-OK status code.
+/// - The platform rejects the API key, as `DshError::Authentication`.
+/// - The platform returns a non-OK status code after all retries.
 /// - There are issues with sending the request or parsing the response.
-async fn request_rest_token(ra: &RequestAttributes) -> Result<String, DshError> {
-    let platform = &ra.domain;
+pub async fn request_rest_token(
+    ra: &RequestAttributes,
+    deadline: Option<Instant>,
+) -> Result<String, DshError> {
     let tenant = &ra.tenant;
     let api_key = &ra.api_key;
 
-    let request_rest_token_url = format!("https://api.{platform}/auth/v0/token");
+    let request_rest_token_url = ra.endpoints.auth_url(&ra.domain);
     let mut map = std::collections::HashMap::new();
     map.insert("tenant", &tenant);
 
-    let response = reqwest::Client::new()
-        .post(&request_rest_token_url)
-        .header("apikey", &api_key.to_string())
-        .json(&map)
-        .send()
-        .await?;
+    let client = reqwest::Client::new();
+    let response = send_with_retry(&ra.retry, deadline, || {
+        client
+            .post(&request_rest_token_url)
+            .header("apikey", &api_key.to_string())
+            .json(&map)
+    })
+    .await
+    .result?;
     match response.status() {
         reqwest::StatusCode::OK => Ok(response.text().await?),
+        status @ (reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN) => {
+            let error = response.text().await?;
+            Err(DshError::Authentication(format!(
+                "{} requesting REST token: {}",
+                status, error
+            )))
+        }
         _ => {
             let error = response.text().await?;
             Err(error.into())
@@ -378,7 +430,64 @@ async fn request_rest_token(ra: &RequestAttributes) -> Result<String, DshError>
     }
 }
 
-/// Main function to run the token fetcher.
+/// Request a single MQTT token with a REST token, retrying like the token fetcher.
+///
+/// # Arguments
+///
+/// * `rest_token` - The REST token used for authorization.
+/// * `ra` - A reference to the RequestAttributes struct containing request parameters like domain, tenant, etc.
+/// * `deadline` - The moment after which no more requests are made, if any.
+///
+/// # Returns
+///
+/// * `Result<Token, DshError>` - The MQTT token, or an error when it could not be requested.
+pub async fn request_mqtt_token(
+    rest_token: &str,
+    ra: &RequestAttributes,
+    deadline: Option<Instant>,
+) -> Result<Token, DshError> {
+    let mut results = request_mqtt_tokens(rest_token, ra, vec![0], deadline);
+    match results.next().await {
+        Some((_, _, result)) => Ok(result?),
+        None => Err(DshError::DeadlineExceeded),
+    }
+}
+
+/// Returns the REST token of the tenant, from the token cache if it holds a valid one.
+///
+/// A newly requested REST token is added to the cache, when given. The caller saves the
+/// cache, so it is written once together with the MQTT tokens.
+///
+/// # Arguments
+///
+/// * `ra` - A reference to the RequestAttributes struct containing request parameters like domain, tenant, etc.
+/// * `cache` - The token cache to take the REST token from and store it in, if any.
+/// * `deadline` - The moment after which no more requests are made, if any.
+///
+/// # Returns
+///
+/// * `Result<(String, bool), DshError>` - The REST token and whether it was taken from the cache,
+///   or an error when it could not be requested.
+async fn get_rest_token(
+    ra: &RequestAttributes,
+    cache: Option<&mut TokenCache>,
+    deadline: Option<Instant>,
+) -> Result<(String, bool), DshError> {
+    match cache {
+        Some(cache) => {
+            if let Some(token) = cache.valid_rest_token(ra) {
+                debug!("Valid REST token found in cache");
+                return Ok((token, true));
+            }
+            let token = request_rest_token(ra, deadline).await?;
+            cache.insert_rest_token(ra, &token);
+            Ok((token, false))
+        }
+        None => Ok((request_rest_token(ra, deadline).await?, false)),
+    }
+}
+
+/// Build the request attributes from the Command options and the configuration.
 ///
 /// # Arguments
 ///
@@ -386,26 +495,227 @@ async fn request_rest_token(ra: &RequestAttributes) -> Result<String, DshError>
 ///
 /// # Returns
 ///
-/// * `Result<(), DshError>` - Returns Ok(()) if successful, otherwise returns an error.
-pub async fn run(opt: &Command) -> Result<(), DshError> {
-    let request_attributes = RequestAttributes {
-        domain: get_platform(opt)?,
-        tenant: get_tenant(opt)?,
-        api_key: get_api_key(opt)?,
+/// * `Result<RequestAttributes, DshError>` - The request attributes, or an error when a required value is missing.
+pub fn get_request_attributes(opt: &Command) -> Result<RequestAttributes, DshError> {
+    let config = resolve_config(opt)?;
+    Ok(RequestAttributes {
+        domain: config.domain()?,
+        tenant: config.tenant()?,
+        api_key: config.api_key()?,
         claims: get_claims(opt)?,
+        client_ids: get_client_ids(opt)?,
         token_amount: opt.token_amount,
         concurrent_connections: opt.concurrent_connections,
         output: opt.output.clone(),
+        endpoints: config.endpoints(),
+        use_cache: opt.reuse_tokens,
+        cache_rest_token: !opt.no_cache,
+        cache_margin: opt.cache_margin,
+        retry: RetryPolicy::from(&opt.retry_args),
+    })
+}
+
+/// Main function to run the token fetcher.
+///
+/// The tokens are written as soon as they are received. Failed requests are reported on
+/// stderr, followed by a summary line. With `--strict`, the tokens are kept in memory and
+/// only written when all tokens were received.
+///
+/// # Arguments
+///
+/// * `opt` - A reference to the Command struct containing user-specified options and arguments.
+///
+/// # Returns
+///
+/// * `Result<(), DshError>` - Returns Ok(()) if successful, otherwise returns an error.
+pub async fn run(opt: &Command) -> Result<(), DshError> {
+    if let Some(command) = &opt.command {
+        return match command {
+            TfCommand::Cache(cmd) => cache::run(cmd),
+            TfCommand::Inspect(cmd) => inspect::run(cmd),
+            TfCommand::Can(cmd) => can::run(cmd, opt).await,
+            TfCommand::Watch(cmd) => watch::run(cmd, opt).await,
+            TfCommand::Serve(cmd) => serve::run(cmd, opt).await,
+            TfCommand::Pool(cmd) => pool::run(cmd, opt).await,
+            TfCommand::RestToken(cmd) => rest_token::run(cmd, opt).await,
+        };
+    }
+
+    let request_attributes = get_request_attributes(opt)?;
+    let token_amount = request_attributes.token_amount;
+
+    let output = OutputOptions {
+        path: request_attributes.output.clone(),
+        file_per_token: opt.file_per_token,
+        append: opt.append,
+        format: opt.format,
+    };
+    output.validate(token_amount)?;
+
+    let mut progress = Progress::new(token_amount, opt.report);
+    let (report, tokens) = if opt.strict {
+        let mut tokens = Vec::new();
+        let report = fetch_tokens(&request_attributes, &mut tokens, &mut progress).await?;
+        (report, Some(tokens))
+    } else {
+        let mut writer = output.open(token_amount);
+        let report = fetch_tokens(&request_attributes, &mut writer, &mut progress).await?;
+        writer.finish()?;
+        (report, None)
+    };
+
+    if opt.report || token_amount > 1 || report.failed > 0 {
+        eprintln!("{}", report.summary());
+    }
+    if let Some(tokens) = tokens {
+        report.check(token_amount)?;
+        output.write(&in_request_order(tokens))?;
+    }
+    report.check(opt.min_success.unwrap_or(token_amount))
+}
+
+/// Fetches tokens based on the specified request attributes, writing them to `sink` as they arrive.
+///
+/// Valid tokens from the token cache are reused when caching is enabled, only the
+/// missing tokens are requested from the platform and added to the cache. Failed requests
+/// are retried according to the retry policy, within its overall deadline, and are
+/// recorded in the returned report instead of failing the fetch.
+///
+/// # Arguments
+///
+/// * `request_attributes` - A reference to the RequestAttributes struct containing request-related attributes and options.
+/// * `sink` - Receives every token as soon as it is received.
+/// * `progress` - Reports the outcome of every request.
+///
+/// # Returns
+///
+/// * `Result<FetchReport, DshError>` - The totals of the fetch, or an error when no REST token
+///   could be obtained or the sink failed.
+pub async fn fetch_tokens(
+    request_attributes: &RequestAttributes,
+    sink: &mut (dyn TokenSink + Send),
+    progress: &mut Progress,
+) -> Result<FetchReport, DshError> {
+    let deadline = request_attributes.retry.deadline_from_now();
+    let mut report = FetchReport::new(request_attributes.token_amount);
+
+    let token_amount = request_attributes.token_amount;
+    let client_ids = &request_attributes.client_ids;
+    let mut cache = if request_attributes.use_cache || request_attributes.cache_rest_token {
+        Some(TokenCache::load()?)
+    } else {
+        None
+    };
+    let key = if request_attributes.use_cache && token_amount <= cache::MAX_CACHED_TOKENS {
+        Some(CacheKey::new(request_attributes)?)
+    } else {
+        if request_attributes.use_cache {
+            warn!(
+                "Not reusing cached tokens for more than {} tokens",
+                cache::MAX_CACHED_TOKENS
+            );
+        }
+        None
     };
 
-    let tokens = get_tokens(&request_attributes).await?;
-    for token in tokens {
-        println!("{}", token.raw_token);
+    // the indexes of the tokens which are not found in the cache
+    let mut missing = Vec::new();
+    match (&key, &cache) {
+        (Some(key), Some(cache)) if *client_ids == ClientIds::Random => {
+            for token in cache.valid_tokens(key, request_attributes.cache_margin, token_amount) {
+                report.received += 1;
+                report.cached += 1;
+                sink.write_token(report.cached, token)?;
+            }
+            missing.extend(report.cached..token_amount);
+        }
+        (Some(key), Some(cache)) => {
+            for index in 0..token_amount {
+                let key = key.for_client_id(client_ids.id(index));
+                match cache
+                    .valid_tokens(&key, request_attributes.cache_margin, 1)
+                    .pop()
+                {
+                    Some(token) => {
+                        report.received += 1;
+                        report.cached += 1;
+                        sink.write_token(index + 1, token)?;
+                    }
+                    None => missing.push(index),
+                }
+            }
+        }
+        _ => missing.extend(0..token_amount),
+    }
+    debug!("{} valid token(s) found in cache", report.cached);
+
+    if !missing.is_empty() {
+        let rest_token_cache = cache
+            .as_mut()
+            .filter(|_| request_attributes.cache_rest_token);
+        let (mut rest_token, mut from_cache) =
+            get_rest_token(request_attributes, rest_token_cache, deadline).await?;
+        let mut pending = missing;
+        while !pending.is_empty() {
+            // the indexes of the tokens for which a cached REST token was rejected
+            let mut rejected = Vec::new();
+            let mut results =
+                request_mqtt_tokens(&rest_token, request_attributes, pending, deadline);
+            while let Some((index, retries, result)) = results.next().await {
+                if from_cache && matches!(&result, Err(failure) if failure.is_authentication()) {
+                    rejected.push(index);
+                    continue;
+                }
+                let (token, result) = match result {
+                    Ok(token) => (Some(token), Ok(())),
+                    Err(failure) => (None, Err(failure)),
+                };
+                let outcome = RequestOutcome {
+                    index: index + 1,
+                    retries,
+                    result,
+                };
+                report.record(&outcome);
+                progress.outcome(&outcome, &report);
+
+                if let Some(token) = token {
+                    if let (Some(key), Some(cache)) = (&key, &mut cache) {
+                        let key = key.for_client_id(client_ids.id(index));
+                        cache.insert(&key, std::slice::from_ref(&token));
+                    }
+                    sink.write_token(index + 1, token)?;
+                }
+            }
+
+            pending = rejected;
+            if !pending.is_empty() {
+                // the cached REST token may have been revoked, retry once with a new one
+                debug!("Cached REST token was rejected, requesting a new one");
+                if let Some(cache) = &mut cache {
+                    cache.remove_rest_token(request_attributes);
+                }
+                let rest_token_cache = cache
+                    .as_mut()
+                    .filter(|_| request_attributes.cache_rest_token);
+                (rest_token, from_cache) =
+                    get_rest_token(request_attributes, rest_token_cache, deadline).await?;
+            }
+        }
+        progress.clear();
+
+        if let Some(cache) = &mut cache {
+            if report.authentication_failure.is_some() {
+                // the REST token may have been revoked, request a new one next time
+                cache.remove_rest_token(request_attributes);
+            }
+            cache.purge(true);
+            cache.save()?;
+        }
     }
-    Ok(())
+    Ok(report)
 }
 
-/// Fetches tokens based on the specified request attributes.
+/// Fetches the requested amount of tokens into memory, failing when not all tokens were received.
 ///
 /// # Arguments
 ///
@@ -415,22 +725,50 @@ pub async fn run(opt: &Command) -> Result<(), DshError> {
 ///
 /// * `Result<Vec<Token>, DshError>` - A vector of fetched tokens if successful, otherwise returns an error.
 pub async fn get_tokens(request_attributes: &RequestAttributes) -> Result<Vec<Token>, DshError> {
-    let rest_token = request_rest_token(request_attributes).await?;
-    let tokens = request_mqtt_token(rest_token, request_attributes).await?;
-    Ok(tokens)
+    let mut tokens = Vec::new();
+    let report = fetch_tokens(request_attributes, &mut tokens, &mut Progress::hidden()).await?;
+    report.check(request_attributes.token_amount)?;
+    Ok(in_request_order(tokens))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::resolve::Source;
 
     #[test]
     fn test_get_claims_with_some() {
         let cmd = Command {
-            claims: Some(String::from("test_claims")),
+            claims: Some(String::from(
+                r#"[ { "action": "subscribe", "resource": { "stream": "publicstreamname", "prefix": "/tt", "topic": "topicname/#", "type": "topic" } } ]"#,
+            )),
             ..Default::default() // assuming you derive Default for Command
         };
-        assert_eq!(get_claims(&cmd).unwrap(), Some(String::from("test_claims")));
+        let claims = get_claims(&cmd).unwrap().unwrap();
+        assert_eq!(claims.len(), 1);
+        assert_eq!(claims[0].resource().stream(), "publicstreamname");
+    }
+
+    #[test]
+    fn test_get_claims_with_invalid_json() {
+        let cmd = Command {
+            claims: Some(String::from("test_claims")),
+            ..Default::default()
+        };
+        assert!(matches!(get_claims(&cmd), Err(DshError::InvalidClaims(_))));
+    }
+
+    #[test]
+    fn test_get_claims_with_flags() {
+        let cmd = Command {
+            claims_args: ClaimsArgs {
+                subscribe: vec![String::from("publicstreamname:topicname/#")],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let claims = get_claims(&cmd).unwrap().unwrap();
+        assert_eq!(claims[0].resource().topic(), "topicname/#");
     }
 
     #[test]
@@ -442,35 +780,61 @@ mod tests {
         assert_eq!(get_claims(&cmd).unwrap(), None);
     }
 
+    /// Resolve the flags of the command on top of the defaults only, independent of the
+    /// environment and the stored configuration.
+    fn resolve_flags(cmd: &Command) -> ResolvedConfig {
+        ResolvedConfig::from_layers(vec![
+            (Source::Flag, flags(cmd)),
+            (Source::Default, PartialConfig::defaults()),
+        ])
+        .unwrap()
+    }
+
     #[test]
     fn test_get_platform_with_domain() {
         let cmd = Command {
             domain: Some(String::from("test_domain")),
             ..Default::default()
         };
-        assert_eq!(get_platform(&cmd).unwrap(), String::from("test_domain"));
+        assert_eq!(
+            resolve_flags(&cmd).domain().unwrap(),
+            String::from("test_domain")
+        );
     }
 
     #[test]
-    fn test_get_platform_without_domain() {
+    fn test_get_platform_strips_api_prefix() {
         let cmd = Command {
-            domain: None,
+            domain: Some(String::from("api.poc.kpn-dsh.com")),
             ..Default::default()
         };
-        // Assuming you have a domain in your config
         assert_eq!(
-            get_platform(&cmd).unwrap(),
-            String::from("api.poc.kpn-dsh.com")
+            resolve_flags(&cmd).domain().unwrap(),
+            String::from("poc.kpn-dsh.com")
         );
     }
 
+    #[test]
+    fn test_get_platform_without_domain() {
+        let cmd = Command {
+            domain: None,
+            ..Default::default()
+        };
+        let resolved = resolve_flags(&cmd);
+        assert_eq!(resolved.domain().unwrap(), String::from("poc.kpn-dsh.com"));
+        assert_eq!(resolved.domain.unwrap().source, Source::Default);
+    }
+
     #[test]
     fn test_get_tenant_with_tenant() {
         let cmd = Command {
             tenant: Some(String::from("test_tenant")),
             ..Default::default()
         };
-        assert_eq!(get_tenant(&cmd).unwrap(), String::from("test_tenant"));
+        assert_eq!(
+            resolve_flags(&cmd).tenant().unwrap(),
+            String::from("test_tenant")
+        );
     }
 
     #[test]
@@ -481,7 +845,7 @@ mod tests {
             ..Default::default()
         };
 
-        let result = get_tenant(&cmd);
+        let result = resolve_flags(&cmd).tenant();
 
         // Assert
         assert!(
@@ -491,7 +855,7 @@ mod tests {
 
         let err_msg = result.unwrap_err().to_string();
         let expected_err_msg =
-            "DshCli error: No tenant configured. Please use the config command to set the tenant.";
+            "Missing configuration: No tenant configured. Please use the config command to set the tenant.";
         assert_eq!(err_msg, expected_err_msg, "Unexpected error message.");
     }
 
@@ -501,7 +865,10 @@ mod tests {
             api_key: Some(String::from("test_key")),
             ..Default::default()
         };
-        assert_eq!(get_api_key(&cmd).unwrap(), String::from("test_key"));
+        assert_eq!(
+            resolve_flags(&cmd).api_key().unwrap(),
+            String::from("test_key")
+        );
     }
 
     #[test]
@@ -512,13 +879,13 @@ mod tests {
             ..Default::default()
         };
 
-        let result = get_api_key(&cmd);
+        let result = resolve_flags(&cmd).api_key();
 
         // Assert
         assert!(result.is_err(), "Expected an error due to missing API key.");
 
         let err_msg = result.unwrap_err().to_string();
-        let expected_err_msg = "DshCli error: No api_key configured. Please use the config command to set the api_key.";
+        let expected_err_msg = "Missing configuration: No api_key configured. Please use the config command to set the api_key.";
         assert_eq!(err_msg, expected_err_msg, "Unexpected error message.");
     }
 }
@@ -0,0 +1,494 @@
+use crate::error::DshError;
+use crate::tf::output::write_private_file;
+use crate::tf::token::{jwt, Token};
+use crate::tf::RequestAttributes;
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const APP_NAME: &str = "dsh";
+const CACHE_NAME: &str = "token_cache";
+
+/// Default number of seconds before expiry after which a cached token is no longer handed out.
+pub const DEFAULT_MARGIN: u64 = 60;
+
+/// The maximum number of tokens of a fetch for which the cache is used, larger fetches
+/// bypass it so the cache and the memory used to load it stay small.
+pub const MAX_CACHED_TOKENS: usize = 100;
+
+/// Subcommands for managing the local token cache.
+#[derive(Subcommand, Debug)]
+pub enum CacheCommand {
+    /// List the cached tokens and their remaining lifetime.
+    List,
+    /// Remove tokens from the cache.
+    Purge {
+        /// Only remove the tokens that are expired.
+        #[clap(long)]
+        expired: bool,
+    },
+}
+
+/// Identifies which cached tokens can be reused for a request.
+///
+/// Entries cached before the endpoint and API key were part of the key get empty values,
+/// so they never match a request.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+pub struct CacheKey {
+    pub domain: String,
+    pub tenant: String,
+    /// The URL the MQTT tokens were requested from.
+    #[serde(default)]
+    pub endpoint: String,
+    /// A fingerprint of the API key the tokens were requested with, see `fingerprint`.
+    #[serde(default)]
+    pub api_key: String,
+    pub claims: Option<String>,
+    pub client_id: Option<String>,
+}
+
+/// A cached token together with the key it was requested with and its expiry.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+pub struct CacheEntry {
+    pub key: CacheKey,
+    pub exp: i64,
+    pub token: Token,
+}
+
+/// A cached REST token of a tenant, used to request MQTT tokens.
+///
+/// Entries cached before the auth URL and API key were part of the entry get empty values,
+/// so they never match a request.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+pub struct RestTokenEntry {
+    pub domain: String,
+    pub tenant: String,
+    /// The URL the REST token was requested from.
+    #[serde(default)]
+    pub auth_url: String,
+    /// A fingerprint of the API key the REST token was requested with, see `fingerprint`.
+    #[serde(default)]
+    pub api_key: String,
+    pub exp: i64,
+    pub token: String,
+}
+
+impl RestTokenEntry {
+    /// Returns whether the REST token was requested with the same tenant, API key and auth URL.
+    fn matches(&self, ra: &RequestAttributes) -> bool {
+        self.domain == ra.domain
+            && self.tenant == ra.tenant
+            && self.auth_url == ra.endpoints.auth_url(&ra.domain)
+            && self.api_key == fingerprint(&ra.api_key)
+    }
+}
+
+/// The payload fields of a REST token needed to cache it.
+#[derive(Deserialize)]
+struct RestTokenPayload {
+    exp: i64,
+}
+
+/// Returns the expiry of a REST token, or `None` when it cannot be decoded.
+pub fn rest_token_expiry(raw_token: &str) -> Option<i64> {
+    match jwt::decode::<RestTokenPayload>(raw_token.trim()) {
+        Ok((_, payload)) => Some(payload.exp),
+        Err(e) => {
+            debug!("Could not decode the expiry of the REST token: {}", e);
+            None
+        }
+    }
+}
+
+/// Persistent cache of MQTT and REST tokens, stored as JSON next to the configuration files.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct TokenCache {
+    entries: Vec<CacheEntry>,
+    #[serde(default)]
+    rest_tokens: Vec<RestTokenEntry>,
+}
+
+impl CacheKey {
+    /// Creates the cache key for the given request attributes.
+    ///
+    /// The claims are serialized as compact JSON, so claims specified via JSON, a file
+    /// or flags result in the same key.
+    pub fn new(ra: &RequestAttributes) -> Result<CacheKey, DshError> {
+        let claims = match &ra.claims {
+            Some(claims) => Some(serde_json::to_string(claims)?),
+            None => None,
+        };
+        Ok(CacheKey {
+            domain: ra.domain.clone(),
+            tenant: ra.tenant.clone(),
+            endpoint: ra.endpoints.mqtt_token_url(&ra.domain),
+            api_key: fingerprint(&ra.api_key),
+            claims,
+            // with random client-ids any client-id will do, see `for_client_id`
+            client_id: None,
+        })
+    }
+
+    /// Returns the key for a token with the given client-id, `None` meaning any client-id.
+    pub fn for_client_id(&self, client_id: Option<String>) -> CacheKey {
+        CacheKey {
+            client_id,
+            ..self.clone()
+        }
+    }
+}
+
+impl TokenCache {
+    /// Returns the location of the cache file.
+    pub fn path() -> Result<PathBuf, DshError> {
+        let path = confy::get_configuration_file_path(APP_NAME, CACHE_NAME)?;
+        Ok(path.with_extension("json"))
+    }
+
+    /// Load the cache from disk, an absent or unreadable cache results in an empty cache.
+    pub fn load() -> Result<TokenCache, DshError> {
+        let path = Self::path()?;
+        match fs::read(&path) {
+            Ok(contents) => match serde_json::from_slice(&contents) {
+                Ok(cache) => Ok(cache),
+                Err(e) => {
+                    warn!("Ignoring unreadable token cache {}: {}", path.display(), e);
+                    Ok(TokenCache::default())
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(TokenCache::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Save the cache to disk, readable for the current user only.
+    pub fn save(&self) -> Result<(), DshError> {
+        let serialized_cache = serde_json::to_vec(&self)?;
+        write_private_file(&Self::path()?, &serialized_cache)
+    }
+
+    /// Returns all entries in the cache.
+    pub fn entries(&self) -> &[CacheEntry] {
+        &self.entries
+    }
+
+    /// Returns at most `amount` tokens for `key` which are valid for at least `margin` more seconds.
+    pub fn valid_tokens(&self, key: &CacheKey, margin: u64, amount: usize) -> Vec<Token> {
+        let valid_until = now() + margin as i64;
+        self.entries
+            .iter()
+            .filter(|entry| &entry.key == key && entry.exp > valid_until)
+            .map(|entry| entry.token.clone())
+            .take(amount)
+            .collect()
+    }
+
+    /// Add freshly fetched tokens for `key` to the cache.
+    pub fn insert(&mut self, key: &CacheKey, tokens: &[Token]) {
+        for token in tokens {
+            self.entries.push(CacheEntry {
+                key: key.clone(),
+                exp: token.token_attributes.exp(),
+                token: token.clone(),
+            });
+        }
+    }
+
+    /// Returns all cached REST tokens.
+    pub fn rest_tokens(&self) -> &[RestTokenEntry] {
+        &self.rest_tokens
+    }
+
+    /// Returns the REST token for the tenant, API key and auth URL of `ra` if it is valid for
+    /// at least the cache margin of `ra` more seconds.
+    pub fn valid_rest_token(&self, ra: &RequestAttributes) -> Option<String> {
+        let valid_until = now() + ra.cache_margin as i64;
+        self.rest_tokens
+            .iter()
+            .find(|entry| entry.matches(ra))
+            .filter(|entry| entry.exp > valid_until)
+            .map(|entry| entry.token.clone())
+    }
+
+    /// Store the REST token requested with `ra`, replacing the previous one.
+    ///
+    /// A token of which the expiry cannot be decoded is not stored, as it can not be
+    /// known when it is no longer valid.
+    pub fn insert_rest_token(&mut self, ra: &RequestAttributes, token: &str) {
+        self.remove_rest_token(ra);
+        if let Some(exp) = rest_token_expiry(token) {
+            self.rest_tokens.push(RestTokenEntry {
+                domain: ra.domain.clone(),
+                tenant: ra.tenant.clone(),
+                auth_url: ra.endpoints.auth_url(&ra.domain),
+                api_key: fingerprint(&ra.api_key),
+                exp,
+                token: token.to_string(),
+            });
+        }
+    }
+
+    /// Remove the REST token requested with `ra`, e.g. after it was rejected by the platform.
+    pub fn remove_rest_token(&mut self, ra: &RequestAttributes) {
+        self.rest_tokens.retain(|entry| !entry.matches(ra));
+    }
+
+    /// Remove tokens from the cache, returning the number of removed tokens.
+    ///
+    /// REST tokens cached without the API key they were requested with are also removed
+    /// when `expired_only`, as they are never used.
+    pub fn purge(&mut self, expired_only: bool) -> usize {
+        let before = self.entries.len() + self.rest_tokens.len();
+        if expired_only {
+            let now = now();
+            self.entries.retain(|entry| entry.exp > now);
+            self.rest_tokens
+                .retain(|entry| entry.exp > now && !entry.api_key.is_empty());
+        } else {
+            self.entries.clear();
+            self.rest_tokens.clear();
+        }
+        before - self.entries.len() - self.rest_tokens.len()
+    }
+}
+
+/// Returns a fingerprint of an API key, which identifies the key without storing it.
+pub fn fingerprint(api_key: &str) -> String {
+    let digest = Sha256::digest(api_key.as_bytes());
+    format!("{:x}", digest)[..16].to_string()
+}
+
+/// Returns the current time as seconds since the Unix epoch.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Run the cache subcommands.
+pub fn run(cmd: &CacheCommand) -> Result<(), DshError> {
+    let mut cache = TokenCache::load()?;
+    match cmd {
+        CacheCommand::List => {
+            let now = now();
+            let expires_in = |exp: i64| {
+                if exp > now {
+                    format!("{}s", exp - now)
+                } else {
+                    "expired".to_string()
+                }
+            };
+            println!(
+                "{:<20} {:<36} {:<36} {:>10} CLAIMS",
+                "TENANT", "DOMAIN", "CLIENT-ID", "EXPIRES-IN"
+            );
+            for entry in cache.rest_tokens() {
+                println!(
+                    "{:<20} {:<36} {:<36} {:>10} (REST token)",
+                    entry.tenant,
+                    entry.domain,
+                    "-",
+                    expires_in(entry.exp)
+                );
+            }
+            for entry in cache.entries() {
+                println!(
+                    "{:<20} {:<36} {:<36} {:>10} {}",
+                    entry.key.tenant,
+                    entry.key.domain,
+                    entry.token.token_attributes.client_id,
+                    expires_in(entry.exp),
+                    entry.key.claims.as_deref().unwrap_or("-")
+                );
+            }
+            Ok(())
+        }
+        CacheCommand::Purge { expired } => {
+            let removed = cache.purge(*expired);
+            cache.save()?;
+            println!("Removed {} token(s) from the cache", removed);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token_with_exp(exp: i64) -> Token {
+        Token::for_test(&format!("client-{}", exp), exp)
+    }
+
+    fn key(claims: Option<&str>) -> CacheKey {
+        CacheKey {
+            domain: "poc.kpn-dsh.com".to_string(),
+            tenant: "tenant".to_string(),
+            endpoint: "https://api.poc.kpn-dsh.com/datastreams/v0/mqtt/token".to_string(),
+            api_key: fingerprint("secret"),
+            claims: claims.map(|c| c.to_string()),
+            client_id: None,
+        }
+    }
+
+    #[test]
+    fn test_valid_tokens_respects_margin() {
+        let mut cache = TokenCache::default();
+        cache.insert(
+            &key(None),
+            &[token_with_exp(now() + 30), token_with_exp(now() + 3600)],
+        );
+
+        assert_eq!(cache.valid_tokens(&key(None), 0, 10).len(), 2);
+        assert_eq!(cache.valid_tokens(&key(None), DEFAULT_MARGIN, 10).len(), 1);
+        assert_eq!(cache.valid_tokens(&key(None), 0, 1).len(), 1);
+    }
+
+    #[test]
+    fn test_valid_tokens_matches_key() {
+        let mut cache = TokenCache::default();
+        cache.insert(&key(Some("[]")), &[token_with_exp(now() + 3600)]);
+
+        assert!(cache.valid_tokens(&key(None), 0, 1).is_empty());
+        assert_eq!(cache.valid_tokens(&key(Some("[]")), 0, 1).len(), 1);
+
+        let device = key(None).for_client_id(Some("device-1".to_string()));
+        cache.insert(&device, &[Token::for_test("device-1", now() + 3600)]);
+        assert!(cache.valid_tokens(&key(None), 0, 1).is_empty());
+        assert_eq!(cache.valid_tokens(&device, 0, 1).len(), 1);
+
+        let other_key = CacheKey {
+            api_key: fingerprint("other"),
+            ..key(Some("[]"))
+        };
+        assert!(cache.valid_tokens(&other_key, 0, 1).is_empty());
+        let other_endpoint = CacheKey {
+            endpoint: "http://localhost:8080/mqtt/token".to_string(),
+            ..key(Some("[]"))
+        };
+        assert!(cache.valid_tokens(&other_endpoint, 0, 1).is_empty());
+    }
+
+    #[test]
+    fn test_old_entries_never_match() {
+        let old_key =
+            r#"{"domain":"poc.kpn-dsh.com","tenant":"tenant","claims":null,"client_id":null}"#;
+        let old_key: CacheKey = serde_json::from_str(old_key).unwrap();
+        assert_eq!(old_key.endpoint, "");
+        assert_ne!(old_key, key(None));
+    }
+
+    #[test]
+    fn test_purge() {
+        let mut cache = TokenCache::default();
+        cache.insert(
+            &key(None),
+            &[token_with_exp(now() - 1), token_with_exp(now() + 3600)],
+        );
+
+        assert_eq!(cache.purge(true), 1);
+        assert_eq!(cache.entries().len(), 1);
+        assert_eq!(cache.purge(false), 1);
+        assert!(cache.entries().is_empty());
+    }
+
+    fn rest_token_attributes(api_key: &str) -> RequestAttributes {
+        RequestAttributes::for_config(&crate::config::Config {
+            tenant: "tenant".to_string(),
+            api_key: api_key.to_string(),
+            ..Default::default()
+        })
+    }
+
+    fn rest_token(exp: i64) -> String {
+        jwt::encode(
+            r#"{"alg":"RS256"}"#,
+            &format!(r#"{{"exp":{},"tenant-id":"tenant"}}"#, exp),
+        )
+    }
+
+    #[test]
+    fn test_rest_token() {
+        let ra = rest_token_attributes("secret");
+        let mut cache = TokenCache::default();
+        cache.insert_rest_token(&ra, &rest_token(now() + 300));
+        assert_eq!(cache.valid_rest_token(&ra), Some(rest_token(now() + 300)));
+        let other_tenant = RequestAttributes {
+            tenant: "other".to_string(),
+            ..ra.clone()
+        };
+        assert_eq!(cache.valid_rest_token(&other_tenant), None);
+        let long_margin = RequestAttributes {
+            cache_margin: 600,
+            ..ra.clone()
+        };
+        assert_eq!(cache.valid_rest_token(&long_margin), None);
+
+        // a new token replaces the old one, an undecodable token is not cached
+        cache.insert_rest_token(&ra, &rest_token(now() - 1));
+        assert_eq!(cache.rest_tokens().len(), 1);
+        assert_eq!(cache.purge(true), 1);
+        cache.insert_rest_token(&ra, "opaque");
+        assert!(cache.rest_tokens().is_empty());
+    }
+
+    #[test]
+    fn test_rest_token_matches_api_key_and_auth_url() {
+        let ra = rest_token_attributes("secret");
+        let mut cache = TokenCache::default();
+        cache.insert_rest_token(&ra, &rest_token(now() + 300));
+
+        let other_key = rest_token_attributes("rotated");
+        assert_eq!(cache.valid_rest_token(&other_key), None);
+        let mut other_auth_url = ra.clone();
+        other_auth_url.endpoints.auth_url = "http://localhost:8080/auth/v0/token".to_string();
+        assert_eq!(cache.valid_rest_token(&other_auth_url), None);
+
+        // the token of the other key is kept, and old entries never match
+        cache.insert_rest_token(&other_key, &rest_token(now() + 600));
+        assert_eq!(cache.rest_tokens().len(), 2);
+        cache.remove_rest_token(&other_key);
+        assert_eq!(cache.valid_rest_token(&ra), Some(rest_token(now() + 300)));
+        let old_entry = format!(
+            r#"{{"domain":"poc.kpn-dsh.com","tenant":"tenant","exp":{},"token":"x"}}"#,
+            now() + 300
+        );
+        let old_entry: RestTokenEntry = serde_json::from_str(&old_entry).unwrap();
+        assert!(!old_entry.matches(&ra));
+        cache.rest_tokens.push(old_entry);
+        assert_eq!(cache.purge(true), 1);
+        assert_eq!(cache.rest_tokens().len(), 1);
+    }
+
+    #[test]
+    fn test_cache_key_serializes_claims() {
+        let ra = RequestAttributes {
+            tenant: "tenant".to_string(),
+            api_key: "key".to_string(),
+            domain: "poc.kpn-dsh.com".to_string(),
+            claims: Some(vec![crate::tf::claims::parse_flag(
+                crate::tf::token::Action::Subscribe,
+                "stream:topic/#",
+            )
+            .unwrap()]),
+            client_ids: Default::default(),
+            token_amount: 1,
+            concurrent_connections: 1,
+            output: None,
+            endpoints: Default::default(),
+            use_cache: true,
+            cache_rest_token: true,
+            cache_margin: DEFAULT_MARGIN,
+            retry: Default::default(),
+        };
+        let key = CacheKey::new(&ra).unwrap();
+        assert_eq!(
+            key.claims,
+            Some(r#"[{"resource":{"stream":"stream","prefix":"/tt","topic":"topic/#","type":"topic"},"action":"subscribe"}]"#.to_string())
+        );
+    }
+}
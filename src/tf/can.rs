@@ -0,0 +1,135 @@
+use crate::error::DshError;
+use crate::tf::inspect::read_raw_token;
+use crate::tf::token::topic::{self, Evaluation, DEFAULT_PREFIX};
+use crate::tf::token::{Action, Claims, Token};
+use crate::tf::{get_request_attributes, get_tokens, Command, RequestAttributes};
+use clap::Args;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+/// Check whether a token allows an action on a topic.
+#[derive(Args, Debug)]
+pub struct CanCommand {
+    /// The action to check.
+    #[clap(value_enum)]
+    action: Action,
+
+    /// The topic to check, as used with `dsh mc` (e.g. 'stream/topic/#') or starting with '/tt/'.
+    topic: String,
+
+    /// Check the claims of this token instead of fetching a new one ('-' reads it from stdin).
+    #[clap(long)]
+    token: Option<String>,
+
+    /// Check the claims of the token in this file instead of fetching a new one.
+    #[clap(long, conflicts_with = "token")]
+    token_file: Option<PathBuf>,
+}
+
+/// Returns the full topic, adding the `/tt` prefix when it is not present yet.
+fn full_topic(topic: &str) -> String {
+    if topic.starts_with(&format!("{}/", DEFAULT_PREFIX)) {
+        topic.to_string()
+    } else {
+        topic::with_prefix(topic)
+    }
+}
+
+/// Describe which claim allows the action, or why none of the claims does.
+fn report(claims: &[Claims], action: Action, topic: &str, evaluation: &Evaluation) -> String {
+    let describe_claim = |index: usize| {
+        let claim = &claims[index];
+        let resource = claim.resource();
+        format!(
+            "claim #{} ({} {}/{}/{})",
+            index + 1,
+            claim.action(),
+            resource.prefix(),
+            resource.stream(),
+            resource.topic()
+        )
+    };
+
+    let mut out = String::new();
+    // `write!` to a String cannot fail
+    match evaluation.matched {
+        Some(index) => {
+            let _ = writeln!(out, "allowed: {} on {}", action, topic);
+            let _ = writeln!(out, "  matched {}", describe_claim(index));
+        }
+        None => {
+            let _ = writeln!(out, "denied: {} on {}", action, topic);
+            if claims.is_empty() {
+                let _ = writeln!(out, "  the token does not contain any claims");
+            }
+            for (index, reason) in &evaluation.mismatches {
+                let _ = writeln!(out, "  {}: {}", describe_claim(*index), reason);
+            }
+        }
+    }
+    out
+}
+
+/// Run the can subcommand, fetching a token with the token fetcher options when no token is provided.
+pub async fn run(cmd: &CanCommand, opt: &Command) -> Result<(), DshError> {
+    let token = if cmd.token.is_some() || cmd.token_file.is_some() {
+        Token::new(read_raw_token(
+            cmd.token.as_deref(),
+            cmd.token_file.as_deref(),
+        )?)?
+    } else {
+        let request_attributes = RequestAttributes {
+            token_amount: 1,
+            ..get_request_attributes(opt)?
+        };
+        get_tokens(&request_attributes)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| DshError::DshCli("No token received".to_string()))?
+    };
+
+    let topic = full_topic(&cmd.topic);
+    let claims = &token.token_attributes.claims;
+    let evaluation = topic::evaluate(claims, cmd.action, &topic);
+    print!("{}", report(claims, cmd.action, &topic, &evaluation));
+
+    if evaluation.is_allowed() {
+        Ok(())
+    } else {
+        Err(DshError::NotAllowedByClaims(format!(
+            "{} on {}",
+            cmd.action, topic
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tf::token::Resource;
+
+    #[test]
+    fn test_full_topic() {
+        assert_eq!(full_topic("stream/a/b"), "/tt/stream/a/b");
+        assert_eq!(full_topic("/tt/stream/a/b"), "/tt/stream/a/b");
+    }
+
+    #[test]
+    fn test_report() {
+        let claims = vec![Claims::new(
+            Action::Subscribe,
+            Resource::new("stream", DEFAULT_PREFIX, "a/#"),
+        )];
+
+        let evaluation = topic::evaluate(&claims, Action::Subscribe, "/tt/stream/a/b");
+        let out = report(&claims, Action::Subscribe, "/tt/stream/a/b", &evaluation);
+        assert!(out.starts_with("allowed: subscribe on /tt/stream/a/b\n"));
+        assert!(out.contains("matched claim #1 (subscribe /tt/stream/a/#)"));
+
+        let evaluation = topic::evaluate(&claims, Action::Publish, "/tt/stream/a/b");
+        let out = report(&claims, Action::Publish, "/tt/stream/a/b", &evaluation);
+        assert!(out.starts_with("denied: publish on /tt/stream/a/b\n"));
+        assert!(out.contains("claim allows subscribe, not publish"));
+    }
+}
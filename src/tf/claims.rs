@@ -0,0 +1,232 @@
+use crate::error::DshError;
+use crate::tf::token::topic::{validate_topic_filter, DEFAULT_PREFIX};
+use crate::tf::token::{Action, Claims, Resource, ResourceType};
+use clap::Args;
+use std::path::{Path, PathBuf};
+
+/// Command-line flags to build the claims of a token without writing JSON.
+#[derive(Args, Debug, Default, Clone)]
+pub struct ClaimsArgs {
+    /// Request a subscribe claim, e.g. 'publicstreamname:topicname/#'. Can be repeated.
+    #[clap(long, value_name = "STREAM:TOPIC[:PREFIX]")]
+    pub subscribe: Vec<String>,
+
+    /// Request a publish claim, e.g. 'publicstreamname:topicname/out'. Can be repeated.
+    #[clap(long, value_name = "STREAM:TOPIC[:PREFIX]")]
+    pub publish: Vec<String>,
+
+    /// Read the claims from a JSON or YAML file (detected by the .yaml/.yml extension).
+    #[clap(long)]
+    pub claims_file: Option<PathBuf>,
+}
+
+/// Combine the JSON claims, the claims file and the claim flags into one validated set of claims.
+///
+/// Returns `None` when no claims are specified, so the platform applies the default claims.
+pub fn resolve_claims(
+    json: Option<&str>,
+    args: &ClaimsArgs,
+) -> Result<Option<Vec<Claims>>, DshError> {
+    let mut claims = Vec::new();
+    if let Some(json) = json {
+        claims.extend(parse_json(json, "--claims")?);
+    }
+    if let Some(path) = &args.claims_file {
+        claims.extend(read_claims_file(path)?);
+    }
+    for flag in &args.subscribe {
+        claims.push(parse_flag(Action::Subscribe, flag)?);
+    }
+    for flag in &args.publish {
+        claims.push(parse_flag(Action::Publish, flag)?);
+    }
+
+    if json.is_none() && args.claims_file.is_none() && claims.is_empty() {
+        return Ok(None);
+    }
+    validate_claims(&claims)?;
+    Ok(Some(claims))
+}
+
+/// Parse a claim flag in the form `stream:topic[:prefix]`.
+pub fn parse_flag(action: Action, flag: &str) -> Result<Claims, DshError> {
+    let parts: Vec<&str> = flag.splitn(3, ':').collect();
+    match parts[..] {
+        [stream, topic] => Ok(Claims::new(
+            action,
+            Resource::new(stream, DEFAULT_PREFIX, topic),
+        )),
+        [stream, topic, prefix] => Ok(Claims::new(action, Resource::new(stream, prefix, topic))),
+        _ => Err(DshError::InvalidClaims(format!(
+            "'{}' is not in the form STREAM:TOPIC[:PREFIX]",
+            flag
+        ))),
+    }
+}
+
+/// Parse claims from JSON, `source` is used to point out where invalid claims came from.
+fn parse_json(json: &str, source: &str) -> Result<Vec<Claims>, DshError> {
+    let value = serde_json::from_str(json)
+        .map_err(|e| DshError::InvalidClaims(format!("{} is not valid: {}", source, e)))?;
+    claims_from_value(value, source)
+}
+
+/// Read claims from a parsed document, rejecting actions and resource types that are not
+/// known, which tokens may contain but requests should not.
+fn claims_from_value(value: serde_json::Value, source: &str) -> Result<Vec<Claims>, DshError> {
+    let claims: Vec<Claims> = serde_json::from_value(value.clone())
+        .map_err(|e| DshError::InvalidClaims(format!("{} is not valid: {}", source, e)))?;
+    for (index, claim) in claims.iter().enumerate() {
+        let unknown = if claim.action() == Action::Unknown {
+            Some(("action", &value[index]["action"]))
+        } else if claim.resource().resource_type() == Some(ResourceType::Unknown) {
+            Some(("type", &value[index]["resource"]["type"]))
+        } else {
+            None
+        };
+        if let Some((field, unknown)) = unknown {
+            return Err(DshError::InvalidClaims(format!(
+                "{} is not valid: unknown {} {} in claim {}",
+                source,
+                field,
+                unknown,
+                index + 1
+            )));
+        }
+    }
+    Ok(claims)
+}
+
+/// Read claims from a JSON or YAML file.
+fn read_claims_file(path: &Path) -> Result<Vec<Claims>, DshError> {
+    let contents = std::fs::read_to_string(path)?;
+    let source = path.display().to_string();
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("yaml") | Some("yml") => {
+            let value = serde_yaml::from_str(&contents)
+                .map_err(|e| DshError::InvalidClaims(format!("{} is not valid: {}", source, e)))?;
+            claims_from_value(value, &source)
+        }
+        _ => parse_json(&contents, &source),
+    }
+}
+
+/// Validate claims locally, so mistakes are reported before any request is made.
+pub fn validate_claims(claims: &[Claims]) -> Result<(), DshError> {
+    for claim in claims {
+        let resource = claim.resource();
+        let invalid = |reason: &str| {
+            Err(DshError::InvalidClaims(format!(
+                "{} claim on stream '{}' with topic '{}': {}",
+                claim.action(),
+                resource.stream(),
+                resource.topic(),
+                reason
+            )))
+        };
+
+        if claim.action() == Action::Unknown {
+            return invalid("the action must be subscribe or publish");
+        }
+        if resource.resource_type() == Some(ResourceType::Unknown) {
+            return invalid("the type must be topic");
+        }
+        if resource.stream().is_empty() {
+            return invalid("the stream is empty");
+        }
+        if resource.stream().contains(['/', '+', '#']) {
+            return invalid("the stream must not contain '/', '+' or '#'");
+        }
+        if !resource.prefix().starts_with('/') {
+            return invalid("the prefix must start with '/'");
+        }
+        if let Err(reason) = validate_topic_filter(resource.topic()) {
+            return invalid(&reason);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_dir;
+
+    #[test]
+    fn test_parse_flag() {
+        let claim = parse_flag(Action::Publish, "stream:topic/out").unwrap();
+        assert_eq!(claim.action(), Action::Publish);
+        assert_eq!(claim.resource().stream(), "stream");
+        assert_eq!(claim.resource().topic(), "topic/out");
+        assert_eq!(claim.resource().prefix(), DEFAULT_PREFIX);
+        assert_eq!(claim.resource().resource_type(), Some(ResourceType::Topic));
+
+        let claim = parse_flag(Action::Subscribe, "stream:topic/#:/other").unwrap();
+        assert_eq!(claim.resource().prefix(), "/other");
+
+        assert!(parse_flag(Action::Subscribe, "stream").is_err());
+    }
+
+    #[test]
+    fn test_resolve_claims() {
+        assert_eq!(resolve_claims(None, &ClaimsArgs::default()).unwrap(), None);
+
+        let args = ClaimsArgs {
+            subscribe: vec!["stream:topic/#".to_string()],
+            publish: vec!["stream:topic/out".to_string()],
+            ..Default::default()
+        };
+        let json = r#"[{"resource":{"stream":"other","prefix":"/tt","topic":"a/+","type":"topic"},"action":"subscribe"}]"#;
+        let claims = resolve_claims(Some(json), &args).unwrap().unwrap();
+        assert_eq!(claims.len(), 3);
+        assert_eq!(claims[0].resource().stream(), "other");
+        assert_eq!(claims[2].action(), Action::Publish);
+
+        assert!(resolve_claims(Some("[]"), &ClaimsArgs::default())
+            .unwrap()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_resolve_claims_reports_typos() {
+        let json =
+            r#"[{"resource":{"stream":"s","prefix":"/tt","topic":"t"},"action":"subcribe"}]"#;
+        let error = resolve_claims(Some(json), &ClaimsArgs::default()).unwrap_err();
+        assert!(matches!(error, DshError::InvalidClaims(_)));
+        assert!(error.to_string().contains("subcribe"));
+    }
+
+    #[test]
+    fn test_read_claims_file() {
+        let dir = test_dir("claims");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("claims.yaml");
+        std::fs::write(
+            &path,
+            "- action: publish\n  resource:\n    stream: s\n    prefix: /tt\n    topic: t/out\n",
+        )
+        .unwrap();
+
+        let args = ClaimsArgs {
+            claims_file: Some(path),
+            ..Default::default()
+        };
+        let claims = resolve_claims(None, &args).unwrap().unwrap();
+        assert_eq!(claims[0].action(), Action::Publish);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_claims() {
+        assert!(validate_claims(&[parse_flag(Action::Subscribe, "s:a/#").unwrap()]).is_ok());
+        assert!(validate_claims(&[parse_flag(Action::Subscribe, ":a/#").unwrap()]).is_err());
+        assert!(validate_claims(&[parse_flag(Action::Subscribe, "s:a/#:tt").unwrap()]).is_err());
+        assert!(validate_claims(&[parse_flag(Action::Publish, "s:a/#/b").unwrap()]).is_err());
+
+        let unknown =
+            r#"[{"resource":{"stream":"s","prefix":"/tt","topic":"a/#"},"action":"delete"}]"#;
+        let claims: Vec<Claims> = serde_json::from_str(unknown).unwrap();
+        assert!(validate_claims(&claims).is_err());
+    }
+}
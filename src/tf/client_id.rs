@@ -0,0 +1,190 @@
+use crate::error::DshError;
+use clap::Args;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// The placeholder in a client id template which is replaced by a counter.
+pub const COUNTER_PLACEHOLDER: &str = "{n}";
+
+/// Command-line flags to choose the MQTT client ids of the requested tokens.
+#[derive(Args, Debug, Default, Clone)]
+pub struct ClientIdArgs {
+    /// The client id of the token, only valid when requesting a single token.
+    #[clap(long, conflicts_with_all = ["client_id_template", "client_ids_file"])]
+    pub client_id: Option<String>,
+
+    // clap renders '{n}' in help texts as a newline, so it is spelled out here
+    /// Template for the client ids, where the placeholder 'n' between curly braces is replaced by a
+    /// counter.
+    #[clap(long, conflicts_with = "client_ids_file")]
+    pub client_id_template: Option<String>,
+
+    /// The first value of the client id template counter [default: 1].
+    #[clap(long, requires = "client_id_template")]
+    pub client_id_start: Option<usize>,
+
+    /// Read the client ids from a file, one per line. Empty lines and lines starting with '#' are skipped.
+    #[clap(long)]
+    pub client_ids_file: Option<PathBuf>,
+}
+
+/// How the client ids of the requested tokens are chosen.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub enum ClientIds {
+    /// A random client id for every token.
+    #[default]
+    Random,
+    /// The same client id for every token.
+    Fixed(String),
+    /// A client id rendered from a template with a counter starting at `start`.
+    Template { template: String, start: usize },
+    /// The client ids from a list, in order.
+    List(Vec<String>),
+}
+
+impl ClientIds {
+    /// Returns the client id for the request with the given index, starting at 0.
+    ///
+    /// Returns `None` when a random client id should be used.
+    pub fn id(&self, index: usize) -> Option<String> {
+        match self {
+            ClientIds::Random => None,
+            ClientIds::Fixed(id) => Some(id.clone()),
+            ClientIds::Template { template, start } => {
+                Some(template.replace(COUNTER_PLACEHOLDER, &(start + index).to_string()))
+            }
+            ClientIds::List(ids) => ids.get(index).cloned(),
+        }
+    }
+
+    /// Check that every one of `token_amount` tokens gets its own client id.
+    pub fn validate(&self, token_amount: usize) -> Result<(), DshError> {
+        match self {
+            ClientIds::Fixed(_) if token_amount > 1 => Err(DshError::DshCli(
+                "--client-id can only be used for a single token, use --client-id-template for more tokens."
+                    .to_string(),
+            )),
+            ClientIds::Template { template, .. }
+                if token_amount > 1 && !template.contains(COUNTER_PLACEHOLDER) =>
+            {
+                Err(DshError::DshCli(format!(
+                    "--client-id-template '{}' must contain '{}' to request more than one token.",
+                    template, COUNTER_PLACEHOLDER
+                )))
+            }
+            ClientIds::List(ids) if ids.len() < token_amount => Err(DshError::DshCli(format!(
+                "The client ids file contains {} client ids, while {} tokens are requested.",
+                ids.len(),
+                token_amount
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Determine the client ids from the command-line flags.
+pub fn resolve_client_ids(args: &ClientIdArgs) -> Result<ClientIds, DshError> {
+    if let Some(id) = &args.client_id {
+        if id.is_empty() {
+            return Err(DshError::DshCli("The client id is empty.".to_string()));
+        }
+        return Ok(ClientIds::Fixed(id.clone()));
+    }
+    if let Some(template) = &args.client_id_template {
+        return Ok(ClientIds::Template {
+            template: template.clone(),
+            start: args.client_id_start.unwrap_or(1),
+        });
+    }
+    if let Some(path) = &args.client_ids_file {
+        return Ok(ClientIds::List(read_client_ids_file(path)?));
+    }
+    Ok(ClientIds::Random)
+}
+
+/// Read client ids from a file with one client id per line.
+fn read_client_ids_file(path: &Path) -> Result<Vec<String>, DshError> {
+    let contents = std::fs::read_to_string(path)?;
+    let ids: Vec<String> = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+
+    let mut seen = HashSet::new();
+    if let Some(duplicate) = ids.iter().find(|id| !seen.insert(*id)) {
+        return Err(DshError::DshCli(format!(
+            "Client id '{}' occurs more than once in {}.",
+            duplicate,
+            path.display()
+        )));
+    }
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_dir;
+
+    #[test]
+    fn test_client_ids() {
+        assert_eq!(ClientIds::Random.id(3), None);
+        assert_eq!(
+            ClientIds::Fixed("a".to_string()).id(3),
+            Some("a".to_string())
+        );
+
+        let template = ClientIds::Template {
+            template: "device-{n}".to_string(),
+            start: 1,
+        };
+        assert_eq!(template.id(0), Some("device-1".to_string()));
+        assert_eq!(template.id(10), Some("device-11".to_string()));
+
+        let list = ClientIds::List(vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(list.id(1), Some("b".to_string()));
+        assert_eq!(list.id(2), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(ClientIds::Random.validate(10).is_ok());
+        assert!(ClientIds::Fixed("a".to_string()).validate(1).is_ok());
+        assert!(ClientIds::Fixed("a".to_string()).validate(2).is_err());
+
+        let template = |template: &str| ClientIds::Template {
+            template: template.to_string(),
+            start: 1,
+        };
+        assert!(template("device").validate(1).is_ok());
+        assert!(template("device").validate(2).is_err());
+        assert!(template("device-{n}").validate(2).is_ok());
+
+        let list = ClientIds::List(vec!["a".to_string()]);
+        assert!(list.validate(1).is_ok());
+        assert!(list.validate(2).is_err());
+    }
+
+    #[test]
+    fn test_resolve_client_ids_from_file() {
+        let dir = test_dir("client-ids");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("client-ids.txt");
+
+        std::fs::write(&path, "# fleet\ndevice-a\n\n  device-b  \n").unwrap();
+        let args = ClientIdArgs {
+            client_ids_file: Some(path.clone()),
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve_client_ids(&args).unwrap(),
+            ClientIds::List(vec!["device-a".to_string(), "device-b".to_string()])
+        );
+
+        std::fs::write(&path, "device-a\ndevice-a\n").unwrap();
+        assert!(resolve_client_ids(&args).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,250 @@
+use crate::error::DshError;
+use crate::tf::token::{Claims, Ports, Token};
+use chrono::{TimeZone, Utc};
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// The formats in which fetched tokens can be written.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum OutputFormat {
+    /// The raw JWT, one token per line.
+    #[default]
+    Raw,
+    /// A JSON document including the decoded token attributes.
+    Json,
+    /// One compact JSON document per line.
+    Jsonl,
+    /// Shell `export` statements, ready to `eval`.
+    Env,
+    /// A YAML document including the decoded token attributes.
+    Yaml,
+}
+
+impl OutputFormat {
+    /// Whether tokens can be appended to a file in this format, which is not the case for
+    /// the formats that write a single document, nor for the env format, as its variables
+    /// would replace the ones of the previous run when the file is evaluated.
+    pub fn can_append(&self) -> bool {
+        matches!(self, OutputFormat::Raw | OutputFormat::Jsonl)
+    }
+}
+
+/// A token together with its decoded attributes, as written in the structured formats.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct TokenOutput<'a> {
+    pub token: &'a str,
+    pub endpoint: &'a str,
+    pub ports: &'a Ports,
+    pub client_id: &'a str,
+    pub tenant_id: &'a str,
+    pub claims: &'a [Claims],
+    pub iat: String,
+    pub exp: String,
+}
+
+impl<'a> From<&'a Token> for TokenOutput<'a> {
+    fn from(token: &'a Token) -> Self {
+        let attributes = &token.token_attributes;
+        TokenOutput {
+            token: &token.raw_token,
+            endpoint: &attributes.endpoint,
+            ports: &attributes.ports,
+            client_id: &attributes.client_id,
+            tenant_id: &attributes.tenant_id,
+            claims: &attributes.claims,
+            iat: rfc3339(attributes.iat()),
+            exp: rfc3339(attributes.exp()),
+        }
+    }
+}
+
+/// Format a timestamp in seconds since the Unix epoch as RFC3339.
+pub fn rfc3339(timestamp: i64) -> String {
+    match Utc.timestamp_opt(timestamp, 0).single() {
+        Some(datetime) => datetime.to_rfc3339(),
+        None => timestamp.to_string(),
+    }
+}
+
+/// Format a single token, for example to write it to its own file.
+pub fn format_token(token: &Token, format: OutputFormat) -> Result<String, DshError> {
+    match format {
+        OutputFormat::Raw => Ok(format!("{}\n", token.raw_token)),
+        OutputFormat::Json => Ok(format!(
+            "{}\n",
+            serde_json::to_string_pretty(&TokenOutput::from(token))?
+        )),
+        OutputFormat::Jsonl => Ok(format!(
+            "{}\n",
+            serde_json::to_string(&TokenOutput::from(token))?
+        )),
+        OutputFormat::Env => format_env(token, ""),
+        OutputFormat::Yaml => Ok(serde_yaml::to_string(&TokenOutput::from(token))?),
+    }
+}
+
+/// Formats tokens one at a time into a single document, so every token can be written
+/// as soon as it is received.
+///
+/// The `json` and `yaml` formats result in a list. With the `env` format, the variable
+/// names get the index of the token as suffix when more than one token is expected.
+#[derive(Debug, Clone)]
+pub struct TokenFormatter {
+    format: OutputFormat,
+    total: usize,
+    count: usize,
+}
+
+impl TokenFormatter {
+    /// Create a formatter for a document of `total` tokens.
+    pub fn new(format: OutputFormat, total: usize) -> Self {
+        TokenFormatter {
+            format,
+            total,
+            count: 0,
+        }
+    }
+
+    /// Format the next token, including the start of the document for the first token.
+    pub fn next(&mut self, token: &Token) -> Result<String, DshError> {
+        let index = self.count;
+        self.count += 1;
+        match self.format {
+            OutputFormat::Raw | OutputFormat::Jsonl => format_token(token, self.format),
+            OutputFormat::Json => Ok(format!(
+                "{}{}",
+                if index == 0 { "[\n" } else { ",\n" },
+                serde_json::to_string_pretty(&TokenOutput::from(token))?
+            )),
+            OutputFormat::Env if self.total == 1 => format_env(token, ""),
+            OutputFormat::Env => format_env(token, &format!("_{}", index)),
+            OutputFormat::Yaml => Ok(serde_yaml::to_string(&[TokenOutput::from(token)])?),
+        }
+    }
+
+    /// Returns the end of the document.
+    pub fn finish(&self) -> &'static str {
+        match (self.format, self.count) {
+            (OutputFormat::Json | OutputFormat::Yaml, 0) => "[]\n",
+            (OutputFormat::Json, _) => "\n]\n",
+            _ => "",
+        }
+    }
+}
+
+/// Format a token as shell `export` statements, with `suffix` appended to the variable names.
+fn format_env(token: &Token, suffix: &str) -> Result<String, DshError> {
+    let attributes = &token.token_attributes;
+    let join_ports = |ports: &[u16]| {
+        ports
+            .iter()
+            .map(|port| port.to_string())
+            .collect::<Vec<String>>()
+            .join(",")
+    };
+    let variables = [
+        ("DSH_MQTT_TOKEN", token.raw_token.clone()),
+        ("DSH_MQTT_ENDPOINT", attributes.endpoint.clone()),
+        ("DSH_MQTT_CLIENT_ID", attributes.client_id.clone()),
+        ("DSH_MQTT_TENANT_ID", attributes.tenant_id.clone()),
+        ("DSH_MQTT_PORTS_MQTTS", join_ports(&attributes.ports.mqtts)),
+        (
+            "DSH_MQTT_PORTS_MQTTWSS",
+            join_ports(&attributes.ports.mqttwss),
+        ),
+        (
+            "DSH_MQTT_CLAIMS",
+            serde_json::to_string(&attributes.claims)?,
+        ),
+        ("DSH_MQTT_IAT", rfc3339(attributes.iat())),
+        ("DSH_MQTT_EXP", rfc3339(attributes.exp())),
+    ];
+    Ok(variables
+        .iter()
+        .map(|(name, value)| format!("export {}{}={}\n", name, suffix, shell_quote(value)))
+        .collect())
+}
+
+/// Quote a value for use in a POSIX shell.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Format a set of tokens as a single document.
+    fn format_tokens(tokens: &[Token], format: OutputFormat) -> Result<String, DshError> {
+        let mut formatter = TokenFormatter::new(format, tokens.len());
+        let mut formatted = String::new();
+        for token in tokens {
+            formatted.push_str(&formatter.next(token)?);
+        }
+        formatted.push_str(formatter.finish());
+        Ok(formatted)
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(1666284104), "2022-10-20T16:41:44+00:00");
+    }
+
+    #[test]
+    fn test_format_json_contains_attributes() {
+        let token = Token::for_test("device-1", 1666284104);
+        let formatted = format_token(&token, OutputFormat::Json).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&formatted).unwrap();
+        assert_eq!(value["client-id"], "device-1");
+        assert_eq!(value["endpoint"], "mqtt.example.com");
+        assert_eq!(value["exp"], "2022-10-20T16:41:44+00:00");
+        assert_eq!(value["ports"]["mqtts"][0], 8883);
+    }
+
+    #[test]
+    fn test_format_tokens_jsonl_and_json() {
+        let tokens = vec![
+            Token::for_test("device-1", 1666284104),
+            Token::for_test("device-2", 1666284104),
+        ];
+        let jsonl = format_tokens(&tokens, OutputFormat::Jsonl).unwrap();
+        assert_eq!(jsonl.lines().count(), 2);
+
+        let json = format_tokens(&tokens, OutputFormat::Json).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value.as_array().unwrap().len(), 2);
+
+        let json = format_tokens(&[], OutputFormat::Json).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert!(value.as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_format_tokens_yaml() {
+        let tokens = vec![
+            Token::for_test("device-1", 1666284104),
+            Token::for_test("device-2", 1666284104),
+        ];
+        let yaml = format_tokens(&tokens, OutputFormat::Yaml).unwrap();
+        let value: serde_yaml::Value = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(value.as_sequence().unwrap().len(), 2);
+        assert_eq!(value[1]["client-id"], "device-2");
+    }
+
+    #[test]
+    fn test_format_env() {
+        let token = Token::for_test("device-1", 1666284104);
+        let env = format_tokens(&[token.clone(), token.clone()], OutputFormat::Env).unwrap();
+        assert!(env.contains("export DSH_MQTT_CLIENT_ID_1='device-1'\n"));
+
+        let env = format_token(&token, OutputFormat::Env).unwrap();
+        assert!(env.contains("export DSH_MQTT_PORTS_MQTTWSS='443,8443'\n"));
+        assert!(env.contains(&format!("export DSH_MQTT_TOKEN='{}'\n", token.raw_token)));
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+}
@@ -0,0 +1,159 @@
+use crate::error::DshError;
+use crate::tf::cache::now;
+use crate::tf::format::rfc3339;
+use crate::tf::token::Token;
+use clap::Args;
+use std::fmt::Write;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Decode an existing token and explain what it allows.
+#[derive(Args, Debug)]
+pub struct InspectCommand {
+    /// The token to inspect. When omitted or '-', the token is read from stdin.
+    token: Option<String>,
+
+    /// Read the token from a file.
+    #[clap(short, long, conflicts_with = "token")]
+    file: Option<PathBuf>,
+}
+
+/// Read a raw token from the argument, a file or stdin, in that order.
+pub fn read_raw_token(token: Option<&str>, file: Option<&Path>) -> Result<String, DshError> {
+    let raw_token = match (token, file) {
+        (Some(token), _) if token != "-" => token.to_string(),
+        (_, Some(file)) => std::fs::read_to_string(file)?,
+        _ => {
+            let mut input = String::new();
+            std::io::stdin().read_to_string(&mut input)?;
+            input
+        }
+    };
+    let raw_token = raw_token.trim();
+    if raw_token.is_empty() {
+        return Err(DshError::DshCli("No token provided.".to_string()));
+    }
+    Ok(raw_token.to_string())
+}
+
+/// Format a number of seconds as a human readable duration, e.g. `1h 2m 3s`.
+pub fn format_duration(seconds: i64) -> String {
+    let seconds = seconds.abs();
+    let (days, hours, minutes, seconds) = (
+        seconds / 86400,
+        seconds % 86400 / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+    );
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{}s", seconds),
+        (0, 0, _) => format!("{}m {}s", minutes, seconds),
+        (0, _, _) => format!("{}h {}m {}s", hours, minutes, seconds),
+        _ => format!("{}d {}h {}m", days, hours, minutes),
+    }
+}
+
+/// Describe the header, attributes and claims of a token relative to `now`.
+fn describe(token: &Token, now: i64) -> Result<String, DshError> {
+    let header = token.header()?;
+    let attributes = &token.token_attributes;
+    let mut out = String::new();
+
+    // `write!` to a String cannot fail
+    let _ = writeln!(out, "Header");
+    let _ = writeln!(out, "  {:<14} {}", "alg:", header.alg);
+    let _ = writeln!(
+        out,
+        "  {:<14} {}",
+        "typ:",
+        header.typ.as_deref().unwrap_or("-")
+    );
+
+    let exp = attributes.exp();
+    let validity = if exp > now {
+        format!("valid for {}", format_duration(exp - now))
+    } else {
+        format!("expired {} ago", format_duration(now - exp))
+    };
+    let _ = writeln!(out, "\nToken attributes");
+    let _ = writeln!(out, "  {:<14} {}", "tenant-id:", attributes.tenant_id);
+    let _ = writeln!(out, "  {:<14} {}", "client-id:", attributes.client_id);
+    let _ = writeln!(out, "  {:<14} {}", "endpoint:", attributes.endpoint);
+    let _ = writeln!(out, "  {:<14} {}", "issued at:", rfc3339(attributes.iat()));
+    let _ = writeln!(
+        out,
+        "  {:<14} {} ({})",
+        "expires at:",
+        rfc3339(exp),
+        validity
+    );
+
+    let _ = writeln!(out, "\nGranted connections");
+    for port in &attributes.ports.mqtts {
+        let _ = writeln!(out, "  mqtts://{}:{}", attributes.endpoint, port);
+    }
+    for port in &attributes.ports.mqttwss {
+        let _ = writeln!(out, "  wss://{}:{}/mqtt", attributes.endpoint, port);
+    }
+
+    let _ = writeln!(out, "\nClaims");
+    if attributes.claims.is_empty() {
+        let _ = writeln!(out, "  none");
+    } else {
+        let _ = writeln!(
+            out,
+            "  {:<10} {:<20} {:<8} {:<30} TYPE",
+            "ACTION", "STREAM", "PREFIX", "TOPIC"
+        );
+        for claim in &attributes.claims {
+            let resource = claim.resource();
+            let _ = writeln!(
+                out,
+                "  {:<10} {:<20} {:<8} {:<30} {}",
+                claim.action(),
+                resource.stream(),
+                resource.prefix(),
+                resource.topic(),
+                resource
+                    .resource_type()
+                    .map_or("-".to_string(), |t| t.to_string()),
+            );
+        }
+    }
+    Ok(out)
+}
+
+/// Run the inspect subcommand.
+pub fn run(cmd: &InspectCommand) -> Result<(), DshError> {
+    let raw_token = read_raw_token(cmd.token.as_deref(), cmd.file.as_deref())?;
+    let token = Token::new(raw_token)?;
+    print!("{}", describe(&token, now())?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(42), "42s");
+        assert_eq!(format_duration(-62), "1m 2s");
+        assert_eq!(format_duration(3723), "1h 2m 3s");
+        assert_eq!(format_duration(90061), "1d 1h 1m");
+    }
+
+    #[test]
+    fn test_describe() {
+        let token = Token::for_test("device-1", 1666284104);
+        let description = describe(&token, 1666284104 - 90).unwrap();
+        assert!(description.contains("alg:           HS256"));
+        assert!(description.contains("client-id:     device-1"));
+        assert!(description.contains("(valid for 1m 30s)"));
+        assert!(description.contains("mqtts://mqtt.example.com:8883"));
+        assert!(description.contains("wss://mqtt.example.com:8443/mqtt"));
+
+        let description = describe(&token, 1666284104 + 5).unwrap();
+        assert!(description.contains("(expired 5s ago)"));
+    }
+}
@@ -0,0 +1,505 @@
+use crate::error::DshError;
+use crate::tf::format::{format_token, OutputFormat, TokenFormatter};
+use crate::tf::token::Token;
+use clap::ValueEnum;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Describes where and how fetched tokens are written.
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    /// The output file, or the file name template when `file_per_token` is set.
+    ///
+    /// If not specified, the tokens are written to stdout.
+    pub path: Option<PathBuf>,
+    /// Write every token to its own file, named after the `path` template.
+    pub file_per_token: bool,
+    /// Append the tokens to the output file instead of replacing it.
+    pub append: bool,
+    /// The format in which the tokens are written.
+    pub format: OutputFormat,
+}
+
+impl OutputOptions {
+    /// Check that the options can be combined for `total` tokens, before any token is requested.
+    ///
+    /// A file name template must contain `{client_id}` or `{index}` for more than one token,
+    /// as the client-ids of a fetch are unique, so every token gets its own file.
+    pub fn validate(&self, total: usize) -> Result<(), DshError> {
+        if let Some(template) = self.path.as_ref().filter(|_| self.file_per_token) {
+            let template = template.to_string_lossy();
+            if total > 1 && !template.contains("{client_id}") && !template.contains("{index}") {
+                return Err(DshError::DshCli(format!(
+                    "Output template {} results in the same file for multiple tokens, use {{client_id}} or {{index}}.",
+                    template
+                )));
+            }
+        }
+        if self.append && !self.format.can_append() {
+            let format = self.format.to_possible_value().expect("no skipped formats");
+            return Err(DshError::DshCli(format!(
+                "Tokens in the {} format can not be appended to a file, use the raw or jsonl format.",
+                format.get_name()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Open a writer for `total` tokens, which writes every token as soon as it is received.
+    pub fn open(&self, total: usize) -> TokenWriter {
+        let target = match &self.path {
+            None => Target::Stdout,
+            Some(template) if self.file_per_token => Target::PerToken {
+                template: template.clone(),
+            },
+            Some(path) => Target::File {
+                path: path.clone(),
+                append: self.append,
+                file: None,
+                tmp_path: None,
+            },
+        };
+        TokenWriter {
+            target,
+            formatter: TokenFormatter::new(self.format, total),
+            format: self.format,
+            count: 0,
+        }
+    }
+
+    /// Returns the file the token of request `index` is written to, `None` for stdout.
+    pub fn token_path(&self, token: &Token, index: usize) -> Option<PathBuf> {
+        let path = self.path.as_ref()?;
+        if self.file_per_token {
+            Some(render_template(path, token, index))
+        } else {
+            Some(path.clone())
+        }
+    }
+
+    /// Write the tokens to stdout or to file(s) according to the options, in the order of
+    /// their requests.
+    pub fn write(&self, tokens: &[Token]) -> Result<(), DshError> {
+        self.validate(tokens.len())?;
+        let mut writer = self.open(tokens.len());
+        for (index, token) in tokens.iter().enumerate() {
+            writer.write_token(index + 1, token.clone())?;
+        }
+        writer.finish()
+    }
+}
+
+/// Receives the tokens of a fetch one at a time, as soon as they are received.
+pub trait TokenSink {
+    /// Receive the token of request `index`, numbered from 1 like the requests in the report.
+    fn write_token(&mut self, index: usize, token: Token) -> Result<(), DshError>;
+}
+
+/// Collects the tokens in memory, together with the index of their request.
+impl TokenSink for Vec<(usize, Token)> {
+    fn write_token(&mut self, index: usize, token: Token) -> Result<(), DshError> {
+        self.push((index, token));
+        Ok(())
+    }
+}
+
+/// Returns the collected tokens in the order of their requests.
+pub fn in_request_order(mut tokens: Vec<(usize, Token)>) -> Vec<Token> {
+    tokens.sort_by_key(|(index, _)| *index);
+    tokens.into_iter().map(|(_, token)| token).collect()
+}
+
+/// Where a `TokenWriter` writes to.
+enum Target {
+    Stdout,
+    /// A single file, opened when the first token is written. Unless appending, the tokens
+    /// are written to the temporary file `tmp_path` which replaces `path` when finished.
+    File {
+        path: PathBuf,
+        append: bool,
+        file: Option<BufWriter<fs::File>>,
+        tmp_path: Option<PathBuf>,
+    },
+    /// A file per token, named after the template.
+    PerToken {
+        template: PathBuf,
+    },
+}
+
+/// Writes tokens to stdout or file(s) as they are received, without keeping them in memory.
+///
+/// Nothing is written when no token is received, so an existing output file is left untouched.
+pub struct TokenWriter {
+    target: Target,
+    formatter: TokenFormatter,
+    format: OutputFormat,
+    count: usize,
+}
+
+impl TokenSink for TokenWriter {
+    fn write_token(&mut self, index: usize, token: Token) -> Result<(), DshError> {
+        self.count += 1;
+        match &mut self.target {
+            Target::Stdout => {
+                let mut stdout = std::io::stdout();
+                stdout.write_all(self.formatter.next(&token)?.as_bytes())?;
+                stdout.flush()?;
+            }
+            Target::File {
+                path,
+                append,
+                file,
+                tmp_path,
+            } => {
+                let file = match file {
+                    Some(file) => file,
+                    None if *append => file.insert(BufWriter::new(open_append_file(path)?)),
+                    None => {
+                        let (new_file, new_tmp_path) = create_temp_file(path)?;
+                        *tmp_path = Some(new_tmp_path);
+                        file.insert(BufWriter::new(new_file))
+                    }
+                };
+                file.write_all(self.formatter.next(&token)?.as_bytes())?;
+            }
+            Target::PerToken { template } => {
+                let path = render_template(template, &token, index);
+                write_private_file(&path, format_token(&token, self.format)?.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TokenWriter {
+    /// Write the end of the document and, unless appending, move the output file into place.
+    pub fn finish(mut self) -> Result<(), DshError> {
+        match &mut self.target {
+            Target::Stdout if self.count > 0 => {
+                print!("{}", self.formatter.finish());
+            }
+            Target::File {
+                path,
+                file: Some(file),
+                tmp_path,
+                ..
+            } => {
+                file.write_all(self.formatter.finish().as_bytes())?;
+                file.flush()?;
+                file.get_ref().sync_all()?;
+                if let Some(tmp_path) = tmp_path {
+                    fs::rename(tmp_path, &*path)?;
+                }
+            }
+            _ => {}
+        }
+        // The file has been moved into place, prevent `drop` from removing it.
+        self.target = Target::Stdout;
+        Ok(())
+    }
+}
+
+impl Drop for TokenWriter {
+    /// Remove the temporary file of an unfinished writer, so no partial output is left behind.
+    fn drop(&mut self) {
+        if let Target::File {
+            tmp_path: Some(tmp_path),
+            ..
+        } = &self.target
+        {
+            let _ = fs::remove_file(tmp_path);
+        }
+    }
+}
+
+/// The number of attempts to find an unused name for a temporary file.
+const TMP_ATTEMPTS: usize = 16;
+
+/// Create a new temporary file next to `path`, used to replace `path` atomically.
+///
+/// The temporary file gets a random name and is created exclusively, so an existing file
+/// or a symbolic link planted at its location is never written to.
+fn create_temp_file(path: &Path) -> Result<(fs::File, PathBuf), DshError> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(parent)?;
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| DshError::DshCli(format!("Invalid output file {}", path.display())))?
+        .to_string_lossy();
+    for _ in 0..TMP_ATTEMPTS {
+        let tmp_path = parent.join(format!(".{}.{:016x}.tmp", file_name, rand::random::<u64>()));
+        match private_open_options().create_new(true).open(&tmp_path) {
+            Ok(file) => return Ok((file, tmp_path)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(DshError::DshCli(format!(
+        "Could not create a temporary file for {}",
+        path.display()
+    )))
+}
+
+/// Open the output file for appending, restricting the permissions of an existing file to
+/// the current user.
+fn open_append_file(path: &Path) -> Result<fs::File, DshError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file = private_open_options()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(file)
+}
+
+/// Render the file name template for a token.
+///
+/// Supported placeholders are `{client_id}`, `{tenant}` and `{index}`, the number of the
+/// request of the token starting at 1. Path separators and
+/// `..` in the client-id and tenant are replaced by `_`, so a token can not direct the
+/// output outside the directory of the template.
+pub fn render_template(template: &Path, token: &Token, index: usize) -> PathBuf {
+    let rendered = template
+        .to_string_lossy()
+        .replace("{client_id}", &sanitize(&token.token_attributes.client_id))
+        .replace("{tenant}", &sanitize(&token.token_attributes.tenant_id))
+        .replace("{index}", &index.to_string());
+    PathBuf::from(rendered)
+}
+
+/// Returns the value with path separators and `..` replaced, for use in a file name.
+fn sanitize(value: &str) -> String {
+    value.replace(['/', '\\'], "_").replace("..", "_")
+}
+
+/// Returns `OpenOptions` which create files with permissions for the current user only.
+fn private_open_options() -> fs::OpenOptions {
+    let mut options = fs::OpenOptions::new();
+    options.write(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+}
+
+/// Write a file via a temporary file and a rename, with permissions for the current user only.
+///
+/// Readers of `path` either see the previous or the new contents, never a partially written file.
+pub fn write_private_file(path: &Path, contents: &[u8]) -> Result<(), DshError> {
+    let (mut file, tmp_path) = create_temp_file(path)?;
+    let written = file
+        .write_all(contents)
+        .and_then(|_| file.sync_all())
+        .and_then(|_| fs::rename(&tmp_path, path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_dir;
+
+    #[test]
+    fn test_render_template() {
+        let token = Token::for_test("device-1", 1700000000);
+        let path = render_template(Path::new("out/{tenant}/{client_id}-{index}.jwt"), &token, 3);
+        assert_eq!(path, PathBuf::from("out/tenant/device-1-3.jwt"));
+
+        let token = Token::for_test("../../etc/passwd", 1700000000);
+        let path = render_template(Path::new("out/{client_id}.jwt"), &token, 0);
+        assert_eq!(path, PathBuf::from("out/____etc_passwd.jwt"));
+    }
+
+    #[test]
+    fn test_write_file_per_token_rejects_duplicates() {
+        let dir = test_dir("duplicates");
+        let tokens = vec![
+            Token::for_test("device-1", 1700000000),
+            Token::for_test("device-2", 1700000000),
+        ];
+        let options = OutputOptions {
+            path: Some(dir.join("token.jwt")),
+            file_per_token: true,
+            ..Default::default()
+        };
+        assert!(options.write(&tokens).is_err());
+        assert!(!dir.exists());
+        assert!(options.write(&tokens[..1]).is_ok());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_unfinished_writer_leaves_output_untouched() {
+        let dir = test_dir("unfinished");
+        let path = dir.join("tokens.json");
+        let options = OutputOptions {
+            path: Some(path.clone()),
+            format: OutputFormat::Json,
+            ..Default::default()
+        };
+        options
+            .write(&[Token::for_test("device-1", 1700000000)])
+            .unwrap();
+        let previous = fs::read_to_string(&path).unwrap();
+
+        let mut writer = options.open(2);
+        writer
+            .write_token(1, Token::for_test("device-2", 1700000000))
+            .unwrap();
+        drop(writer);
+        assert_eq!(fs::read_to_string(&path).unwrap(), previous);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        options.open(1).finish().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), previous);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_single_file_and_append() {
+        let dir = test_dir("single");
+        let path = dir.join("tokens.jwt");
+        let tokens = vec![
+            Token::for_test("device-1", 1700000000),
+            Token::for_test("device-2", 1700000000),
+        ];
+
+        let mut options = OutputOptions {
+            path: Some(path.clone()),
+            ..Default::default()
+        };
+        options.write(&tokens).unwrap();
+        options.write(&tokens[..1]).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        }
+
+        options.append = true;
+        options.write(&tokens).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_private_file_does_not_follow_symlinks() {
+        let dir = test_dir("symlink");
+        let path = dir.join("cache.json");
+        let target = dir.join("target");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&target, "unchanged").unwrap();
+        std::os::unix::fs::symlink(&target, dir.join("cache.json.tmp")).unwrap();
+
+        write_private_file(&path, b"contents").unwrap();
+        write_private_file(&path, b"new contents").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new contents");
+        assert_eq!(fs::read_to_string(&target).unwrap(), "unchanged");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_append_format() {
+        let mut options = OutputOptions {
+            path: Some(PathBuf::from("tokens")),
+            append: true,
+            ..Default::default()
+        };
+        for format in [OutputFormat::Raw, OutputFormat::Jsonl] {
+            options.format = format;
+            assert!(options.validate(1).is_ok());
+        }
+        // appended env variables would replace the ones of the previous run
+        for format in [OutputFormat::Json, OutputFormat::Yaml, OutputFormat::Env] {
+            options.format = format;
+            let error = options.validate(1).unwrap_err().to_string();
+            assert!(error.contains("can not be appended"), "{}", error);
+        }
+        options.append = false;
+        assert!(options.validate(1).is_ok());
+    }
+
+    #[test]
+    fn test_write_file_per_token() {
+        let dir = test_dir("per-token");
+        let tokens = vec![
+            Token::for_test("device-1", 1700000000),
+            Token::for_test("device-2", 1700000000),
+        ];
+        let options = OutputOptions {
+            path: Some(dir.join("{client_id}.jwt")),
+            file_per_token: true,
+            ..Default::default()
+        };
+        options.write(&tokens).unwrap();
+
+        assert_eq!(
+            options.token_path(&tokens[1], 2),
+            Some(dir.join("device-2.jwt"))
+        );
+        let contents = fs::read_to_string(dir.join("device-2.jwt")).unwrap();
+        assert_eq!(contents.trim(), tokens[1].raw_token);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_file_per_token_by_request_index() {
+        let dir = test_dir("per-index");
+        let options = OutputOptions {
+            path: Some(dir.join("token-{index}.jwt")),
+            file_per_token: true,
+            ..Default::default()
+        };
+        // the responses arrive in another order than the requests were made
+        let mut writer = options.open(3);
+        for (index, client_id) in [(3, "device-3"), (1, "device-1"), (2, "device-2")] {
+            writer
+                .write_token(index, Token::for_test(client_id, 1700000000))
+                .unwrap();
+        }
+        writer.finish().unwrap();
+
+        for (index, client_id) in [(1, "device-1"), (2, "device-2"), (3, "device-3")] {
+            let path = dir.join(format!("token-{}.jwt", index));
+            let contents = fs::read_to_string(path).unwrap();
+            let expected = Token::for_test(client_id, 1700000000).raw_token;
+            assert_eq!(contents.trim(), expected);
+        }
+        assert!(!dir.join("token-0.jwt").exists());
+
+        let collected = vec![
+            (2, Token::for_test("device-2", 1700000000)),
+            (1, Token::for_test("device-1", 1700000000)),
+        ];
+        let tokens = in_request_order(collected);
+        assert_eq!(tokens[0].token_attributes.client_id, "device-1");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,572 @@
+use crate::error::DshError;
+use crate::tf::cache::now;
+use crate::tf::client_id::ClientIds;
+use crate::tf::format::{format_token, OutputFormat};
+use crate::tf::output::TokenSink;
+use crate::tf::report::Progress;
+use crate::tf::token::Token;
+use crate::tf::{fetch_tokens, get_request_attributes, Command, RequestAttributes};
+use clap::Args;
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::Notify;
+
+/// The longest time the refiller sleeps before checking for expiring tokens.
+const MAX_IDLE: Duration = Duration::from_secs(60);
+
+/// Keep a pool of valid, unused tokens ready and hand them out one at a time.
+///
+/// Tokens are taken with a line protocol on stdin/stdout or a Unix socket:
+/// `take [N]` (or an empty line) returns N tokens, one per line, `status` returns the
+/// number of available tokens and `quit` closes the connection. Every token is handed
+/// out once, so every client gets its own client id. A take which can not be served,
+/// because refilling the pool failed or no token arrived in time, returns a line starting
+/// with `error:`.
+#[derive(Args, Debug)]
+pub struct PoolCommand {
+    /// The number of tokens to keep ready.
+    #[clap(long, default_value_t = 10)]
+    size: usize,
+
+    /// Serve the line protocol on this Unix socket instead of stdin/stdout.
+    #[clap(long)]
+    socket: Option<PathBuf>,
+
+    /// The number of seconds a take waits for a token when the pool is empty.
+    #[clap(long, default_value_t = 30)]
+    take_timeout: u64,
+}
+
+/// Tokens ready to be taken, refilled in the background.
+struct Pool {
+    tokens: Mutex<VecDeque<Token>>,
+    size: usize,
+    /// Tokens expiring within this number of seconds are dropped from the pool.
+    margin: u64,
+    /// The number of failed refills and the error of the last one.
+    failures: Mutex<(usize, String)>,
+    /// Whether the refiller stopped, so no tokens are added anymore.
+    stopped: AtomicBool,
+    /// Notified when tokens are added or a refill failed.
+    added: Notify,
+    /// Notified when tokens are taken.
+    taken: Notify,
+}
+
+impl Pool {
+    fn new(size: usize, margin: u64) -> Self {
+        Pool {
+            tokens: Mutex::new(VecDeque::with_capacity(size)),
+            size,
+            margin,
+            failures: Mutex::new((0, String::new())),
+            stopped: AtomicBool::new(false),
+            added: Notify::new(),
+            taken: Notify::new(),
+        }
+    }
+
+    /// Returns whether a token is still usable at `now`.
+    fn is_fresh(&self, token: &Token, now: i64) -> bool {
+        token.token_attributes.exp() - self.margin as i64 > now
+    }
+
+    /// Drop the tokens which are about to expire and return the number of available tokens.
+    fn available(&self, now: i64) -> usize {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|token| self.is_fresh(token, now));
+        tokens.len()
+    }
+
+    /// Take a token if one is available.
+    fn try_take(&self, now: i64) -> Option<Token> {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|token| self.is_fresh(token, now));
+        let token = tokens.pop_front();
+        if token.is_some() {
+            self.taken.notify_one();
+        }
+        token
+    }
+
+    /// Take a token, waiting for the refiller when the pool is empty.
+    ///
+    /// Fails when a refill fails or the refiller stopped while waiting, or when no token
+    /// arrives within `timeout`.
+    async fn take(&self, timeout: Duration) -> Result<Token, DshError> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let failures = self.failures.lock().unwrap().0;
+        loop {
+            // register before checking, so a token added in between is not missed
+            let added = self.added.notified();
+            if let Some(token) = self.try_take(now()) {
+                return Ok(token);
+            }
+            {
+                let (current_failures, error) = &*self.failures.lock().unwrap();
+                if *current_failures != failures || self.stopped.load(Ordering::SeqCst) {
+                    return Err(DshError::DshCli(format!("no token available: {}", error)));
+                }
+            }
+            if tokio::time::timeout_at(deadline, added).await.is_err() {
+                return Err(DshError::DshCli(format!(
+                    "no token available within {} seconds",
+                    timeout.as_secs()
+                )));
+            }
+        }
+    }
+
+    /// Record a failed refill, failing the clients waiting for a token.
+    fn fail(&self, error: String, stop: bool) {
+        {
+            let mut failures = self.failures.lock().unwrap();
+            failures.0 += 1;
+            failures.1 = error;
+        }
+        if stop {
+            self.stopped.store(true, Ordering::SeqCst);
+        }
+        self.added.notify_waiters();
+    }
+
+    /// Returns how long until the first token is about to expire.
+    fn next_expiry(&self, now: i64) -> Duration {
+        let tokens = self.tokens.lock().unwrap();
+        let first = tokens
+            .iter()
+            .map(|token| token.token_attributes.exp() - self.margin as i64)
+            .min();
+        match first {
+            Some(first) => Duration::from_secs((first - now).max(1) as u64).min(MAX_IDLE),
+            None => MAX_IDLE,
+        }
+    }
+}
+
+/// Adds the fetched tokens to the pool as soon as they arrive.
+impl TokenSink for &Pool {
+    fn write_token(&mut self, _index: usize, token: Token) -> Result<(), DshError> {
+        self.tokens.lock().unwrap().push_back(token);
+        self.added.notify_waiters();
+        Ok(())
+    }
+}
+
+/// Adds the fetched tokens of a refill to the pool and records their client ids.
+struct RefillSink<'a> {
+    pool: &'a Pool,
+    received: HashSet<String>,
+}
+
+impl TokenSink for RefillSink<'_> {
+    fn write_token(&mut self, index: usize, token: Token) -> Result<(), DshError> {
+        self.received
+            .insert(token.token_attributes.client_id.clone());
+        let mut pool = self.pool;
+        pool.write_token(index, token)
+    }
+}
+
+/// Hands out the client ids for the refills of the pool.
+///
+/// A client id for which no token was received is handed out again by the next refill,
+/// every other client id is handed out once.
+struct ClientIdQueue {
+    client_ids: ClientIds,
+    /// The number of client ids taken from `client_ids` so far.
+    issued: usize,
+    /// The client ids of failed requests, handed out before new client ids.
+    unused: VecDeque<String>,
+}
+
+impl ClientIdQueue {
+    fn new(client_ids: ClientIds) -> Self {
+        ClientIdQueue {
+            client_ids,
+            issued: 0,
+            unused: VecDeque::new(),
+        }
+    }
+
+    /// Returns the client ids for a refill of at most `amount` tokens, fewer when the
+    /// client ids run out.
+    fn next(&mut self, amount: usize) -> Result<ClientIds, DshError> {
+        if self.client_ids == ClientIds::Random {
+            return Ok(ClientIds::Random);
+        }
+        let reused = self.unused.len().min(amount);
+        let mut ids: Vec<String> = self.unused.drain(..reused).collect();
+        while ids.len() < amount {
+            match self.client_ids.id(self.issued) {
+                Some(id) => ids.push(id),
+                None => break,
+            }
+            self.issued += 1;
+        }
+        if ids.is_empty() {
+            return Err(DshError::DshCli(
+                "All client ids of the client ids file are used.".to_string(),
+            ));
+        }
+        Ok(ClientIds::List(ids))
+    }
+
+    /// Hand out the client ids of a refill again for which no token was received.
+    fn release(&mut self, requested: &ClientIds, received: &HashSet<String>) {
+        if let ClientIds::List(ids) = requested {
+            let failed = ids.iter().filter(|id| !received.contains(*id));
+            self.unused.extend(failed.cloned());
+        }
+    }
+}
+
+/// Keep the pool filled, fetching new tokens when tokens are taken or about to expire.
+///
+/// A client id from a template or file is never handed out twice, see `ClientIdQueue`.
+/// Refilling stops when the client ids file runs out.
+async fn refill(pool: Arc<Pool>, request_attributes: RequestAttributes) {
+    let mut failures = 0;
+    let mut filled = false;
+    let mut client_ids = ClientIdQueue::new(request_attributes.client_ids.clone());
+    loop {
+        let missing = pool.size.saturating_sub(pool.available(now()));
+        if missing > 0 {
+            let requested = match client_ids.next(missing) {
+                Ok(requested) => requested,
+                Err(e) => {
+                    error!("Refilling the token pool stopped: {}", e);
+                    pool.fail(e.to_string(), true);
+                    return;
+                }
+            };
+            let missing_attributes = RequestAttributes {
+                token_amount: match &requested {
+                    ClientIds::List(ids) => ids.len(),
+                    _ => missing,
+                },
+                client_ids: requested,
+                ..request_attributes.clone()
+            };
+            let mut sink = RefillSink {
+                pool: &pool,
+                received: HashSet::new(),
+            };
+            let fetched =
+                fetch_tokens(&missing_attributes, &mut sink, &mut Progress::hidden()).await;
+            client_ids.release(&missing_attributes.client_ids, &sink.received);
+            match fetched {
+                Ok(report) if report.received > 0 => failures = 0,
+                Ok(report) => {
+                    error!("Refilling the token pool failed: {}", report.summary());
+                    pool.fail(report.summary(), false);
+                    failures += 1;
+                }
+                Err(e) => {
+                    error!("Refilling the token pool failed: {}", e);
+                    pool.fail(e.to_string(), false);
+                    failures += 1;
+                }
+            }
+            if failures > 0 {
+                tokio::time::sleep(request_attributes.retry.backoff(failures - 1)).await;
+                continue;
+            }
+            if !filled && pool.available(now()) >= pool.size {
+                filled = true;
+                eprintln!("token pool filled with {} tokens", pool.size);
+            }
+        }
+
+        tokio::select! {
+            _ = pool.taken.notified() => {}
+            _ = tokio::time::sleep(pool.next_expiry(now())) => {}
+        }
+    }
+}
+
+/// Take `amount` tokens and format them one per line, followed by an error line when not
+/// all tokens could be taken.
+async fn take_lines(
+    pool: &Pool,
+    amount: usize,
+    timeout: Duration,
+    format: OutputFormat,
+) -> Result<String, DshError> {
+    let mut response = String::new();
+    for _ in 0..amount {
+        match pool.take(timeout).await {
+            Ok(token) => response.push_str(&format_token(&token, format)?),
+            Err(e) => {
+                response.push_str(&format!("error: {}\n", e));
+                break;
+            }
+        }
+    }
+    Ok(response)
+}
+
+/// Serve the line protocol on a connection until it is closed or `quit` is received.
+async fn serve_lines<R, W>(
+    pool: &Pool,
+    reader: R,
+    mut writer: W,
+    timeout: Duration,
+    format: OutputFormat,
+) -> Result<(), DshError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut lines = reader.lines();
+    while let Some(line) = lines.next_line().await? {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let response = match words[..] {
+            [] | ["take"] => take_lines(pool, 1, timeout, format).await?,
+            ["take", amount] => match amount.parse::<usize>() {
+                Ok(amount) => take_lines(pool, amount, timeout, format).await?,
+                Err(_) => format!("error: invalid amount '{}'\n", amount),
+            },
+            ["status"] => format!("available {} of {}\n", pool.available(now()), pool.size),
+            ["quit"] => break,
+            _ => format!(
+                "error: unknown command '{}', use take [N], status or quit\n",
+                line.trim()
+            ),
+        };
+        writer.write_all(response.as_bytes()).await?;
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+/// Serve the line protocol on a Unix socket, only accessible for the current user.
+#[cfg(unix)]
+async fn serve_socket(
+    pool: Arc<Pool>,
+    path: &std::path::Path,
+    timeout: Duration,
+    format: OutputFormat,
+) -> Result<(), DshError> {
+    let listener = super::serve::bind_private_socket(path)?;
+    eprintln!("serving the token pool on {}", path.display());
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            let (reader, writer) = stream.into_split();
+            let reader = BufReader::new(reader);
+            if let Err(e) = serve_lines(&pool, reader, writer, timeout, format).await {
+                warn!("Token pool connection failed: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(not(unix))]
+async fn serve_socket(
+    _pool: Arc<Pool>,
+    _path: &std::path::Path,
+    _timeout: Duration,
+    _format: OutputFormat,
+) -> Result<(), DshError> {
+    Err(DshError::DshCli(
+        "Unix sockets are only supported on unix".to_string(),
+    ))
+}
+
+/// Run the pool subcommand, using the token fetcher options.
+///
+/// The tokens are written in the format of the token fetcher options, which must be raw
+/// or jsonl so every token fits on a single line.
+pub async fn run(cmd: &PoolCommand, opt: &Command) -> Result<(), DshError> {
+    if !matches!(opt.format, OutputFormat::Raw | OutputFormat::Jsonl) {
+        return Err(DshError::DshCli(
+            "The token pool only supports the raw and jsonl formats.".to_string(),
+        ));
+    }
+    if cmd.size == 0 {
+        return Err(DshError::DshCli(
+            "The token pool size must be at least 1.".to_string(),
+        ));
+    }
+    let request_attributes = RequestAttributes {
+        // pooled tokens are handed out once, so they are never taken from the cache
+        use_cache: false,
+        token_amount: cmd.size,
+        ..get_request_attributes(opt)?
+    };
+    if let ClientIds::Fixed(_) = request_attributes.client_ids {
+        return Err(DshError::DshCli(
+            "The token pool needs a client id per token, use --client-id-template instead of --client-id."
+                .to_string(),
+        ));
+    }
+    // more tokens than the size of the pool are handed out over time, so a template needs
+    // a counter even for a pool of a single token
+    let amount = match request_attributes.client_ids {
+        ClientIds::Template { .. } => cmd.size.max(2),
+        _ => cmd.size,
+    };
+    request_attributes.client_ids.validate(amount)?;
+    let pool = Arc::new(Pool::new(cmd.size, request_attributes.cache_margin));
+    let refiller = tokio::spawn(refill(pool.clone(), request_attributes));
+
+    let timeout = Duration::from_secs(cmd.take_timeout);
+    let result = tokio::select! {
+        result = async {
+            match &cmd.socket {
+                Some(path) => serve_socket(pool.clone(), path, timeout, opt.format).await,
+                None => {
+                    let stdin = BufReader::new(tokio::io::stdin());
+                    serve_lines(&pool, stdin, tokio::io::stdout(), timeout, opt.format).await
+                }
+            }
+        } => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+    refiller.abort();
+    if let Some(path) = &cmd.socket {
+        let _ = std::fs::remove_file(path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn pool(tokens: Vec<Token>) -> Pool {
+        let pool = Pool::new(tokens.len(), 60);
+        let mut sink = &pool;
+        for (index, token) in tokens.into_iter().enumerate() {
+            sink.write_token(index + 1, token).unwrap();
+        }
+        pool
+    }
+
+    #[test]
+    fn test_pool_drops_expiring_tokens() {
+        let now = now();
+        let pool = pool(vec![
+            Token::for_test("expiring", now + 30),
+            Token::for_test("fresh", now + 3600),
+        ]);
+        assert_eq!(pool.available(now), 1);
+        let token = pool.try_take(now).unwrap();
+        assert_eq!(token.token_attributes.client_id, "fresh");
+        assert!(pool.try_take(now).is_none());
+    }
+
+    #[test]
+    fn test_client_id_queue() {
+        let mut queue = ClientIdQueue::new(ClientIds::Template {
+            template: "device-{n}".to_string(),
+            start: 1,
+        });
+        let ids = |ids: &[&str]| ClientIds::List(ids.iter().map(|id| id.to_string()).collect());
+
+        let requested = queue.next(3).unwrap();
+        assert_eq!(requested, ids(&["device-1", "device-2", "device-3"]));
+        // the token of device-2 was not received
+        let received = HashSet::from(["device-1".to_string(), "device-3".to_string()]);
+        queue.release(&requested, &received);
+        assert_eq!(queue.next(2).unwrap(), ids(&["device-2", "device-4"]));
+
+        let mut queue = ClientIdQueue::new(ids(&["a", "b", "c"]));
+        assert_eq!(queue.next(2).unwrap(), ids(&["a", "b"]));
+        assert_eq!(queue.next(2).unwrap(), ids(&["c"]));
+        assert!(queue.next(1).is_err());
+
+        let mut queue = ClientIdQueue::new(ClientIds::Random);
+        assert_eq!(queue.next(5).unwrap(), ClientIds::Random);
+    }
+
+    #[test]
+    fn test_next_expiry() {
+        let pool = pool(vec![Token::for_test("device-1", 1000 + 60 + 30)]);
+        assert_eq!(pool.next_expiry(1000), Duration::from_secs(30));
+        assert_eq!(Pool::new(1, 60).next_expiry(1000), MAX_IDLE);
+    }
+
+    #[tokio::test]
+    async fn test_serve_lines() {
+        let exp = now() + 3600;
+        let pool = pool(vec![
+            Token::for_test("device-1", exp),
+            Token::for_test("device-2", exp),
+            Token::for_test("device-3", exp),
+        ]);
+        let input: &[u8] = b"take\nstatus\ntake 2\nnope\nquit\ntake\n";
+        let mut output = Vec::new();
+        serve_lines(&pool, input, &mut output, TIMEOUT, OutputFormat::Jsonl)
+            .await
+            .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].contains("\"client-id\":\"device-1\""));
+        assert_eq!(lines[1], "available 2 of 3");
+        assert!(lines[3].contains("\"client-id\":\"device-3\""));
+        assert!(lines[4].starts_with("error: unknown command 'nope'"));
+    }
+
+    #[tokio::test]
+    async fn test_take_waits_for_tokens() {
+        let pool = Arc::new(Pool::new(1, 60));
+        let taker = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.take(TIMEOUT).await }
+        });
+        tokio::task::yield_now().await;
+        let mut sink = pool.as_ref();
+        sink.write_token(1, Token::for_test("device-1", now() + 3600))
+            .unwrap();
+        let token = taker.await.unwrap().unwrap();
+        assert_eq!(token.token_attributes.client_id, "device-1");
+    }
+
+    #[tokio::test]
+    async fn test_take_fails_after_refill_failure() {
+        let pool = Arc::new(Pool::new(1, 60));
+        let taker = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.take(TIMEOUT).await }
+        });
+        tokio::task::yield_now().await;
+        pool.fail("401 Unauthorized".to_string(), false);
+        let error = taker.await.unwrap().unwrap_err();
+        assert!(error.to_string().contains("401 Unauthorized"));
+
+        // a stopped refiller fails every take once the pool is empty
+        pool.fail("the client ids ran out".to_string(), true);
+        let mut output = Vec::new();
+        serve_lines(
+            &pool,
+            &b"take 2\n"[..],
+            &mut output,
+            TIMEOUT,
+            OutputFormat::Raw,
+        )
+        .await
+        .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("error: "), "{}", output);
+        assert!(output.contains("the client ids ran out"));
+    }
+
+    #[tokio::test]
+    async fn test_take_times_out() {
+        let pool = Pool::new(1, 60);
+        let error = pool.take(Duration::from_millis(10)).await.unwrap_err();
+        assert!(error.to_string().contains("within"));
+    }
+}
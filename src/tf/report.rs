@@ -0,0 +1,298 @@
+use crate::error::DshError;
+use reqwest::StatusCode;
+use std::fmt;
+use std::io::IsTerminal;
+use std::time::{Duration, Instant};
+
+/// Why a single token request failed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Failure {
+    /// The platform responded with a non-OK status code and body.
+    Status(StatusCode, String),
+    /// The platform could not be reached or did not respond in time.
+    Network(String),
+    /// The response did not contain a valid token.
+    InvalidToken(String),
+}
+
+impl Failure {
+    /// Returns whether the platform rejected the credentials.
+    pub fn is_authentication(&self) -> bool {
+        matches!(
+            self,
+            Failure::Status(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN, _)
+        )
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Status(status, body) => write!(f, "status {}: {}", status, body.trim()),
+            Failure::Network(e) => write!(f, "network error: {}", e),
+            Failure::InvalidToken(e) => write!(f, "invalid token: {}", e),
+        }
+    }
+}
+
+/// A failed request on its own, e.g. when a single token is requested to verify a configuration.
+impl From<Failure> for DshError {
+    fn from(failure: Failure) -> Self {
+        match failure {
+            failure if failure.is_authentication() => {
+                DshError::Authentication(format!("{} requesting MQTT token", failure))
+            }
+            Failure::Network(e) => DshError::Network(e),
+            failure => DshError::DshCli(format!("Requesting MQTT token failed: {}", failure)),
+        }
+    }
+}
+
+/// The outcome of a single token request.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RequestOutcome {
+    /// The index of the request within the fetch, starting at 1.
+    pub index: usize,
+    pub retries: u32,
+    pub result: Result<(), Failure>,
+}
+
+impl fmt::Display for RequestOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "request #{}: ", self.index)?;
+        match &self.result {
+            Ok(()) => write!(f, "ok")?,
+            Err(failure) => write!(f, "failed with {}", failure)?,
+        }
+        match self.retries {
+            0 => Ok(()),
+            1 => write!(f, " (1 retry)"),
+            retries => write!(f, " ({} retries)", retries),
+        }
+    }
+}
+
+/// The totals of a fetch, updated with the outcome of every request.
+///
+/// Only totals and the first failures are kept, so the memory use does not grow with the
+/// number of requested tokens.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct FetchReport {
+    pub requested: usize,
+    /// The number of tokens received, including the tokens taken from the cache.
+    pub received: usize,
+    /// The number of tokens taken from the token cache instead of requested.
+    pub cached: usize,
+    pub failed: usize,
+    pub retries: u32,
+    /// The first failure, if any.
+    pub first_failure: Option<Failure>,
+    /// The first failure caused by rejected credentials, if any.
+    pub authentication_failure: Option<Failure>,
+    /// Whether all failures were network failures.
+    pub only_network_failures: bool,
+}
+
+impl FetchReport {
+    /// Create an empty report for a fetch of `requested` tokens.
+    pub fn new(requested: usize) -> Self {
+        FetchReport {
+            requested,
+            only_network_failures: true,
+            ..Default::default()
+        }
+    }
+
+    /// Record the outcome of a request.
+    pub fn record(&mut self, outcome: &RequestOutcome) {
+        self.retries += outcome.retries;
+        match &outcome.result {
+            Ok(()) => self.received += 1,
+            Err(failure) => {
+                self.failed += 1;
+                if self.first_failure.is_none() {
+                    self.first_failure = Some(failure.clone());
+                }
+                if self.authentication_failure.is_none() && failure.is_authentication() {
+                    self.authentication_failure = Some(failure.clone());
+                }
+                if !matches!(failure, Failure::Network(_)) {
+                    self.only_network_failures = false;
+                }
+            }
+        }
+    }
+
+    /// Returns a one line summary of the fetch.
+    pub fn summary(&self) -> String {
+        format!(
+            "received {} of {} tokens ({} from cache), {} failed, {} retries",
+            self.received, self.requested, self.cached, self.failed, self.retries
+        )
+    }
+
+    /// Check that at least `min_success` tokens were received.
+    ///
+    /// When no token was received at all, the error describes the kind of failure, so an
+    /// authentication or network failure gets its own exit code.
+    pub fn check(&self, min_success: usize) -> Result<(), DshError> {
+        if self.received >= min_success {
+            return Ok(());
+        }
+        if self.received == 0 {
+            if let Some(failure) = &self.authentication_failure {
+                return Err(DshError::Authentication(failure.to_string()));
+            }
+            if let Some(failure) = &self.first_failure {
+                if self.only_network_failures {
+                    return Err(DshError::Network(failure.to_string()));
+                }
+            }
+        }
+        Err(DshError::PartialFailure(self.received, min_success))
+    }
+}
+
+/// The minimum time between two updates of the progress line.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Reports the outcome of the requests on stderr while a fetch is running.
+///
+/// Failed requests are always reported, successful requests only when `report_all` is set.
+/// The progress line is only shown when stderr is a terminal.
+#[derive(Debug)]
+pub struct Progress {
+    report_all: bool,
+    show_progress: bool,
+    last_update: Option<Instant>,
+}
+
+impl Progress {
+    /// Create a progress reporter for a fetch of `requested` tokens.
+    pub fn new(requested: usize, report_all: bool) -> Self {
+        Progress {
+            report_all,
+            show_progress: requested > 1 && std::io::stderr().is_terminal(),
+            last_update: None,
+        }
+    }
+
+    /// A progress reporter which only logs failed requests.
+    pub fn hidden() -> Self {
+        Progress {
+            report_all: false,
+            show_progress: false,
+            last_update: None,
+        }
+    }
+
+    /// Report the outcome of a request, together with the totals so far.
+    pub fn outcome(&mut self, outcome: &RequestOutcome, report: &FetchReport) {
+        if !self.show_progress && !self.report_all {
+            if outcome.result.is_err() {
+                error!("{}", outcome);
+            }
+            return;
+        }
+        if self.report_all || outcome.result.is_err() {
+            self.clear();
+            eprintln!("{}", outcome);
+        }
+        let due = self
+            .last_update
+            .is_none_or(|last_update| last_update.elapsed() >= PROGRESS_INTERVAL);
+        if self.show_progress && due {
+            eprint!("\r{}", report.summary());
+            self.last_update = Some(Instant::now());
+        }
+    }
+
+    /// Remove the progress line.
+    pub fn clear(&mut self) {
+        if self.show_progress && self.last_update.is_some() {
+            eprint!("\r\x1b[K");
+            self.last_update = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(results: Vec<Result<(), Failure>>) -> (FetchReport, Vec<RequestOutcome>) {
+        let mut report = FetchReport::new(results.len());
+        let mut outcomes = Vec::new();
+        for (index, result) in results.into_iter().enumerate() {
+            let outcome = RequestOutcome {
+                index: index + 1,
+                retries: index as u32,
+                result,
+            };
+            report.record(&outcome);
+            outcomes.push(outcome);
+        }
+        (report, outcomes)
+    }
+
+    #[test]
+    fn test_failure_into_error() {
+        let unauthorized = Failure::Status(StatusCode::UNAUTHORIZED, "no".to_string());
+        assert!(matches!(
+            DshError::from(unauthorized),
+            DshError::Authentication(_)
+        ));
+        let network = Failure::Network("timeout".to_string());
+        assert!(matches!(DshError::from(network), DshError::Network(_)));
+        let invalid = Failure::InvalidToken("garbage".to_string());
+        assert!(matches!(DshError::from(invalid), DshError::DshCli(_)));
+    }
+
+    #[test]
+    fn test_outcome_display() {
+        let (report, outcomes) = report(vec![
+            Ok(()),
+            Err(Failure::Status(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "try again\n".to_string(),
+            )),
+        ]);
+        assert_eq!(outcomes[0].to_string(), "request #1: ok");
+        assert_eq!(
+            outcomes[1].to_string(),
+            "request #2: failed with status 503 Service Unavailable: try again (1 retry)"
+        );
+        assert_eq!(
+            report.summary(),
+            "received 1 of 2 tokens (0 from cache), 1 failed, 1 retries"
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let (partial, _) = report(vec![Ok(()), Err(Failure::Network("timeout".to_string()))]);
+        assert!(partial.check(1).is_ok());
+        assert!(matches!(
+            partial.check(2),
+            Err(DshError::PartialFailure(1, 2))
+        ));
+
+        let (unauthorized, _) = report(vec![
+            Err(Failure::Network("timeout".to_string())),
+            Err(Failure::Status(StatusCode::UNAUTHORIZED, String::new())),
+        ]);
+        assert!(matches!(
+            unauthorized.check(1),
+            Err(DshError::Authentication(_))
+        ));
+
+        let (unreachable, _) = report(vec![Err(Failure::Network("timeout".to_string()))]);
+        assert!(matches!(unreachable.check(1), Err(DshError::Network(_))));
+
+        let (invalid, _) = report(vec![Err(Failure::InvalidToken("x".to_string()))]);
+        assert!(matches!(
+            invalid.check(1),
+            Err(DshError::PartialFailure(0, 1))
+        ));
+    }
+}
@@ -0,0 +1,78 @@
+use crate::error::DshError;
+use crate::tf::cache::TokenCache;
+use crate::tf::format::shell_quote;
+use crate::tf::{get_request_attributes, get_rest_token, Command};
+use clap::Args;
+
+/// Print the REST token of the tenant, for use with other DSH APIs.
+///
+/// A valid REST token from the token cache is reused, unless `--no-cache` is given, e.g.
+/// `curl -H "$(dsh tf rest-token --header)" https://api.poc.kpn-dsh.com/...`, or with
+/// `eval "$(dsh tf rest-token --env)"`,
+/// `curl -H "Authorization: Bearer $DSH_REST_TOKEN" "$DSH_REST_API_BASE/..."`.
+#[derive(Args, Debug)]
+pub struct RestTokenCommand {
+    /// Print the token as an HTTP authorization header, `Authorization: Bearer <token>`.
+    #[clap(long, conflicts_with = "env")]
+    header: bool,
+
+    /// Print `export` statements of the token and the REST API base URL of the platform,
+    /// `DSH_REST_TOKEN` and `DSH_REST_API_BASE`.
+    #[clap(long)]
+    env: bool,
+}
+
+/// Returns the text to print for `token`, with `rest_api_base` the base URL of the REST APIs.
+fn render(cmd: &RestTokenCommand, token: &str, rest_api_base: &str) -> String {
+    if cmd.header {
+        format!("Authorization: Bearer {}", token.trim())
+    } else if cmd.env {
+        format!(
+            "export DSH_REST_TOKEN={}\nexport DSH_REST_API_BASE={}",
+            shell_quote(token.trim()),
+            shell_quote(rest_api_base)
+        )
+    } else {
+        token.trim().to_string()
+    }
+}
+
+/// Run the rest-token subcommand, using the token fetcher options.
+pub async fn run(cmd: &RestTokenCommand, opt: &Command) -> Result<(), DshError> {
+    let request_attributes = get_request_attributes(opt)?;
+    let deadline = request_attributes.retry.deadline_from_now();
+    let mut cache = if request_attributes.cache_rest_token {
+        Some(TokenCache::load()?)
+    } else {
+        None
+    };
+    let (token, _) = get_rest_token(&request_attributes, cache.as_mut(), deadline).await?;
+    if let Some(cache) = &cache {
+        cache.save()?;
+    }
+    let rest_api_base = request_attributes
+        .endpoints
+        .rest_api_base(&request_attributes.domain);
+    println!("{}", render(cmd, &token, &rest_api_base));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let base = "https://api.poc.kpn-dsh.com";
+        let cmd = |header, env| RestTokenCommand { header, env };
+        assert_eq!(render(&cmd(false, false), "abc\n", base), "abc");
+        assert_eq!(
+            render(&cmd(true, false), "abc\n", base),
+            "Authorization: Bearer abc"
+        );
+        assert_eq!(
+            render(&cmd(false, true), "abc\n", base),
+            "export DSH_REST_TOKEN='abc'\nexport DSH_REST_API_BASE='https://api.poc.kpn-dsh.com'"
+        );
+    }
+}
@@ -0,0 +1,359 @@
+use crate::error::DshError;
+use clap::Args;
+use rand::Rng;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Command-line flags controlling retries and timeouts of the token requests.
+#[derive(Args, Debug, Clone)]
+pub struct RetryArgs {
+    /// The maximum number of retries per request on connection errors, 429 and 5xx responses.
+    #[clap(long, default_value_t = 3)]
+    pub retries: u32,
+
+    /// The initial backoff between retries in milliseconds, doubled on every retry.
+    #[clap(long, default_value_t = 200)]
+    pub retry_backoff_ms: u64,
+
+    /// The timeout of a single HTTP request in seconds.
+    #[clap(long, default_value_t = 30)]
+    pub request_timeout: u64,
+
+    /// The overall deadline in seconds for fetching all tokens, including retries.
+    #[clap(long)]
+    pub deadline: Option<u64>,
+
+    /// The longest Retry-After in seconds to wait for, a request asking to wait longer fails.
+    #[clap(long, default_value_t = 60)]
+    pub max_retry_after: u64,
+}
+
+/// Determines how often and how fast failed requests are retried.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub request_timeout: Duration,
+    pub deadline: Option<Duration>,
+    pub max_retry_after: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(30),
+            deadline: None,
+            max_retry_after: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Default for RetryArgs {
+    fn default() -> Self {
+        RetryArgs::from(&RetryPolicy::default())
+    }
+}
+
+impl From<&RetryArgs> for RetryPolicy {
+    fn from(args: &RetryArgs) -> Self {
+        RetryPolicy {
+            max_retries: args.retries,
+            initial_backoff: Duration::from_millis(args.retry_backoff_ms),
+            request_timeout: Duration::from_secs(args.request_timeout),
+            deadline: args.deadline.map(Duration::from_secs),
+            max_retry_after: Duration::from_secs(args.max_retry_after),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<&RetryPolicy> for RetryArgs {
+    fn from(policy: &RetryPolicy) -> Self {
+        RetryArgs {
+            retries: policy.max_retries,
+            retry_backoff_ms: policy.initial_backoff.as_millis() as u64,
+            request_timeout: policy.request_timeout.as_secs(),
+            deadline: policy.deadline.map(|deadline| deadline.as_secs()),
+            max_retry_after: policy.max_retry_after.as_secs(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the moment the overall deadline expires when starting now.
+    pub fn deadline_from_now(&self) -> Option<Instant> {
+        self.deadline.map(|deadline| Instant::now() + deadline)
+    }
+
+    /// Returns the exponential backoff with jitter before retry number `attempt` (starting at 0).
+    ///
+    /// The delay is randomly chosen between half and the full exponential backoff, so
+    /// concurrent requests do not retry in lockstep.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        let half = exponential / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+}
+
+/// Returns whether a response status is worth retrying.
+fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Returns whether a request error is worth retrying, which are only failures to connect
+/// and timeouts. Other errors, such as an invalid URL, fail the same way when retried.
+fn is_retryable_error(error: &reqwest::Error) -> bool {
+    error.is_connect() || error.is_timeout()
+}
+
+/// Parse a `Retry-After` header value, given in seconds or as HTTP date.
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// The result of sending a request, together with the number of retries it took.
+#[derive(Debug)]
+pub struct Sent {
+    pub result: Result<Response, DshError>,
+    pub retries: u32,
+}
+
+/// Send the request built by `build`, retrying connection errors, 429 and 5xx responses.
+///
+/// The `Retry-After` header of a response is honoured when present, otherwise the backoff of
+/// the policy is used. No retry is started when it would end after the `deadline`, or when
+/// the `Retry-After` is longer than the maximum of the policy.
+/// When all retries are exhausted, the last response is returned so the caller can report it.
+pub async fn send_with_retry<F>(policy: &RetryPolicy, deadline: Option<Instant>, build: F) -> Sent
+where
+    F: Fn() -> RequestBuilder,
+{
+    let mut attempt = 0;
+    let result = loop {
+        let request = build().timeout(policy.request_timeout).send();
+        let result = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline.into(), request).await {
+                Ok(result) => result,
+                Err(_) => {
+                    return Sent {
+                        result: Err(DshError::DeadlineExceeded),
+                        retries: attempt,
+                    }
+                }
+            },
+            None => request.await,
+        };
+
+        let retry_after = match &result {
+            Ok(response) => response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_retry_after),
+            Err(_) => None,
+        };
+        let delay = match &result {
+            Ok(response) if is_retryable_status(response.status()) => {
+                retry_after.unwrap_or_else(|| policy.backoff(attempt))
+            }
+            Err(e) if is_retryable_error(e) => policy.backoff(attempt),
+            _ => break result,
+        };
+
+        let past_deadline = deadline.is_some_and(|deadline| Instant::now() + delay > deadline);
+        let too_long = retry_after.is_some_and(|retry_after| retry_after > policy.max_retry_after);
+        if attempt >= policy.max_retries || past_deadline || too_long {
+            break result;
+        }
+
+        match &result {
+            Ok(response) => warn!(
+                "Request failed with status {}, retrying in {:?} ({}/{})",
+                response.status(),
+                delay,
+                attempt + 1,
+                policy.max_retries
+            ),
+            Err(e) => warn!(
+                "Request failed: {}, retrying in {:?} ({}/{})",
+                e,
+                delay,
+                attempt + 1,
+                policy.max_retries
+            ),
+        }
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    };
+    Sent {
+        result: result.map_err(DshError::from),
+        retries: attempt,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves the given raw HTTP responses in order, one per connection.
+    async fn serve(responses: Vec<&'static str>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buffer = [0; 1024];
+                let _ = socket.read(&mut buffer).await;
+                counter.fetch_add(1, Ordering::SeqCst);
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default();
+        for attempt in 0..10 {
+            let backoff = policy.backoff(attempt);
+            let exponential = (policy.initial_backoff * 2u32.pow(attempt)).min(policy.max_backoff);
+            assert!(backoff >= exponential / 2 && backoff <= exponential);
+        }
+        assert!(policy.backoff(u32::MAX) <= policy.max_backoff);
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("3"), Some(Duration::from_secs(3)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[tokio::test]
+    async fn test_send_with_retry_retries_server_errors() {
+        let (url, requests) = serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n",
+            "HTTP/1.1 429 Too Many Requests\r\nretry-after: 0\r\ncontent-length: 0\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok",
+        ])
+        .await;
+        let client = reqwest::Client::new();
+        let sent = send_with_retry(&policy(), None, || client.get(&url)).await;
+        assert_eq!(sent.result.unwrap().status(), StatusCode::OK);
+        assert_eq!(sent.retries, 2);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_send_with_retry_returns_last_response() {
+        let (url, requests) = serve(vec![
+            "HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\n\r\n",
+            "HTTP/1.1 502 Bad Gateway\r\ncontent-length: 0\r\n\r\n",
+        ])
+        .await;
+        let policy = RetryPolicy {
+            max_retries: 1,
+            ..policy()
+        };
+        let client = reqwest::Client::new();
+        let sent = send_with_retry(&policy, None, || client.get(&url)).await;
+        assert_eq!(sent.result.unwrap().status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(sent.retries, 1);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_send_with_retry_waits_for_retry_after() {
+        let (url, requests) = serve(vec![
+            "HTTP/1.1 429 Too Many Requests\r\nretry-after: 1\r\ncontent-length: 0\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok",
+        ])
+        .await;
+        let policy = RetryPolicy {
+            max_backoff: Duration::from_millis(10),
+            ..policy()
+        };
+        let client = reqwest::Client::new();
+        let start = Instant::now();
+        let sent = send_with_retry(&policy, None, || client.get(&url)).await;
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(sent.result.unwrap().status(), StatusCode::OK);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_send_with_retry_gives_up_on_long_retry_after() {
+        let (url, requests) = serve(vec![
+            "HTTP/1.1 429 Too Many Requests\r\nretry-after: 3600\r\ncontent-length: 0\r\n\r\n",
+            "HTTP/1.1 503 Service Unavailable\r\nretry-after: 30\r\ncontent-length: 0\r\n\r\n",
+        ])
+        .await;
+        let client = reqwest::Client::new();
+        let sent = send_with_retry(&policy(), None, || client.get(&url)).await;
+        assert_eq!(sent.result.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(sent.retries, 0);
+
+        // a Retry-After beyond the deadline is not waited for either
+        let deadline = Some(Instant::now() + Duration::from_secs(5));
+        let sent = send_with_retry(&policy(), deadline, || client.get(&url)).await;
+        assert_eq!(
+            sent.result.unwrap().status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(sent.retries, 0);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_send_with_retry_does_not_retry_invalid_requests() {
+        let client = reqwest::Client::new();
+        let sent = send_with_retry(&policy(), None, || client.get("http://")).await;
+        assert!(sent.result.is_err());
+        assert_eq!(sent.retries, 0);
+    }
+
+    #[tokio::test]
+    async fn test_send_with_retry_does_not_retry_client_errors() {
+        let (url, requests) = serve(vec![
+            "HTTP/1.1 401 Unauthorized\r\ncontent-length: 0\r\n\r\n",
+        ])
+        .await;
+        let client = reqwest::Client::new();
+        let sent = send_with_retry(&policy(), None, || client.get(&url)).await;
+        assert_eq!(sent.result.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(sent.retries, 0);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+}
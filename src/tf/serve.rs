@@ -0,0 +1,524 @@
+use crate::error::DshError;
+use crate::tf::claims::{self, ClaimsArgs};
+use crate::tf::format::TokenOutput;
+use crate::tf::{get_request_attributes, get_tokens, Command, RequestAttributes};
+use clap::Args;
+use hyper::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+    CACHE_CONTROL, CONTENT_TYPE, HOST, ORIGIN,
+};
+use hyper::http::uri::Authority;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde_json::json;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Serve tokens to local applications over HTTP, so they never see the API key.
+///
+/// `GET /token` returns a fresh token with its decoded attributes, so every caller gets its
+/// own client-id. With `--cache`, a cached token of the same claims is returned until it is
+/// about to expire, shared by the callers. The `fresh=true|false` query parameter overrides
+/// this per request. The claims can be given with the `claims` (JSON), `subscribe` and
+/// `publish` query parameters, e.g. `/token?subscribe=stream:topic/%23`, otherwise the
+/// claims of the token fetcher options are used.
+///
+/// Requests with a `Host` header other than localhost or the listen address, and requests
+/// from browser pages of origins that are not allowed, are rejected.
+#[derive(Args, Debug)]
+pub struct ServeCommand {
+    /// The address to listen on.
+    #[clap(long, default_value = "127.0.0.1:7879")]
+    listen: SocketAddr,
+
+    /// Listen on this Unix socket instead of a TCP address.
+    #[clap(long, conflicts_with = "listen")]
+    unix_socket: Option<PathBuf>,
+
+    /// Allow browser pages from this origin to fetch tokens, e.g. 'http://localhost:3000'.
+    /// Can be repeated.
+    #[clap(long)]
+    allow_origin: Vec<String>,
+
+    /// Return a cached token of the same claims while it is valid, instead of a fresh token
+    /// with its own client-id for every request.
+    #[clap(long)]
+    cache: bool,
+}
+
+/// The state shared by all requests.
+struct State {
+    /// The request attributes from the token fetcher options.
+    base: RequestAttributes,
+    allowed_origins: Vec<String>,
+    /// The address the server listens on, `None` for a Unix socket.
+    listen: Option<SocketAddr>,
+}
+
+impl State {
+    /// Returns why a request is forbidden, if it is.
+    ///
+    /// Checking the `Host` header prevents DNS rebinding, where a web page on another domain
+    /// that resolves to the local address reads the tokens. Browser pages can only fetch tokens
+    /// when their origin is allowed.
+    fn forbidden(&self, request: &Request<Body>) -> Option<String> {
+        if let Some(listen) = self.listen {
+            let host = request
+                .headers()
+                .get(HOST)
+                .and_then(|host| host.to_str().ok());
+            match host {
+                Some(host) if host_allowed(host, listen) => {}
+                Some(host) => return Some(format!("host '{}' is not allowed", host)),
+                None => return Some("the host header is missing".to_string()),
+            }
+        }
+        if let Some(origin) = request.headers().get(ORIGIN) {
+            let allowed = self
+                .allowed_origins
+                .iter()
+                .any(|allowed| allowed.as_bytes() == origin.as_bytes());
+            if !allowed {
+                return Some(format!(
+                    "origin '{}' is not allowed, see --allow-origin",
+                    String::from_utf8_lossy(origin.as_bytes())
+                ));
+            }
+        }
+        None
+    }
+}
+
+/// Returns whether a `Host` header addresses the server listening on `listen`.
+///
+/// Allowed are `localhost`, loopback addresses and the listen address, or any IP address
+/// when listening on all interfaces, with the port of the server if a port is given.
+fn host_allowed(host: &str, listen: SocketAddr) -> bool {
+    let authority = match host.parse::<Authority>() {
+        Ok(authority) => authority,
+        Err(_) => return false,
+    };
+    if authority
+        .port_u16()
+        .is_some_and(|port| port != listen.port())
+    {
+        return false;
+    }
+    let name = authority
+        .host()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    match name.parse::<IpAddr>() {
+        Ok(ip) => ip.is_loopback() || ip == listen.ip() || listen.ip().is_unspecified(),
+        Err(_) => name.eq_ignore_ascii_case("localhost"),
+    }
+}
+
+/// Returns the HTTP status for an error that occurred while fetching a token.
+fn error_status(error: &DshError) -> StatusCode {
+    match error {
+        DshError::InvalidClaims(_) => StatusCode::BAD_REQUEST,
+        DshError::Network(_) | DshError::DeadlineExceeded | DshError::Request(_) => {
+            StatusCode::GATEWAY_TIMEOUT
+        }
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
+
+/// Build the request attributes for a token request from its query string.
+fn request_attributes(
+    base: &RequestAttributes,
+    query: &str,
+) -> Result<RequestAttributes, DshError> {
+    let mut json = None;
+    let mut args = ClaimsArgs::default();
+    let mut use_cache = base.use_cache;
+    for (name, value) in form_urlencoded::parse(query.as_bytes()) {
+        match name.as_ref() {
+            "claims" => json = Some(value.into_owned()),
+            "subscribe" => args.subscribe.push(value.into_owned()),
+            "publish" => args.publish.push(value.into_owned()),
+            "fresh" => {
+                use_cache = match value.as_ref() {
+                    "true" => false,
+                    "false" => true,
+                    value => {
+                        return Err(DshError::InvalidClaims(format!(
+                            "invalid value '{}' of fresh, use true or false",
+                            value
+                        )))
+                    }
+                }
+            }
+            _ => {
+                return Err(DshError::InvalidClaims(format!(
+                    "unknown query parameter '{}'",
+                    name
+                )))
+            }
+        }
+    }
+    let claims = match claims::resolve_claims(json.as_deref(), &args)? {
+        Some(claims) => Some(claims),
+        None => base.claims.clone(),
+    };
+    Ok(RequestAttributes {
+        claims,
+        token_amount: 1,
+        use_cache,
+        ..base.clone()
+    })
+}
+
+/// Fetch a token for a `GET /token` request and return it with its decoded attributes.
+async fn token(state: &State, query: &str) -> Result<String, DshError> {
+    let request_attributes = request_attributes(&state.base, query)?;
+    let tokens = get_tokens(&request_attributes).await?;
+    let token = tokens
+        .first()
+        .ok_or_else(|| DshError::DshCli("No token received".to_string()))?;
+    Ok(serde_json::to_string(&TokenOutput::from(token))?)
+}
+
+/// Handle a single HTTP request.
+async fn handle(request: Request<Body>, state: Arc<State>) -> Result<Response<Body>, Infallible> {
+    let forbidden = state.forbidden(&request);
+    let (status, body) = match (request.method(), request.uri().path()) {
+        _ if forbidden.is_some() => (
+            StatusCode::FORBIDDEN,
+            json!({ "error": forbidden }).to_string(),
+        ),
+        (&Method::GET, "/token") => {
+            match token(&state, request.uri().query().unwrap_or_default()).await {
+                Ok(body) => (StatusCode::OK, body),
+                Err(e) => {
+                    warn!("Serving token failed: {}", e);
+                    (
+                        error_status(&e),
+                        json!({ "error": e.to_string() }).to_string(),
+                    )
+                }
+            }
+        }
+        // the preflight of a browser page, the CORS headers are added below
+        (&Method::OPTIONS, "/token") => (StatusCode::NO_CONTENT, String::new()),
+        (_, "/token") => (
+            StatusCode::METHOD_NOT_ALLOWED,
+            json!({ "error": "only GET is supported" }).to_string(),
+        ),
+        (_, path) => (
+            StatusCode::NOT_FOUND,
+            json!({ "error": format!("{} not found, use /token", path) }).to_string(),
+        ),
+    };
+    info!("{} {} {}", request.method(), request.uri().path(), status);
+
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    if let Some(origin) = request
+        .headers()
+        .get(ORIGIN)
+        .filter(|_| status != StatusCode::FORBIDDEN)
+    {
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        if request.method() == Method::OPTIONS {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_METHODS,
+                HeaderValue::from_static("GET, OPTIONS"),
+            );
+            if let Some(requested) = request.headers().get(ACCESS_CONTROL_REQUEST_HEADERS) {
+                headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+            }
+            headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("600"));
+        }
+    }
+    Ok(response)
+}
+
+/// Resolves when the server should shut down.
+async fn shutdown() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Bind a Unix socket at `path` which is only accessible for the current user.
+///
+/// The socket is created in a new directory which only the current user can access, and
+/// moved into place once its permissions are restricted, so no other user can connect in
+/// between.
+#[cfg(unix)]
+pub fn bind_private_socket(path: &std::path::Path) -> Result<tokio::net::UnixListener, DshError> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+    // remove the socket of a previous run, but never a regular file
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(DshError::DshCli(format!(
+                "{} already exists and is not a socket",
+                path.display()
+            )));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => std::path::Path::new("."),
+    };
+    let private_dir = parent.join(format!(".dsh-serve-{:016x}", rand::random::<u64>()));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)?;
+    let private_path = private_dir.join("socket");
+    let bound = tokio::net::UnixListener::bind(&private_path).and_then(|listener| {
+        std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&private_path, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&private_path);
+    let _ = std::fs::remove_dir(&private_dir);
+    Ok(bound?)
+}
+
+/// Serve on a Unix socket, only accessible for the current user.
+#[cfg(unix)]
+async fn serve_unix(path: &std::path::Path, state: Arc<State>) -> Result<(), DshError> {
+    let listener = bind_private_socket(path)?;
+    eprintln!("serving tokens on {}, GET /token", path.display());
+
+    let incoming = hyper::server::accept::poll_fn(move |cx| {
+        listener
+            .poll_accept(cx)
+            .map(|result| Some(result.map(|(stream, _)| stream)))
+    });
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |request| handle(request, state.clone()))) }
+    });
+    let result = Server::builder(incoming)
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown())
+        .await;
+    let _ = std::fs::remove_file(path);
+    result.map_err(|e| DshError::DshCli(format!("Server error: {}", e)))
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(_path: &std::path::Path, _state: Arc<State>) -> Result<(), DshError> {
+    Err(DshError::DshCli(
+        "Unix sockets are only supported on unix".to_string(),
+    ))
+}
+
+/// Run the serve subcommand until interrupted, using the token fetcher options.
+pub async fn run(cmd: &ServeCommand, opt: &Command) -> Result<(), DshError> {
+    // by default every caller gets a fresh token, a cached token shares its client-id
+    let base = RequestAttributes {
+        use_cache: cmd.cache,
+        ..get_request_attributes(opt)?
+    };
+    let state = Arc::new(State {
+        base,
+        allowed_origins: cmd.allow_origin.clone(),
+        listen: cmd.unix_socket.is_none().then_some(cmd.listen),
+    });
+
+    if let Some(path) = &cmd.unix_socket {
+        return serve_unix(path, state).await;
+    }
+
+    if !cmd.listen.ip().is_loopback() {
+        warn!(
+            "Listening on {}, anyone who can reach it can fetch tokens",
+            cmd.listen
+        );
+    }
+    let server = Server::try_bind(&cmd.listen)
+        .map_err(|e| DshError::DshCli(format!("Could not listen on {}: {}", cmd.listen, e)))?;
+    eprintln!("serving tokens on http://{}/token", cmd.listen);
+
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |request| handle(request, state.clone()))) }
+    });
+    server
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown())
+        .await
+        .map_err(|e| DshError::DshCli(format!("Server error: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_dir;
+    use crate::tf::retry::RetryPolicy;
+    use crate::tf::token::Action;
+
+    fn state() -> Arc<State> {
+        Arc::new(State {
+            base: RequestAttributes {
+                tenant: "tenant".to_string(),
+                api_key: "api-key".to_string(),
+                domain: "example.com".to_string(),
+                claims: None,
+                client_ids: Default::default(),
+                token_amount: 5,
+                concurrent_connections: 1,
+                output: None,
+                endpoints: Default::default(),
+                use_cache: false,
+                cache_rest_token: true,
+                cache_margin: 60,
+                retry: RetryPolicy::default(),
+            },
+            allowed_origins: vec!["http://localhost:3000".to_string()],
+            listen: Some("127.0.0.1:7879".parse().unwrap()),
+        })
+    }
+
+    async fn get(uri: &str, origin: Option<&str>) -> Response<Body> {
+        let mut request = Request::builder().uri(uri).header(HOST, "localhost:7879");
+        if let Some(origin) = origin {
+            request = request.header(ORIGIN, origin);
+        }
+        handle(request.body(Body::empty()).unwrap(), state())
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_request_attributes() {
+        let base = state().base.clone();
+        let ra =
+            request_attributes(&base, "subscribe=stream%3Atopic%2F%23&publish=stream:out").unwrap();
+        let claims = ra.claims.unwrap();
+        assert_eq!(ra.token_amount, 1);
+        assert_eq!(claims.len(), 2);
+        assert_eq!(claims[0].action(), Action::Subscribe);
+        assert_eq!(claims[0].resource().topic(), "topic/#");
+
+        assert_eq!(request_attributes(&base, "").unwrap().claims, None);
+        assert!(request_attributes(&base, "topic=x").is_err());
+
+        assert!(!request_attributes(&base, "").unwrap().use_cache);
+        assert!(request_attributes(&base, "fresh=false").unwrap().use_cache);
+        let cached = RequestAttributes {
+            use_cache: true,
+            ..base.clone()
+        };
+        assert!(!request_attributes(&cached, "fresh=true").unwrap().use_cache);
+        assert!(request_attributes(&cached, "").unwrap().use_cache);
+        assert!(request_attributes(&base, "fresh=maybe").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handle_rejects_invalid_requests() {
+        let response = get("/token?claims=nope", None).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = get("/other", None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/token")
+            .header(HOST, "127.0.0.1:7879")
+            .body(Body::empty())
+            .unwrap();
+        let response = handle(request, state()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_handle_allows_configured_origins() {
+        let response = get("/other", Some("http://localhost:3000")).await;
+        assert_eq!(
+            response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "http://localhost:3000"
+        );
+
+        let response = get("/token", Some("http://evil.example.com")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[tokio::test]
+    async fn test_handle_preflight() {
+        let request = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/token")
+            .header(HOST, "localhost:7879")
+            .header(ORIGIN, "http://localhost:3000")
+            .header(ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+            .body(Body::empty())
+            .unwrap();
+        let response = handle(request, state()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "http://localhost:3000"
+        );
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_METHODS], "GET, OPTIONS");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_HEADERS], "content-type");
+    }
+
+    #[tokio::test]
+    async fn test_handle_rejects_other_hosts() {
+        for host in [Some("evil.example.com:7879"), Some("localhost:80"), None] {
+            let mut request = Request::builder().uri("/token");
+            if let Some(host) = host {
+                request = request.header(HOST, host);
+            }
+            let response = handle(request.body(Body::empty()).unwrap(), state())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{:?}", host);
+        }
+    }
+
+    #[test]
+    fn test_host_allowed() {
+        let listen: SocketAddr = "127.0.0.1:7879".parse().unwrap();
+        assert!(host_allowed("localhost:7879", listen));
+        assert!(host_allowed("LOCALHOST", listen));
+        assert!(host_allowed("127.0.0.1:7879", listen));
+        assert!(host_allowed("[::1]:7879", listen));
+        assert!(!host_allowed("192.168.1.2:7879", listen));
+        assert!(!host_allowed("rebind.example.com:7879", listen));
+        assert!(!host_allowed("localhost:7880", listen));
+
+        let all: SocketAddr = "0.0.0.0:7879".parse().unwrap();
+        assert!(host_allowed("192.168.1.2:7879", all));
+        assert!(!host_allowed("rebind.example.com:7879", all));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_bind_private_socket() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = test_dir("socket");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dsh.sock");
+
+        let listener = bind_private_socket(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+        drop(listener);
+
+        // a socket of a previous run is replaced, a regular file is not
+        bind_private_socket(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "data").unwrap();
+        assert!(bind_private_socket(&path).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,6 +1,10 @@
 use crate::error::DshError;
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 
+pub mod jwt;
+pub mod topic;
+
 /// Represents an authentication token and its attributes.
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
 pub struct Token {
@@ -9,25 +13,63 @@ pub struct Token {
 }
 
 /// Contains the attributes of a token, extracted and deserialized from the raw token.
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+///
+/// Unknown fields are ignored and missing fields other than `exp` get their default value,
+/// so changes in the token format of the platform do not break the parsing of tokens. A
+/// token without an expiry is rejected, as it can not be known when to refresh it.
+#[derive(Serialize, Deserialize, Debug, Default, Eq, PartialEq, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct TokenAttributes {
+    #[serde(default)]
     gen: i32,
+    #[serde(default)]
     pub endpoint: String,
+    #[serde(default)]
     iss: String,
+    #[serde(default)]
     pub claims: Vec<Claims>,
-    exp: i32,
+    exp: i64,
+    #[serde(default)]
     pub ports: Ports,
+    #[serde(default)]
     pub client_id: String,
-    iat: i32,
+    #[serde(default)]
+    iat: i64,
+    #[serde(default)]
     pub tenant_id: String,
 }
 
 /// Represents the claims contained within a token, defining resources and actions.
+///
+/// The same model is used to request claims from the platform and to read them back
+/// from a token, so it (de)serializes exactly as the DSH token endpoint expects.
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
 pub struct Claims {
     resource: Resource,
-    action: String,
+    action: Action,
+}
+
+/// The action a claim allows on its resource.
+///
+/// Actions this version does not know are read as `Unknown`, so a token with a new kind of
+/// claim can still be used, but such a claim never allows anything.
+#[derive(Serialize, Deserialize, ValueEnum, Debug, Eq, PartialEq, Clone, Copy, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Subscribe,
+    Publish,
+    #[serde(other)]
+    #[value(skip)]
+    Unknown,
+}
+
+/// The type of resource a claim applies to, `Unknown` for types this version does not know.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ResourceType {
+    Topic,
+    #[serde(other)]
+    Unknown,
 }
 
 /// Represents a resource in the claims of a token, defining stream, prefix, topic, and optional type.
@@ -36,11 +78,13 @@ pub struct Resource {
     stream: String,
     prefix: String,
     topic: String,
-    type_: Option<String>,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    type_: Option<ResourceType>,
 }
 
 /// Contains the ports information for MQTT over SSL/TLS and WebSockets.
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Eq, PartialEq, Clone)]
+#[serde(default)]
 pub struct Ports {
     pub mqtts: Vec<u16>,
     pub mqttwss: Vec<u16>,
@@ -55,29 +99,125 @@ impl Token {
     ///
     /// # Returns
     /// - `Result<Token, DshError>`: A `Token` instance on successful parsing and deserialization, or a `DshError` on failure.
+    ///
+    /// # Errors
+    /// - `DshError::MalformedToken` if the token does not consist of three segments or contains invalid JSON.
+    /// - `DshError::InvalidBase64Segment` if a segment is not URL-safe base64 encoded.
+    /// - `DshError::UnsupportedAlgorithm` if the token is not signed with a supported algorithm.
     pub fn new(raw_token: String) -> Result<Token, DshError> {
-        use base64::{alphabet, engine, read};
-        use std::io::Read;
-
-        // Split token and get the [1] part
-        let split_token = raw_token.split('.').collect::<Vec<&str>>();
-
-        // Create an instance of the GeneralPurpose engine with the STANDARD alphabet
-        let engine =
-            engine::GeneralPurpose::new(&alphabet::STANDARD, engine::general_purpose::NO_PAD);
-
-        // Decode the token using DecoderReader
-        let mut decoder = read::DecoderReader::new(split_token[1].as_bytes(), &engine);
-        let mut decoded_token = Vec::new();
-        decoder.read_to_end(&mut decoded_token)?;
-
-        let token_attributes: TokenAttributes = serde_json::from_slice(&decoded_token)?;
+        let (_, token_attributes) = jwt::decode::<TokenAttributes>(raw_token.trim())?;
         let token = Token {
             raw_token,
             token_attributes,
         };
         Ok(token)
     }
+
+    /// Decodes the header of the token, containing for example the signing algorithm.
+    pub fn header(&self) -> Result<jwt::Header, DshError> {
+        let segments = jwt::split(self.raw_token.trim())?;
+        jwt::parse_header(segments.header)
+    }
+}
+
+impl Claims {
+    /// Creates a claim allowing `action` on `resource`.
+    pub fn new(action: Action, resource: Resource) -> Claims {
+        Claims { resource, action }
+    }
+
+    /// Returns the action this claim allows.
+    pub fn action(&self) -> Action {
+        self.action
+    }
+
+    /// Returns the resource this claim applies to.
+    pub fn resource(&self) -> &Resource {
+        &self.resource
+    }
+}
+
+impl Resource {
+    /// Creates a topic resource within `stream`, where `topic` may contain MQTT wildcards.
+    pub fn new(stream: &str, prefix: &str, topic: &str) -> Resource {
+        Resource {
+            stream: stream.to_string(),
+            prefix: prefix.to_string(),
+            topic: topic.to_string(),
+            type_: Some(ResourceType::Topic),
+        }
+    }
+
+    /// Returns the name of the stream.
+    pub fn stream(&self) -> &str {
+        &self.stream
+    }
+
+    /// Returns the topic prefix, e.g. `/tt`.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Returns the topic filter within the stream.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Returns the type of the resource, if present.
+    pub fn resource_type(&self) -> Option<ResourceType> {
+        self.type_
+    }
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Subscribe => write!(f, "subscribe"),
+            Action::Publish => write!(f, "publish"),
+            Action::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+impl std::fmt::Display for ResourceType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResourceType::Topic => write!(f, "topic"),
+            ResourceType::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+impl TokenAttributes {
+    /// Returns the expiry time of the token as seconds since the Unix epoch.
+    pub fn exp(&self) -> i64 {
+        self.exp
+    }
+
+    /// Returns the issued-at time of the token as seconds since the Unix epoch.
+    pub fn iat(&self) -> i64 {
+        self.iat
+    }
+}
+
+#[cfg(test)]
+impl Token {
+    /// Creates an unsigned token with the given client-id and expiry, for use in tests.
+    pub fn for_test(client_id: &str, exp: i64) -> Token {
+        let payload = serde_json::json!({
+            "gen": 1,
+            "endpoint": "mqtt.example.com",
+            "iss": "0",
+            "claims": [],
+            "exp": exp,
+            "ports": { "mqtts": [8883], "mqttwss": [443, 8443] },
+            "client-id": client_id,
+            "iat": exp - 3600,
+            "tenant-id": "tenant",
+        });
+        let raw_token = jwt::encode(r#"{"typ":"JWT","alg":"HS256"}"#, &payload.to_string());
+        Token::new(raw_token).unwrap()
+    }
 }
 
 // test
@@ -103,9 +243,9 @@ mod test {
                         stream: "ajucpublic".to_string(),
                         prefix: "/tt".to_string(),
                         topic: "ajuc/#".to_string(),
-                        type_: None,
+                        type_: Some(ResourceType::Topic),
                     },
-                    action: "subscribe".to_string(),
+                    action: Action::Subscribe,
                 }],
                 exp: 1666284104,
                 ports: Ports {
@@ -121,4 +261,84 @@ mod test {
         // assert equal
         assert_eq!(validation_token, token);
     }
+
+    /// Tests that unknown fields are ignored and missing fields get their default value.
+    #[test]
+    fn test_token_tolerates_extra_and_missing_fields() {
+        let payload = r#"{"endpoint":"mqtt.example.com","exp":1666284104,"client-id":"c","tenant-id":"t","new-field":true}"#;
+        let raw_token = jwt::encode(r#"{"alg":"HS256"}"#, payload);
+        let token = Token::new(raw_token).unwrap();
+        assert_eq!(token.token_attributes.endpoint, "mqtt.example.com");
+        assert_eq!(token.token_attributes.exp(), 1666284104);
+        assert!(token.token_attributes.claims.is_empty());
+        assert!(token.token_attributes.ports.mqtts.is_empty());
+    }
+
+    /// Tests that malformed tokens result in typed errors instead of a panic.
+    #[test]
+    fn test_malformed_tokens() {
+        assert!(matches!(
+            Token::new("not-a-token".to_string()),
+            Err(DshError::MalformedToken(_))
+        ));
+        assert!(matches!(
+            Token::new("eyJhbGciOiJIUzI1NiJ9.@@@.sig".to_string()),
+            Err(DshError::InvalidBase64Segment("payload", _))
+        ));
+        assert!(matches!(
+            Token::new(jwt::encode(r#"{"alg":"none"}"#, "{}")),
+            Err(DshError::UnsupportedAlgorithm(_))
+        ));
+    }
+
+    /// Tests that claims serialize exactly as the token endpoint expects and round-trip.
+    #[test]
+    fn test_claims_round_trip() {
+        let json = r#"[{"resource":{"stream":"ajucpublic","prefix":"/tt","topic":"ajuc/#","type":"topic"},"action":"subscribe"},{"resource":{"stream":"ajucpublic","prefix":"/tt","topic":"ajuc/out"},"action":"publish"}]"#;
+        let claims: Vec<Claims> = serde_json::from_str(json).unwrap();
+
+        assert_eq!(claims[0].action(), Action::Subscribe);
+        assert_eq!(claims[0].resource().stream(), "ajucpublic");
+        assert_eq!(claims[0].resource().prefix(), "/tt");
+        assert_eq!(claims[0].resource().topic(), "ajuc/#");
+        assert_eq!(
+            claims[0].resource().resource_type(),
+            Some(ResourceType::Topic)
+        );
+        assert_eq!(claims[1].action(), Action::Publish);
+        assert_eq!(claims[1].resource().resource_type(), None);
+        assert_eq!(serde_json::to_string(&claims).unwrap(), json);
+    }
+
+    /// Tests that unknown actions and resource types are read as unknown.
+    #[test]
+    fn test_claims_unknown_action() {
+        let json = r#"{"resource":{"stream":"s","prefix":"/tt","topic":"t","type":"group"},"action":"delete"}"#;
+        let claim = serde_json::from_str::<Claims>(json).unwrap();
+        assert_eq!(claim.action(), Action::Unknown);
+        assert_eq!(
+            claim.resource().resource_type(),
+            Some(ResourceType::Unknown)
+        );
+    }
+
+    /// Tests that a token without an expiry is rejected.
+    #[test]
+    fn test_token_requires_exp() {
+        let payload = r#"{"endpoint":"mqtt.example.com","client-id":"c","tenant-id":"t"}"#;
+        let raw_token = jwt::encode(r#"{"alg":"HS256"}"#, payload);
+        assert!(matches!(
+            Token::new(raw_token),
+            Err(DshError::MalformedToken(_))
+        ));
+    }
+
+    /// Tests that timestamps after 2038 are parsed.
+    #[test]
+    fn test_token_after_2038() {
+        let exp = 4_102_444_800; // 2100-01-01
+        let token = Token::for_test("c", exp);
+        assert_eq!(token.token_attributes.exp(), exp);
+        assert_eq!(token.token_attributes.iat(), exp - 3600);
+    }
 }
@@ -0,0 +1,179 @@
+use crate::error::DshError;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::{alphabet, Engine};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// The signing algorithms a token can be signed with.
+pub const SUPPORTED_ALGORITHMS: [&str; 12] = [
+    "HS256", "HS384", "HS512", "RS256", "RS384", "RS512", "ES256", "ES384", "ES512", "PS256",
+    "PS384", "PS512",
+];
+
+/// URL-safe base64 engine which accepts segments with and without padding.
+const ENGINE: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// The decoded header of a JWT.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+pub struct Header {
+    pub alg: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub typ: Option<String>,
+}
+
+/// The three segments of a JWT: header, payload and signature.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Segments<'a> {
+    pub header: &'a str,
+    pub payload: &'a str,
+    pub signature: &'a str,
+}
+
+/// Split a raw JWT into its three segments.
+pub fn split(raw_token: &str) -> Result<Segments<'_>, DshError> {
+    let segments: Vec<&str> = raw_token.split('.').collect();
+    match segments[..] {
+        [header, payload, signature] if !header.is_empty() && !payload.is_empty() => Ok(Segments {
+            header,
+            payload,
+            signature,
+        }),
+        [_, _, _] => Err(DshError::MalformedToken(
+            "token contains an empty header or payload segment".to_string(),
+        )),
+        _ => Err(DshError::MalformedToken(format!(
+            "expected 3 segments separated by '.', found {}",
+            segments.len()
+        ))),
+    }
+}
+
+/// Decode a URL-safe base64 encoded segment.
+pub fn decode_segment(name: &'static str, segment: &str) -> Result<Vec<u8>, DshError> {
+    ENGINE
+        .decode(segment)
+        .map_err(|e| DshError::InvalidBase64Segment(name, e))
+}
+
+/// Decode and deserialize the JSON contained in a segment.
+fn parse_segment<T: DeserializeOwned>(name: &'static str, segment: &str) -> Result<T, DshError> {
+    let decoded = decode_segment(name, segment)?;
+    serde_json::from_slice(&decoded)
+        .map_err(|e| DshError::MalformedToken(format!("invalid {} segment: {}", name, e)))
+}
+
+/// Parse the header segment and check that the signing algorithm is supported.
+pub fn parse_header(segment: &str) -> Result<Header, DshError> {
+    let header: Header = parse_segment("header", segment)?;
+    if !SUPPORTED_ALGORITHMS.contains(&header.alg.as_str()) {
+        return Err(DshError::UnsupportedAlgorithm(header.alg));
+    }
+    Ok(header)
+}
+
+/// Validate a raw JWT and decode its header and payload.
+///
+/// The signature is not verified, it can only be verified by the platform.
+pub fn decode<T: DeserializeOwned>(raw_token: &str) -> Result<(Header, T), DshError> {
+    let segments = split(raw_token)?;
+    let header = parse_header(segments.header)?;
+    let payload = parse_segment("payload", segments.payload)?;
+    Ok((header, payload))
+}
+
+/// Encode the segments of an unsigned JWT, for use in tests.
+#[cfg(test)]
+pub fn encode(header: &str, payload: &str) -> String {
+    let engine = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    format!(
+        "{}.{}.signature",
+        engine.encode(header),
+        engine.encode(payload)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose;
+    use proptest::prelude::*;
+
+    const HEADER: &str = r#"{"typ":"JWT","alg":"HS256"}"#;
+
+    #[test]
+    fn test_split_requires_three_segments() {
+        assert!(matches!(split("a.b"), Err(DshError::MalformedToken(_))));
+        assert!(matches!(split("a.b.c.d"), Err(DshError::MalformedToken(_))));
+        assert!(matches!(split(".b.c"), Err(DshError::MalformedToken(_))));
+        assert_eq!(
+            split("a.b.").unwrap(),
+            Segments {
+                header: "a",
+                payload: "b",
+                signature: ""
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_segment_padding_and_alphabet() {
+        // "??>" encodes to "Pz8-" in the URL-safe alphabet
+        assert_eq!(decode_segment("payload", "Pz8-").unwrap(), b"??>");
+        assert_eq!(decode_segment("payload", "YQ").unwrap(), b"a");
+        assert_eq!(decode_segment("payload", "YQ==").unwrap(), b"a");
+        assert!(matches!(
+            decode_segment("payload", "Pz8+"),
+            Err(DshError::InvalidBase64Segment("payload", _))
+        ));
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let engine = general_purpose::URL_SAFE_NO_PAD;
+        let header = parse_header(&engine.encode(r#"{"alg":"RS256","kid":"x"}"#)).unwrap();
+        assert_eq!(header.alg, "RS256");
+        assert_eq!(header.typ, None);
+
+        let result = parse_header(&engine.encode(r#"{"alg":"none","typ":"JWT"}"#));
+        assert!(matches!(result, Err(DshError::UnsupportedAlgorithm(alg)) if alg == "none"));
+
+        let result = parse_header(&engine.encode(r#"{"typ":"JWT"}"#));
+        assert!(matches!(result, Err(DshError::MalformedToken(_))));
+    }
+
+    proptest! {
+        #[test]
+        fn test_decode_never_panics(raw_token in "\\PC*") {
+            let _ = decode::<serde_json::Value>(&raw_token);
+        }
+
+        #[test]
+        fn test_decode_never_panics_on_segments(raw_token in "[A-Za-z0-9_=+/-]{0,40}(\\.[A-Za-z0-9_=+/-]{0,40}){0,4}") {
+            let _ = decode::<serde_json::Value>(&raw_token);
+        }
+
+        #[test]
+        fn test_decode_round_trip(value in "\\PC*", number in any::<i64>()) {
+            let payload = serde_json::json!({ "value": value, "number": number });
+            let raw_token = encode(HEADER, &payload.to_string());
+            let (header, decoded): (Header, serde_json::Value) = decode(&raw_token).unwrap();
+            prop_assert_eq!(header.alg, "HS256");
+            prop_assert_eq!(decoded, payload);
+        }
+
+        #[test]
+        fn test_decode_accepts_padding(value in "\\PC*") {
+            let payload = serde_json::json!({ "value": value }).to_string();
+            let raw_token = format!(
+                "{}.{}.signature",
+                general_purpose::URL_SAFE.encode(HEADER),
+                general_purpose::URL_SAFE.encode(&payload)
+            );
+            let (_, decoded): (Header, serde_json::Value) = decode(&raw_token).unwrap();
+            prop_assert_eq!(decoded.to_string(), payload);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use crate::tf::token::{Action, Claims, ResourceType};
+
+/// The prefix of the topics on the DSH MQTT broker.
+pub const DEFAULT_PREFIX: &str = "/tt";
+
+/// The outcome of evaluating an action on a topic against the claims of a token.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Evaluation {
+    /// The index of the first claim that allows the action, if any.
+    pub matched: Option<usize>,
+    /// For every claim that does not allow the action, its index and the reason why.
+    pub mismatches: Vec<(usize, String)>,
+}
+
+impl Evaluation {
+    /// Returns whether the action is allowed.
+    pub fn is_allowed(&self) -> bool {
+        self.matched.is_some()
+    }
+}
+
+/// Prefix a topic as the MQTT client does, e.g. `stream/topic` becomes `/tt/stream/topic`.
+pub fn with_prefix(topic: &str) -> String {
+    if topic.starts_with('/') {
+        format!("{}{}", DEFAULT_PREFIX, topic)
+    } else {
+        format!("{}/{}", DEFAULT_PREFIX, topic)
+    }
+}
+
+/// Validate an MQTT topic filter, where `+` and `#` must occupy a whole level and
+/// `#` must be the last level.
+pub fn validate_topic_filter(filter: &str) -> Result<(), String> {
+    if filter.is_empty() {
+        return Err("the topic is empty".to_string());
+    }
+    let levels: Vec<&str> = filter.split('/').collect();
+    for (index, level) in levels.iter().enumerate() {
+        if level.contains(['+', '#']) && level.len() > 1 {
+            return Err(format!(
+                "wildcard in level '{}' must occupy the whole level",
+                level
+            ));
+        }
+        if *level == "#" && index != levels.len() - 1 {
+            return Err("'#' must be the last level of the topic".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether the topic `filter` covers `topic` with MQTT wildcard semantics.
+///
+/// The `topic` may contain wildcards itself, e.g. for a subscription. It is only covered
+/// when every topic it can match is also matched by `filter`.
+pub fn filter_covers(filter: &str, topic: &str) -> bool {
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            // '#' matches the parent level and any number of child levels
+            (Some("#"), _) => return true,
+            (Some("+"), Some("#")) => return false,
+            (Some("+"), Some(_)) => continue,
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Check whether a single claim allows `action` on the full `topic`, e.g. `/tt/stream/a/b`.
+pub fn claim_allows(claim: &Claims, action: Action, topic: &str) -> Result<(), String> {
+    let resource = claim.resource();
+    if claim.action() != action {
+        return Err(format!("claim allows {}, not {}", claim.action(), action));
+    }
+    if resource.resource_type() == Some(ResourceType::Unknown) {
+        return Err("claim applies to an unknown resource type".to_string());
+    }
+    let stream_root = format!("{}/{}/", resource.prefix(), resource.stream());
+    let topic_in_stream = match topic.strip_prefix(&stream_root) {
+        Some(topic_in_stream) => topic_in_stream,
+        None => {
+            return Err(format!(
+                "topic is not in stream '{}' with prefix '{}'",
+                resource.stream(),
+                resource.prefix()
+            ))
+        }
+    };
+    if !filter_covers(resource.topic(), topic_in_stream) {
+        return Err(format!(
+            "topic filter '{}' does not cover '{}'",
+            resource.topic(),
+            topic_in_stream
+        ));
+    }
+    Ok(())
+}
+
+/// Evaluate whether any of the claims allows `action` on the full `topic`.
+pub fn evaluate(claims: &[Claims], action: Action, topic: &str) -> Evaluation {
+    let mut evaluation = Evaluation {
+        matched: None,
+        mismatches: Vec::new(),
+    };
+    for (index, claim) in claims.iter().enumerate() {
+        match claim_allows(claim, action, topic) {
+            Ok(()) => {
+                evaluation.matched = Some(index);
+                break;
+            }
+            Err(reason) => evaluation.mismatches.push((index, reason)),
+        }
+    }
+    evaluation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tf::token::Resource;
+
+    fn claim(action: Action, stream: &str, topic: &str) -> Claims {
+        Claims::new(action, Resource::new(stream, DEFAULT_PREFIX, topic))
+    }
+
+    #[test]
+    fn test_with_prefix() {
+        assert_eq!(with_prefix("stream/topic"), "/tt/stream/topic");
+        assert_eq!(with_prefix("/stream/topic"), "/tt/stream/topic");
+    }
+
+    #[test]
+    fn test_validate_topic_filter() {
+        assert!(validate_topic_filter("a/+/c").is_ok());
+        assert!(validate_topic_filter("a/#").is_ok());
+        assert!(validate_topic_filter("#").is_ok());
+        assert!(validate_topic_filter("").is_err());
+        assert!(validate_topic_filter("a/#/c").is_err());
+        assert!(validate_topic_filter("a/b#").is_err());
+        assert!(validate_topic_filter("a+/b").is_err());
+    }
+
+    #[test]
+    fn test_filter_covers() {
+        assert!(filter_covers("a/b", "a/b"));
+        assert!(!filter_covers("a/b", "a/c"));
+        assert!(!filter_covers("a/b", "a/b/c"));
+        assert!(!filter_covers("a/b/c", "a/b"));
+
+        assert!(filter_covers("a/+", "a/b"));
+        assert!(filter_covers("a/+/c", "a/b/c"));
+        assert!(!filter_covers("a/+", "a/b/c"));
+        assert!(filter_covers("a/+", "a/+"));
+
+        assert!(filter_covers("#", "a/b/c"));
+        assert!(filter_covers("a/#", "a"));
+        assert!(filter_covers("a/#", "a/b/c"));
+        assert!(filter_covers("a/#", "a/#"));
+        assert!(filter_covers("a/#", "a/+/c"));
+        assert!(!filter_covers("a/#", "b/c"));
+
+        assert!(!filter_covers("a/b", "a/+"));
+        assert!(!filter_covers("a/+", "a/#"));
+        assert!(!filter_covers("a/b", "a/#"));
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let claims = vec![
+            claim(Action::Subscribe, "stream", "a/#"),
+            claim(Action::Publish, "stream", "a/+/out"),
+        ];
+
+        let evaluation = evaluate(&claims, Action::Subscribe, "/tt/stream/a/b");
+        assert_eq!(evaluation.matched, Some(0));
+
+        let evaluation = evaluate(&claims, Action::Publish, "/tt/stream/a/b/out");
+        assert_eq!(evaluation.matched, Some(1));
+        assert_eq!(evaluation.mismatches.len(), 1);
+
+        let evaluation = evaluate(&claims, Action::Publish, "/tt/other/a/b/out");
+        assert!(!evaluation.is_allowed());
+        assert_eq!(
+            evaluation.mismatches[1].1,
+            "topic is not in stream 'stream' with prefix '/tt'"
+        );
+
+        let evaluation = evaluate(&claims, Action::Publish, "/tt/stream/a/b/in");
+        assert_eq!(
+            evaluation.mismatches[1].1,
+            "topic filter 'a/+/out' does not cover 'a/b/in'"
+        );
+        assert!(!evaluate(&[], Action::Subscribe, "/tt/stream/a").is_allowed());
+    }
+}
@@ -0,0 +1,235 @@
+use crate::error::DshError;
+use crate::tf::cache::now;
+use crate::tf::client_id::ClientIds;
+use crate::tf::format::rfc3339;
+use crate::tf::inspect::format_duration;
+use crate::tf::output::OutputOptions;
+use crate::tf::token::Token;
+use crate::tf::{get_request_attributes, get_tokens, Command, RequestAttributes};
+use clap::Args;
+use std::time::Duration;
+
+/// The minimum time between two token requests.
+const MIN_DELAY: Duration = Duration::from_secs(1);
+
+/// Keep a valid token in the output by fetching a new one before the current one expires.
+#[derive(Args, Debug)]
+pub struct WatchCommand {
+    /// The fraction of the token lifetime after which a new token is fetched, e.g. 0.75.
+    #[clap(long, default_value_t = 0.75, value_parser = parse_fraction)]
+    refresh_at: f64,
+
+    /// A shell command to run after every rotation.
+    ///
+    /// The new token is available in `DSH_MQTT_TOKEN` and the output file, if any, in
+    /// `DSH_MQTT_TOKEN_FILE`.
+    #[clap(long)]
+    exec: Option<String>,
+
+    /// Send a signal to this process after every rotation.
+    #[clap(long)]
+    signal_pid: Option<i32>,
+
+    /// The signal to send to `--signal-pid`, e.g. SIGHUP or USR1.
+    #[clap(long, default_value = "SIGHUP", requires = "signal_pid", value_parser = parse_signal)]
+    signal: String,
+}
+
+/// Parse a fraction between 0 and 1, exclusive.
+fn parse_fraction(value: &str) -> Result<f64, String> {
+    let fraction: f64 = value.parse().map_err(|e| format!("{}", e))?;
+    if fraction > 0.0 && fraction < 1.0 {
+        Ok(fraction)
+    } else {
+        Err("must be between 0 and 1".to_string())
+    }
+}
+
+/// Returns how long to wait before refreshing `token`, at `fraction` of its lifetime.
+fn refresh_delay(token: &Token, fraction: f64, now: i64) -> Duration {
+    let iat = token.token_attributes.iat();
+    let exp = token.token_attributes.exp();
+    let refresh_at = iat + ((exp - iat) as f64 * fraction) as i64;
+    Duration::from_secs(refresh_at.saturating_sub(now).max(0) as u64).max(MIN_DELAY)
+}
+
+/// Parse a signal name with or without `SIG` prefix, e.g. `hup` becomes `SIGHUP`.
+fn parse_signal(value: &str) -> Result<String, String> {
+    let value = value.to_uppercase();
+    let name = if value.starts_with("SIG") {
+        value
+    } else {
+        format!("SIG{}", value)
+    };
+    #[cfg(unix)]
+    {
+        use std::str::FromStr;
+        nix::sys::signal::Signal::from_str(&name).map_err(|_| "unknown signal".to_string())?;
+    }
+    Ok(name)
+}
+
+/// Send a signal, as parsed by `parse_signal`, to a process.
+#[cfg(unix)]
+fn send_signal(pid: i32, signal: &str) -> Result<(), DshError> {
+    use nix::sys::signal::{kill, Signal};
+    use nix::unistd::Pid;
+    use std::str::FromStr;
+
+    let signal = Signal::from_str(signal)
+        .map_err(|_| DshError::DshCli(format!("Unknown signal '{}'", signal)))?;
+    kill(Pid::from_raw(pid), signal)
+        .map_err(|e| DshError::DshCli(format!("Could not signal process {}: {}", pid, e)))
+}
+
+#[cfg(not(unix))]
+fn send_signal(_pid: i32, _signal: &str) -> Result<(), DshError> {
+    Err(DshError::DshCli(
+        "Signalling a process is only supported on unix".to_string(),
+    ))
+}
+
+/// Run the hook command and signal the process configured for a rotation.
+///
+/// Failures are logged, they do not stop the watch.
+async fn after_rotation(cmd: &WatchCommand, output: &OutputOptions, token: &Token) {
+    if let Some(exec) = &cmd.exec {
+        let mut hook = if cfg!(windows) {
+            let mut hook = tokio::process::Command::new("cmd");
+            hook.arg("/C");
+            hook
+        } else {
+            let mut hook = tokio::process::Command::new("sh");
+            hook.arg("-c");
+            hook
+        };
+        hook.arg(exec).env("DSH_MQTT_TOKEN", &token.raw_token);
+        if let Some(path) = output.token_path(token, 1) {
+            hook.env("DSH_MQTT_TOKEN_FILE", path);
+        }
+        match hook.status().await {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!("Hook '{}' exited with {}", exec, status),
+            Err(e) => warn!("Could not run hook '{}': {}", exec, e),
+        }
+    }
+    if let Some(pid) = cmd.signal_pid {
+        if let Err(e) = send_signal(pid, &cmd.signal) {
+            warn!("{}", e);
+        }
+    }
+}
+
+/// Run the watch subcommand until interrupted, using the token fetcher options.
+pub async fn run(cmd: &WatchCommand, opt: &Command) -> Result<(), DshError> {
+    tokio::select! {
+        result = watch(cmd, opt) => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    }
+}
+
+/// Fetch and write a new token whenever the current one reaches the refresh point.
+///
+/// A failure to fetch or write the first token is returned, so a misconfiguration is
+/// reported immediately. Later failures are retried with the backoff of the retry policy.
+async fn watch(cmd: &WatchCommand, opt: &Command) -> Result<(), DshError> {
+    let request_attributes = RequestAttributes {
+        token_amount: 1,
+        use_cache: false,
+        ..get_request_attributes(opt)?
+    };
+    let output = OutputOptions {
+        path: opt.output.clone(),
+        file_per_token: opt.file_per_token,
+        append: opt.append,
+        format: opt.format,
+    };
+    output.validate(1)?;
+    if output.file_per_token && request_attributes.client_ids == ClientIds::Random {
+        // every rotation would write a new file instead of replacing the previous one
+        return Err(DshError::DshCli(
+            "--file-per-token requires a fixed client id in watch, use --client-id.".to_string(),
+        ));
+    }
+
+    let mut rotations = 0;
+    let mut failures = 0;
+    loop {
+        let rotated = match get_tokens(&request_attributes).await {
+            Ok(tokens) => output.write(&tokens).map(|_| tokens),
+            Err(e) => Err(e),
+        };
+        let delay = match rotated {
+            Ok(tokens) => {
+                let token = &tokens[0];
+                after_rotation(cmd, &output, token).await;
+                rotations += 1;
+                failures = 0;
+
+                let delay = refresh_delay(token, cmd.refresh_at, now());
+                eprintln!(
+                    "rotated token for client {}, valid until {}, next refresh in {}",
+                    token.token_attributes.client_id,
+                    rfc3339(token.token_attributes.exp()),
+                    format_duration(delay.as_secs() as i64)
+                );
+                delay
+            }
+            Err(e) if rotations == 0 => return Err(e),
+            Err(e) => {
+                let delay = request_attributes.retry.backoff(failures).max(MIN_DELAY);
+                failures += 1;
+                error!(
+                    "Refreshing the token failed, retrying in {:?}: {}",
+                    delay, e
+                );
+                delay
+            }
+        };
+
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fraction() {
+        assert_eq!(parse_fraction("0.75"), Ok(0.75));
+        assert!(parse_fraction("0").is_err());
+        assert!(parse_fraction("1").is_err());
+        assert!(parse_fraction("half").is_err());
+    }
+
+    #[test]
+    fn test_refresh_delay() {
+        // issued at 1699996400, valid for an hour
+        let token = Token::for_test("device-1", 1700000000);
+        assert_eq!(
+            refresh_delay(&token, 0.75, 1699996400),
+            Duration::from_secs(2700)
+        );
+        assert_eq!(
+            refresh_delay(&token, 0.5, 1699998000),
+            Duration::from_secs(200)
+        );
+        assert_eq!(refresh_delay(&token, 0.75, 1700000000), MIN_DELAY);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!(parse_signal("hup"), Ok("SIGHUP".to_string()));
+        assert_eq!(parse_signal("SIGUSR1"), Ok("SIGUSR1".to_string()));
+        assert!(parse_signal("NOPE").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_send_signal() {
+        // SIGCONT to ourselves is harmless
+        assert!(send_signal(std::process::id() as i32, "SIGCONT").is_ok());
+    }
+}